@@ -1 +1 @@
-cd client-bevy && cargo watch -w ./src -s 'wasm-pack build --target web --dev -- --features web && http-server'
\ No newline at end of file
+cd client-bevy && cargo watch -w ./src -s 'wasm-pack build --target web --dev -- --features web' & cargo run -p server -- --web client-bevy
\ No newline at end of file
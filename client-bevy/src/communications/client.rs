@@ -3,8 +3,14 @@ use crossbeam_channel::Receiver;
 
 #[cfg(feature = "native")]
 use async_compat::Compat;
-use client_lib::Client;
+#[cfg(feature = "native")]
+use client_lib::Loopback;
+use client_lib::{AssetCache, Client, ClientControl, ConnectionEvent};
+use protocol::{AssetError, ClientMessage, ServerMessage};
+use tokio::sync::mpsc::error::TrySendError;
 
+#[cfg(feature = "native")]
+use super::server::LocalHost;
 use super::shared::*;
 
 pub struct ClientPlugin;
@@ -12,59 +18,313 @@ pub struct ClientPlugin;
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
         app.add_system_set(
-            SystemSet::on_enter(ClientState::Open).with_system(setup_client.system()),
+            SystemSet::on_enter(ClientState::Connecting).with_system(setup_client.system()),
         )
         .add_system_set(
-            SystemSet::on_update(ClientState::Open).with_system(message_system.system()),
+            SystemSet::on_update(ClientState::Connecting)
+                .with_system(connection_event_system.system()),
+        )
+        .add_system_set(
+            SystemSet::on_update(ClientState::Open)
+                .with_system(message_system.system())
+                .with_system(connection_event_system.system()),
         );
     }
 }
 
 fn setup_client(
     mut commands: Commands,
-    communication: Res<CommunicationResource>,
+    mut communication: ResMut<CommunicationResource>,
+    mut connection_status: ResMut<ConnectionStatus>,
+    mut client_state: ResMut<State<ClientState>>,
+    login: Res<LoginDetails>,
+    #[cfg(feature = "native")] local_host: Option<Res<LocalHost>>,
+    mut players: ResMut<Players>,
+    mut identity: ResMut<Identity>,
+    mut received_messages: ResMut<ReceivedMessages>,
+    mut assets: ResMut<CampaignAssets>,
+    mut cache: ResMut<AssetCache>,
     task_pool: Res<IoTaskPool>,
 ) {
     if !communication.running {
-        eprintln!("Not running");
+        warn!("Not running");
         return;
     }
-    if let CommunicationState::Client { url } = &communication.state {
-        println!("Setting up client");
-        let client = Client::<String>::new(url.clone());
-
-        if let Ok(client) = client {
-            commands.insert_resource(client.receiver.clone());
-            commands.insert_resource(client.sender.clone());
-            commands.insert_resource(client.control_sender.clone());
-
-            #[cfg(feature = "native")]
-            task_pool.spawn(Compat::new(client.start())).detach();
-            #[cfg(feature = "web")]
-            task_pool.spawn(client.start()).detach();
-        } else {
-            eprintln!("Error setting up client");
+    // Whatever was seen on the last server doesn't apply to this one.
+    *players = Players::default();
+    *identity = Identity::default();
+    *received_messages = ReceivedMessages::default();
+    *assets = CampaignAssets::default();
+    cache.cancel_fetches();
+    match &communication.state {
+        CommunicationState::Client { url } => {
+            info!("Setting up client");
+            match Client::new(url.clone()) {
+                Ok(mut client) => {
+                    client.login = Some(login.credentials());
+                    client.campaign = login.campaign();
+                    connection_status.message = None;
+                    insert_channels(&mut commands, &client);
+                    #[cfg(feature = "native")]
+                    task_pool.spawn(Compat::new(client.start())).detach();
+                    #[cfg(feature = "web")]
+                    task_pool.spawn(client.start()).detach();
+                    return;
+                }
+                Err(error) => {
+                    error!("Error setting up client: {}", error);
+                    connection_status.message = Some(format!("Couldn't connect: {}", error));
+                }
+            }
         }
-    } else {
-        eprintln!("Can't set up client");
+        // The host plays on its own server like anyone else, just without
+        // going through the network.
+        #[cfg(feature = "native")]
+        CommunicationState::Server { .. } => match local_host {
+            Some(local_host) => {
+                info!("Joining hosted game");
+                let connector = local_host.connector.clone();
+                let client = Client::with_transport(
+                    String::from("memory://host"),
+                    Loopback::new(move || connector.connect()),
+                );
+                match client {
+                    Ok(mut client) => {
+                        client.login = Some(local_host.login.clone());
+                        connection_status.message = None;
+                        insert_channels(&mut commands, &client);
+                        task_pool.spawn(Compat::new(client.start())).detach();
+                        return;
+                    }
+                    Err(error) => {
+                        error!("Error joining hosted game: {}", error);
+                        connection_status.message = Some(format!("Couldn't join: {}", error));
+                    }
+                }
+            }
+            None => error!("Can't join a game that isn't hosted"),
+        },
+        _ => error!("Can't set up client"),
     }
+    // Don't leave the last connection's events to be read.
+    commands.remove_resource::<Receiver<ConnectionEvent>>();
+    commands.remove_resource::<tokio::sync::mpsc::Sender<ClientControl>>();
+    stopped(&mut communication);
+    change_state(&mut client_state, ClientState::Closed);
+}
+
+/// Hands the client's channels to the game. The client still has to be
+/// started.
+fn insert_channels<T>(commands: &mut Commands, client: &Client<T>) {
+    commands.insert_resource(client.receiver.clone());
+    commands.insert_resource(client.events.clone());
+    commands.insert_resource(client.sender.clone());
+    commands.insert_resource(client.control_sender.clone());
 }
 
 fn message_system(
-    client_sender: Res<tokio::sync::mpsc::Sender<String>>,
-    client_receiver: Res<Receiver<(usize, String)>>,
+    client_sender: Res<tokio::sync::mpsc::Sender<ClientMessage>>,
+    client_receiver: Res<Receiver<ServerMessage>>,
     mut send_message_reader: EventReader<SendMessageEvent>,
+    mut load_older_reader: EventReader<LoadOlderChatEvent>,
     mut received_messages: ResMut<ReceivedMessages>,
+    mut players: ResMut<Players>,
+    mut identity: ResMut<Identity>,
+    mut login: ResMut<LoginDetails>,
+    mut upload_reader: EventReader<UploadAssetEvent>,
+    mut assets: ResMut<CampaignAssets>,
+    mut cache: ResMut<AssetCache>,
+    time: Res<Time>,
 ) {
     for msg in send_message_reader.iter() {
-        if client_sender.try_send(msg.value.clone()).is_ok() {
+        let msg = ClientMessage::Chat {
+            text: msg.value.clone(),
+            channel: msg.channel,
+            in_character: msg.in_character,
+        };
+        if client_sender.try_send(msg).is_ok() {
         } else {
-            eprint!("Failed to send a message");
+            warn!("Failed to send a message");
+        }
+    }
+    if load_older_reader.iter().last().is_some() {
+        if let Some(msg) = received_messages.chat.request_older() {
+            if client_sender.try_send(msg).is_err() {
+                warn!("Failed to ask for older chat");
+            }
+        }
+    }
+    for upload in upload_reader.iter() {
+        match read_upload(&upload.path) {
+            Ok(msg) => {
+                if client_sender.try_send(msg).is_err() {
+                    warn!("Failed to send upload");
+                }
+            }
+            Err(error) => warn!("Can't upload {}", error),
         }
     }
 
     while let Ok(msg) = client_receiver.try_recv() {
-        println!("Got Message {:?}", msg);
-        received_messages.messages.push(msg);
+        match msg {
+            ServerMessage::Welcome {
+                user,
+                role,
+                password,
+                ..
+            } => {
+                *identity = Identity {
+                    user: Some(user),
+                    role,
+                };
+                // Everyone already playing is listed again after each welcome,
+                // so anyone who left while we were away is forgotten.
+                *players = Players::default();
+                // The invite we signed up with won't let us back in, so the
+                // login window shows the password that will.
+                if let Some(password) = password {
+                    login.secret = password;
+                    login.invite = false;
+                }
+            }
+            ServerMessage::Chat { entry, .. } | ServerMessage::PrivateChat { entry } => {
+                received_messages.chat.push(entry);
+            }
+            ServerMessage::ChatHistory { entries, more } => {
+                received_messages.chat.page(entries, more);
+            }
+            ServerMessage::Snapshot { state, .. } => {
+                let msg = received_messages.chat.reload(state.chat);
+                if client_sender.try_send(msg).is_err() {
+                    warn!("Failed to ask for chat history");
+                }
+                for info in state.assets.values() {
+                    cache.want(info);
+                }
+                assets.assets = state.assets;
+            }
+            ServerMessage::AssetAdded { info, .. } => {
+                cache.want(&info);
+                assets.assets.insert(info.hash.clone(), info);
+            }
+            ServerMessage::Asset { hash, data } => {
+                if let Err(error) = cache.receive(hash, data.0) {
+                    warn!("Discarded asset: {}", error);
+                }
+            }
+            ServerMessage::AssetRejected { reason } => {
+                if let AssetError::Unknown(hash) = &reason {
+                    cache.failed(hash);
+                }
+                warn!("Asset refused: {}", reason);
+            }
+            ServerMessage::ChatRejected { reason } => {
+                warn!("Chat not sent: {}", reason);
+            }
+            ServerMessage::EditRejected { reason } => {
+                warn!("Edit rejected: {}", reason);
+            }
+            ServerMessage::PermissionDenied { reason } => {
+                warn!("Not allowed: {}", reason);
+            }
+            ServerMessage::Presence(presence) => players.observe(&presence),
+            ServerMessage::MapActivated { map, .. } => info!("Now showing map {}", map),
+            ServerMessage::Announcement { text } => {
+                info!("Server announcement: {}", text);
+                received_messages.announcements.push(text);
+            }
+            _ => {}
+        }
+    }
+
+    // Assets are asked for a few at a time, so a big campaign doesn't flood
+    // the server.
+    cache.tick(time.delta());
+    while let Some(msg) = cache.next_fetch() {
+        if let Err(TrySendError::Full(msg) | TrySendError::Closed(msg)) =
+            client_sender.try_send(msg)
+        {
+            if let ClientMessage::FetchAsset { hash } = msg {
+                cache.unsent(&hash);
+            }
+            break;
+        }
+    }
+}
+
+/// Follows the client task as it connects, drops and stops, passing each
+/// step on as a Bevy event and moving `ClientState` along to match. Also
+/// stops the client when the player disconnects.
+fn connection_event_system(
+    mut commands: Commands,
+    events: Option<Res<Receiver<ConnectionEvent>>>,
+    control_sender: Option<Res<tokio::sync::mpsc::Sender<ClientControl>>>,
+    mut disconnect_reader: EventReader<DisconnectClientEvent>,
+    mut event_writer: EventWriter<ConnectionEvent>,
+    mut connection_status: ResMut<ConnectionStatus>,
+    mut communications: ResMut<CommunicationResource>,
+    mut client_state: ResMut<State<ClientState>>,
+    mut cache: ResMut<AssetCache>,
+) {
+    let (events, control_sender) = match (events, control_sender) {
+        (Some(events), Some(control_sender)) => (events, control_sender),
+        _ => return,
+    };
+    if disconnect_reader.iter().last().is_some() {
+        info!("Disconnecting");
+        if control_sender.try_send(ClientControl::Disconnect).is_err() {
+            warn!("Client had already stopped");
+        }
+        // Its last events aren't wanted, and mustn't be mistaken for those of
+        // the next connection.
+        commands.remove_resource::<Receiver<ConnectionEvent>>();
+        commands.remove_resource::<tokio::sync::mpsc::Sender<ClientControl>>();
+        connection_status.message = Some(String::from("Disconnected"));
+        stopped(&mut communications);
+        change_state(&mut client_state, ClientState::Closed);
+        return;
+    }
+    while let Ok(event) = events.try_recv() {
+        connection_status.message = match &event {
+            ConnectionEvent::Connecting | ConnectionEvent::Connected => None,
+            ConnectionEvent::Reconnecting { attempt, delay } => Some(format!(
+                "Reconnecting... (attempt {} in {:.1}s)",
+                attempt,
+                delay.as_secs_f32()
+            )),
+            ConnectionEvent::Resumed => Some(String::from("Connection resumed")),
+            ConnectionEvent::Rejoined => Some(String::from("Reconnected as a new player")),
+            ConnectionEvent::GaveUp => Some(String::from("Gave up reconnecting")),
+            ConnectionEvent::Failed { reason } => Some(format!("Couldn't connect: {}", reason)),
+            ConnectionEvent::Disconnected => Some(String::from("Disconnected")),
+        };
+        match &event {
+            ConnectionEvent::Connected => change_state(&mut client_state, ClientState::Open),
+            // Assets asked for on the dropped connection won't arrive.
+            ConnectionEvent::Reconnecting { .. } => cache.retry_fetches(),
+            ConnectionEvent::GaveUp
+            | ConnectionEvent::Failed { .. }
+            | ConnectionEvent::Disconnected => {
+                stopped(&mut communications);
+                change_state(&mut client_state, ClientState::Closed);
+            }
+            _ => {}
+        }
+        event_writer.send(event);
+    }
+}
+
+/// Notes the client has stopped. A host's server keeps running without it.
+fn stopped(communications: &mut CommunicationResource) {
+    if let CommunicationState::Client { .. } = communications.state {
+        communications.running = false;
+    }
+}
+
+/// Moves to `next` unless already there, replacing any change already queued
+/// this frame.
+fn change_state(client_state: &mut State<ClientState>, next: ClientState) {
+    if client_state.current() != &next && client_state.overwrite_set(next).is_err() {
+        error!("Failed to set client state");
     }
 }
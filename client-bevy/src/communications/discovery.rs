@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+use bevy::prelude::*;
+use client_lib::{DiscoveredSession, Discovery};
+
+use super::shared::*;
+
+pub struct DiscoveryPlugin;
+
+impl Plugin for DiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LanSessions>()
+            .add_system(discovery_system.system());
+    }
+}
+
+/// Games hosted on the local network, for the connection window to offer.
+#[derive(Default)]
+pub struct LanSessions {
+    discovery: Option<Discovery>,
+    /// Why we can't look for games, if we can't.
+    pub error: Option<String>,
+}
+
+impl LanSessions {
+    pub fn sessions(&self) -> impl Iterator<Item = &DiscoveredSession> {
+        self.discovery
+            .iter()
+            .flat_map(|discovery| discovery.sessions())
+    }
+}
+
+/// Listens for hosts while the player is picking a server to join, and lets
+/// go of the port otherwise.
+fn discovery_system(communications: Res<CommunicationResource>, mut lan: ResMut<LanSessions>) {
+    let picking = !communications.running
+        && matches!(communications.state, CommunicationState::Client { .. });
+    if !picking {
+        if lan.discovery.is_some() || lan.error.is_some() {
+            *lan = LanSessions::default();
+        }
+        return;
+    }
+    if lan.discovery.is_none() && lan.error.is_none() {
+        match Discovery::listen() {
+            Ok(discovery) => lan.discovery = Some(discovery),
+            Err(error) => {
+                warn!("Can't look for games on the local network: {}", error);
+                lan.error = Some(format!("Can't look for games: {}", error));
+            }
+        }
+    }
+    if let Some(discovery) = &mut lan.discovery {
+        discovery.poll(Instant::now());
+    }
+}
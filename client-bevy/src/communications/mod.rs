@@ -1,10 +1,16 @@
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext};
+use client_lib::{AssetCache, ConnectionEvent};
+use protocol::{ChatChannel, ChatEntry, Role, UserId};
 
+#[cfg(feature = "native")]
+pub mod discovery;
 #[cfg(feature = "native")]
 pub mod server;
 pub mod shared;
 #[cfg(feature = "native")]
+use discovery::*;
+#[cfg(feature = "native")]
 use server::*;
 
 pub mod client;
@@ -16,19 +22,34 @@ pub struct CommunicationsPlugin;
 
 const DEFAULT_PORT: u16 = 4867;
 
+/// What a hosted campaign is called until the host names it.
+const DEFAULT_CAMPAIGN: &str = "host";
+
 impl Plugin for CommunicationsPlugin {
     fn build(&self, app: &mut App) {
         #[cfg(feature = "native")]
-        app.add_plugin(ServerPlugin);
+        app.add_plugin(ServerPlugin).add_plugin(DiscoveryPlugin);
 
         app.add_event::<CloseServerEvent>()
+            .add_event::<DisconnectClientEvent>()
+            .add_event::<ConnectionEvent>()
             .add_event::<SendMessageEvent>()
+            .add_event::<LoadOlderChatEvent>()
+            .add_event::<UploadAssetEvent>()
             .add_state(ServerState::Closed)
             .add_state(ClientState::Closed)
             .add_plugin(ClientPlugin)
             .init_resource::<CommunicationResource>()
+            .init_resource::<ConnectionStatus>()
+            .init_resource::<LoginDetails>()
+            .init_resource::<HostInvite>()
+            .init_resource::<Players>()
+            .init_resource::<Identity>()
             .init_resource::<PendingMessage>()
             .init_resource::<ReceivedMessages>()
+            .init_resource::<PendingUpload>()
+            .init_resource::<CampaignAssets>()
+            .init_resource::<AssetCache>()
             .add_system(display_connection_ui.system())
             .add_system(message_system.system());
     }
@@ -40,13 +61,19 @@ fn display_connection_ui(
     mut server_state: ResMut<State<ServerState>>,
     mut client_state: ResMut<State<ClientState>>,
     mut server_events: EventWriter<CloseServerEvent>,
+    mut disconnect_events: EventWriter<DisconnectClientEvent>,
+    connection_status: Res<ConnectionStatus>,
+    mut login: ResMut<LoginDetails>,
+    host_invite: Res<HostInvite>,
+    players: Res<Players>,
+    #[cfg(feature = "native")] lan_sessions: Res<LanSessions>,
 ) {
     egui::Window::new("Connection").show(egui_context.ctx(), |ui| {
         let mut is_server = false;
         let mut is_client = false;
         match &communications.state {
             CommunicationState::None => {}
-            CommunicationState::Server { port: _ } => {
+            CommunicationState::Server { .. } => {
                 is_server = true;
             }
             CommunicationState::Client { url: _ } => {
@@ -69,7 +96,10 @@ fn display_connection_ui(
                     if is_server {
                         communications.state = CommunicationState::None;
                     } else {
-                        communications.state = CommunicationState::Server { port: DEFAULT_PORT };
+                        communications.state = CommunicationState::Server {
+                            port: DEFAULT_PORT,
+                            campaign: DEFAULT_CAMPAIGN.to_string(),
+                        };
                     }
                 }
 
@@ -79,7 +109,7 @@ fn display_connection_ui(
                     } else {
                         communications.state = CommunicationState::Client {
                             url: if cfg!(debug_assertions) {
-                                format!("ws://localhost:{}", DEFAULT_PORT)
+                                format!("ws://localhost:{}/server", DEFAULT_PORT)
                             } else {
                                 "wss://caladluin-vtt.com/server".to_string()
                             },
@@ -90,31 +120,45 @@ fn display_connection_ui(
         }
 
         ui.horizontal(|ui| {
-            if let CommunicationState::Server { port } = &communications.state {
+            if let CommunicationState::Server { port, campaign } = &communications.state {
                 if !communications.running {
                     ui.label("Port:");
                     let mut port = port.to_string();
+                    let mut campaign = campaign.clone();
                     if ui.text_edit_singleline(&mut port).changed() {
                         if let Ok(i) = port.parse::<u16>() {
-                            println!("Parsed {}", port);
-                            communications.state = CommunicationState::Server { port: i };
+                            communications.state = CommunicationState::Server {
+                                port: i,
+                                campaign: campaign.clone(),
+                            };
                         } else {
-                            println!("COULDNT PARSE {}", port);
+                            debug!("Couldn't parse port {}", port);
+                        }
+                    }
+                    ui.label("Campaign:");
+                    if ui.text_edit_singleline(&mut campaign).changed() {
+                        if let CommunicationState::Server { campaign: name, .. } =
+                            &mut communications.state
+                        {
+                            *name = campaign;
                         }
                     }
 
                     if ui.button("Start Host").clicked() {
                         communications.running = true;
                         if server_state.push(ServerState::Open).is_ok() {
-                            println!("Starting Host")
+                            info!("Starting host")
                         } else {
-                            eprintln!("Failed to set host state");
+                            error!("Failed to set host state");
                         }
                     }
                 } else {
-                    ui.label(format!("Host Running on Port {}", port));
+                    ui.label(format!("Hosting {} on Port {}", campaign, port));
+                    if let Some(token) = &host_invite.token {
+                        ui.label(format!("Invite: {}", token));
+                    }
                     if ui.button("Close Host").clicked() {
-                        println!("Attempting to close server");
+                        info!("Attempting to close server");
                         communications.running = false;
                         server_events.send(CloseServerEvent {});
                     }
@@ -128,48 +172,231 @@ fn display_connection_ui(
                     if ui.text_edit_singleline(&mut url).changed() {
                         communications.state = CommunicationState::Client { url };
                     }
+                    ui.label("Name:");
+                    ui.text_edit_singleline(&mut login.username);
+                    if ui.selectable_label(!login.invite, "Password").clicked() {
+                        login.invite = false;
+                    }
+                    if ui.selectable_label(login.invite, "Invite").clicked() {
+                        login.invite = true;
+                    }
+                    if login.invite {
+                        ui.text_edit_singleline(&mut login.secret);
+                    } else {
+                        ui.add(egui::TextEdit::singleline(&mut login.secret).password(true));
+                    }
+                    ui.label("Campaign (blank for the server's default):");
+                    ui.text_edit_singleline(&mut login.campaign);
                     if ui.button("Start Client").clicked() {
-                        communications.running = true;
-                        if client_state.push(ClientState::Open).is_ok() {
-                            println!("Starting Client")
-                        } else {
-                            eprintln!("Failed to set client state")
-                        }
+                        start_client(&mut communications, &mut client_state);
                     }
                 } else {
-                    ui.label(format!("Connected to server at {}", url));
+                    ui.label(match client_state.current() {
+                        ClientState::Open => format!("Connected to server at {}", url),
+                        _ => format!("Connecting to server at {}", url),
+                    });
+                    if ui.button("Disconnect").clicked() {
+                        disconnect_events.send(DisconnectClientEvent);
+                    }
                 }
             }
-        })
+        });
+
+        #[cfg(feature = "native")]
+        if !communications.running
+            && matches!(communications.state, CommunicationState::Client { .. })
+        {
+            ui.label("Games on your network");
+            if let Some(error) = &lan_sessions.error {
+                ui.label(error);
+            }
+            let mut chosen = None;
+            for session in lan_sessions.sessions() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{} at {} - {} playing",
+                        session.campaign, session.address, session.players
+                    ));
+                    if ui.button("Join").clicked() {
+                        chosen = Some(session.clone());
+                    }
+                });
+            }
+            if let Some(session) = chosen {
+                communications.state = CommunicationState::Client { url: session.url() };
+                login.campaign = session.campaign;
+                start_client(&mut communications, &mut client_state);
+            }
+        }
+
+        if let Some(message) = &connection_status.message {
+            ui.label(message);
+        }
+
+        if communications.running && !players.players.is_empty() {
+            ui.label("Players");
+            for (user, player) in players.players.iter() {
+                ui.horizontal(|ui| {
+                    match &player.name {
+                        Some(name) => ui.label(name),
+                        None => ui.label(format!("Player {}", user)),
+                    };
+                    if let Some(millis) = player.latency {
+                        ui.label(format!("{} ms", millis));
+                    }
+                });
+            }
+        }
     });
 }
 
+fn start_client(communications: &mut CommunicationResource, client_state: &mut State<ClientState>) {
+    communications.running = true;
+    if client_state.set(ClientState::Connecting).is_ok() {
+        info!("Starting client")
+    } else {
+        error!("Failed to set client state")
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", allow(unused_mut, unused_variables))]
 fn message_system(
     egui_context: ResMut<EguiContext>,
     communications: Res<CommunicationResource>,
     mut pending: ResMut<PendingMessage>,
     mut send_event: EventWriter<SendMessageEvent>,
+    mut load_older_event: EventWriter<LoadOlderChatEvent>,
     received_messages: Res<ReceivedMessages>,
+    players: Res<Players>,
+    identity: Res<Identity>,
+    mut upload: ResMut<PendingUpload>,
+    mut upload_event: EventWriter<UploadAssetEvent>,
+    assets: Res<CampaignAssets>,
+    cache: Res<AssetCache>,
 ) {
     if !communications.running {
         return;
     }
     egui::Window::new("Messaging").show(egui_context.ctx(), |ui| {
         ui.vertical(|ui| {
-            ui.label("Message");
-            ui.text_edit_singleline(&mut pending.value);
-            if ui.button("Send Message").clicked() {
-                let value = pending.value.clone();
-                pending.value = String::new();
-                send_event.send(SendMessageEvent { value });
+            for text in received_messages.announcements.iter() {
+                ui.label(format!("Server: {}", text));
             }
-            ui.label("Recieved Messages");
-            for (sender, message) in received_messages.messages.iter() {
-                ui.horizontal(|ui| {
-                    ui.label(sender.to_string());
-                    ui.label(message);
+            if received_messages.chat.can_load_older() && ui.button("Load older").clicked() {
+                load_older_event.send(LoadOlderChatEvent);
+            }
+            egui::ScrollArea::vertical()
+                .max_height(300.)
+                .show(ui, |ui| {
+                    for entry in received_messages.chat.entries() {
+                        ui.label(chat_line(entry, &players, &identity));
+                    }
+                });
+
+            // Whoever we were whispering to may have left.
+            if let ChatChannel::Whisper { to } = pending.channel {
+                if !players.players.contains_key(&to) {
+                    pending.channel = ChatChannel::Everyone;
+                }
+            }
+            ui.horizontal(|ui| {
+                ui.label("To");
+                egui::ComboBox::from_id_source("chat_channel")
+                    .selected_text(channel_name(&pending.channel, &players))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut pending.channel,
+                            ChatChannel::Everyone,
+                            "Everyone",
+                        );
+                        if identity.role == Role::GameMaster {
+                            ui.selectable_value(
+                                &mut pending.channel,
+                                ChatChannel::GameMasters,
+                                "Game masters",
+                            );
+                        }
+                        for user in players.players.keys() {
+                            if Some(*user) != identity.user {
+                                let channel = ChatChannel::Whisper { to: *user };
+                                let name = channel_name(&channel, &players);
+                                ui.selectable_value(&mut pending.channel, channel, name);
+                            }
+                        }
+                    });
+                ui.checkbox(&mut pending.in_character, "In character");
+            });
+            ui.text_edit_singleline(&mut pending.value);
+            if ui.button("Send Message").clicked() && !pending.value.trim().is_empty() {
+                send_event.send(SendMessageEvent {
+                    value: std::mem::take(&mut pending.value),
+                    channel: pending.channel,
+                    in_character: pending.in_character,
                 });
             }
         });
     });
+    egui::Window::new("Assets").show(egui_context.ctx(), |ui| {
+        // Browsers don't let us read files by path.
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.horizontal(|ui| {
+            ui.label("File:");
+            ui.text_edit_singleline(&mut upload.path);
+            if ui.button("Upload").clicked() {
+                let path = std::mem::take(&mut upload.path);
+                upload_event.send(UploadAssetEvent { path });
+            }
+        });
+        for (hash, info) in assets.assets.iter() {
+            ui.horizontal(|ui| {
+                ui.label(&info.name);
+                ui.label(format!("{} KiB", info.size / 1024));
+                if cache.get(hash).is_none() {
+                    ui.label("(not downloaded)");
+                }
+            });
+        }
+    });
+}
+
+/// A player's name, if we know it.
+fn player_name(user: UserId, players: &Players) -> String {
+    players
+        .players
+        .get(&user)
+        .and_then(|player| player.name.clone())
+        .unwrap_or_else(|| format!("Player {}", user))
+}
+
+fn channel_name(channel: &ChatChannel, players: &Players) -> String {
+    match channel {
+        ChatChannel::Everyone => String::from("Everyone"),
+        ChatChannel::GameMasters => String::from("Game masters"),
+        ChatChannel::Whisper { to } => format!("Whisper to {}", player_name(*to, players)),
+    }
+}
+
+/// How a chat message reads in the messaging window, e.g.
+/// "18:04 Frodo [IC] (Whisper to Sam): Psst". Times are UTC.
+fn chat_line(entry: &ChatEntry, players: &Players, identity: &Identity) -> String {
+    let minutes = entry.sent / 60_000;
+    let name = match entry.name.as_str() {
+        "" => player_name(entry.from, players),
+        name => name.to_string(),
+    };
+    let tag = if entry.in_character { "IC" } else { "OOC" };
+    let channel = match entry.channel {
+        ChatChannel::Everyone => String::new(),
+        ChatChannel::Whisper { to } if Some(to) == identity.user => String::from(" (Whisper)"),
+        channel => format!(" ({})", channel_name(&channel, players)),
+    };
+    format!(
+        "{:02}:{:02} {} [{}]{}: {}",
+        minutes / 60 % 24,
+        minutes % 60,
+        name,
+        tag,
+        channel,
+        entry.text
+    )
 }
@@ -1,8 +1,11 @@
 use super::shared::*;
 use async_compat::Compat;
 use bevy::{prelude::*, tasks::IoTaskPool};
-use crossbeam_channel::Receiver;
-use server_lib::{Clients, Server, ServerControl};
+use protocol::{Credentials, Role};
+use server_lib::{
+    AssetStore, CampaignRegistry, Game, LocalConnector, Rooms, Server, ServerControl, UserStore,
+};
+use std::path::PathBuf;
 use tokio::sync::mpsc::Sender;
 pub struct ServerPlugin;
 
@@ -13,85 +16,159 @@ impl Plugin for ServerPlugin {
         )
         .add_system_set(
             SystemSet::on_update(ServerState::Open)
-                .with_system(message_system.system())
+                .with_system(join_as_host.system())
                 .with_system(close_server.system()),
         );
     }
 }
 
+/// How the host joins its own server - through memory rather than the
+/// network, but otherwise like any other player.
+pub struct LocalHost {
+    pub connector: LocalConnector,
+    pub login: Credentials,
+}
+
 fn setup_server(
     mut commands: Commands,
     communication: Res<CommunicationResource>,
+    mut host_invite: ResMut<HostInvite>,
     task_pool: Res<IoTaskPool>,
 ) {
     if !communication.running {
-        eprintln!("Not running");
+        warn!("Not running");
         return;
     }
-    if let CommunicationState::Server { port } = communication.state {
-        println!("Setting up server");
-        let server = Server::<String>::new(format!("0.0.0.0:{}", port));
+    if let CommunicationState::Server { port, campaign } = &communication.state {
+        info!("Setting up server");
+        let users = match UserStore::temporary() {
+            Ok(users) => users,
+            Err(error) => {
+                error!("Error setting up users: {}", error);
+                return;
+            }
+        };
+        let campaigns = match CampaignRegistry::temporary() {
+            Ok(campaigns) => campaigns,
+            Err(error) => {
+                error!("Error setting up campaigns: {}", error);
+                return;
+            }
+        };
+        if let Err(error) = campaigns.ensure(campaign) {
+            error!("Error setting up campaign: {}", error);
+            return;
+        }
+        let mut rooms = match Rooms::temporary() {
+            Ok(rooms) => rooms,
+            Err(error) => {
+                error!("Error setting up rooms: {}", error);
+                return;
+            }
+        };
+        // Uploads only last as long as the server does.
+        let asset_dir = std::env::temp_dir().join(format!("vtt-host-{}", std::process::id()));
+        match AssetStore::open(asset_dir.clone()) {
+            Ok(assets) => rooms.assets = Some(assets),
+            Err(error) => {
+                error!("Error setting up assets: {}", error);
+                return;
+            }
+        }
+        // Removed again if the server doesn't start.
+        let uploads = HostUploads { dir: asset_dir };
+        // The host signs up before anyone else can take its name, with a
+        // password only it ever sees.
+        let host_password = match users.sign_up(HOST_NAME, campaign, Role::GameMaster) {
+            Ok((_, password)) => password,
+            Err(error) => {
+                error!("Error setting up host: {}", error);
+                return;
+            }
+        };
+        host_invite.token = users.invite(campaign, Role::Player).ok();
+        rooms.users = Some(users.clone());
+        let server = Server::new(
+            format!("0.0.0.0:{}", port),
+            campaign.clone(),
+            users,
+            campaigns,
+        );
 
-        if let Ok(server) = server {
-            commands.insert_resource(server.clients.clone());
-            commands.insert_resource(server.reciever.clone());
+        if let Ok(mut server) = server {
+            server.advertise = true;
+            rooms.metrics = Some(server.metrics.clone());
+            let game = Game::new(rooms, server.clients.clone());
+            let events = server.reciever.clone();
+            std::thread::spawn(move || game.run(events));
             commands.insert_resource(server.control_sender.clone());
+            commands.insert_resource(uploads);
+            commands.insert_resource(LocalHost {
+                connector: server.local_connector(),
+                login: Credentials::Password {
+                    username: HOST_NAME.to_string(),
+                    password: host_password,
+                },
+            });
             task_pool.spawn(Compat::new(server.start())).detach();
         } else {
-            eprintln!("Error setting up server");
+            error!("Error setting up server");
         }
     } else {
-        eprintln!("Can't set up server");
+        error!("Can't set up server");
     }
 }
 
-fn message_system(
-    clients: Res<Clients<String>>,
-    client_to_game_receiver: Res<Receiver<(usize, String)>>,
-    mut send_message_reader: EventReader<SendMessageEvent>,
-    mut received_messages: ResMut<ReceivedMessages>,
-) {
-    let mut messages: Vec<(usize, String)> = send_message_reader
-        .iter()
-        .map(|val| (0, val.value.clone()))
-        .collect();
+/// Where the hosted campaign's uploads are kept. The directory is removed
+/// when this is dropped, whether the server is closed or the game quits.
+struct HostUploads {
+    dir: PathBuf,
+}
 
-    while let Ok((client, msg)) = client_to_game_receiver.try_recv() {
-        println!("Got Message {:?}", &msg);
-        received_messages.messages.push((client, msg.clone()));
-        messages.push((client, msg));
+impl Drop for HostUploads {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_dir_all(&self.dir) {
+            warn!("Couldn't remove {}: {}", self.dir.display(), error);
+        }
     }
+}
 
-    let mut clients = clients.lock().unwrap();
-    let mut failures: Vec<usize> = Vec::new();
-    for (id, client) in clients.iter() {
-        for msg in messages.iter() {
-            if msg.0 == *id {
-                continue;
-            }
-            if client.sender.try_send(msg.to_owned()).is_err() {
-                eprint!("Failed to send a message");
-                failures.push(*id);
-            }
+/// The name the host plays under.
+const HOST_NAME: &str = "Host";
+
+/// Connects the host's own client once its server is up.
+fn join_as_host(local_host: Option<Res<LocalHost>>, mut client_state: ResMut<State<ClientState>>) {
+    if let Some(local_host) = local_host {
+        if local_host.is_added() && client_state.set(ClientState::Connecting).is_err() {
+            error!("Failed to set client state");
         }
     }
-    for id in failures.iter() {
-        clients.remove(id);
-    }
 }
 
 fn close_server(
+    mut commands: Commands,
     control: Option<Res<Sender<ServerControl>>>,
     mut event: EventReader<CloseServerEvent>,
+    mut disconnect_events: EventWriter<DisconnectClientEvent>,
+    mut server_state: ResMut<State<ServerState>>,
 ) {
     let control = match control {
         Some(it) => it,
         _ => return,
     };
     if event.iter().next().is_some() {
-        eprintln!("Closing Server");
+        info!("Closing server");
+        // Otherwise the host's client would try to reconnect to a server
+        // that's gone.
+        disconnect_events.send(DisconnectClientEvent);
         if control.blocking_send(ServerControl::CloseServer).is_err() {
-            eprintln!("Couldn't close server");
+            error!("Couldn't close server");
+        }
+        commands.remove_resource::<Sender<ServerControl>>();
+        commands.remove_resource::<LocalHost>();
+        commands.remove_resource::<HostUploads>();
+        if server_state.pop().is_err() {
+            error!("Failed to set host state");
         }
     }
 }
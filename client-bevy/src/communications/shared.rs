@@ -1,3 +1,10 @@
+use client_lib::ChatLog;
+use protocol::{
+    AssetData, AssetHash, AssetInfo, ChatChannel, ClientMessage, Credentials, Presence, Role,
+    UserId,
+};
+use std::{collections::BTreeMap, path::Path};
+
 #[derive(Default)]
 pub struct CommunicationResource {
     pub state: CommunicationState,
@@ -6,8 +13,14 @@ pub struct CommunicationResource {
 
 pub enum CommunicationState {
     None,
-    Server { port: u16 },
-    Client { url: String },
+    /// Hosting `campaign` for players to join on `port`.
+    Server {
+        port: u16,
+        campaign: String,
+    },
+    Client {
+        url: String,
+    },
 }
 
 impl Default for CommunicationState {
@@ -31,16 +44,157 @@ pub enum ClientState {
 
 pub struct CloseServerEvent;
 
+/// Asks the client to drop its connection to the server.
+pub struct DisconnectClientEvent;
+
+/// What the connection window shows about a client connection that has
+/// dropped, if anything.
+#[derive(Default)]
+pub struct ConnectionStatus {
+    pub message: Option<String>,
+}
+
+/// What the player types into the connection window to log in.
+#[derive(Default)]
+pub struct LoginDetails {
+    pub username: String,
+    /// A password, or an invite token if `invite` is set.
+    pub secret: String,
+    pub invite: bool,
+    /// Left empty to join whichever campaign the server picks.
+    pub campaign: String,
+}
+
+impl LoginDetails {
+    pub fn campaign(&self) -> Option<String> {
+        match self.campaign.trim() {
+            "" => None,
+            campaign => Some(campaign.to_string()),
+        }
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        if self.invite {
+            Credentials::Invite {
+                username: self.username.clone(),
+                token: self.secret.clone(),
+            }
+        } else {
+            Credentials::Password {
+                username: self.username.clone(),
+                password: self.secret.clone(),
+            }
+        }
+    }
+}
+
+/// The invite players need to join a server hosted from this client.
+#[derive(Default)]
+pub struct HostInvite {
+    pub token: Option<String>,
+}
+
+/// Who the server says we are, once it has let us in.
+#[derive(Default)]
+pub struct Identity {
+    pub user: Option<UserId>,
+    pub role: Role,
+}
+
+/// Who else is connected to the campaign, as far as we've heard.
+#[derive(Default)]
+pub struct Players {
+    pub players: BTreeMap<UserId, PlayerInfo>,
+}
+
+#[derive(Default)]
+pub struct PlayerInfo {
+    /// `None` until the server has said who they are.
+    pub name: Option<String>,
+    /// The player's last round trip to the server.
+    pub latency: Option<u32>,
+}
+
+impl Players {
+    pub fn observe(&mut self, presence: &Presence) {
+        match presence {
+            Presence::Joined { user, name } => {
+                self.players.entry(*user).or_default().name = Some(name.clone());
+            }
+            Presence::Left { user } => {
+                self.players.remove(user);
+            }
+            Presence::Latency { user, millis } => {
+                self.players.entry(*user).or_default().latency = Some(*millis);
+            }
+        }
+    }
+}
+
+/// The chat message being written, and who it's for.
 #[derive(Default)]
 pub struct PendingMessage {
     pub value: String,
+    pub channel: ChatChannel,
+    pub in_character: bool,
 }
 
 #[derive(Default)]
 pub struct ReceivedMessages {
-    pub messages: Vec<(usize, String)>,
+    pub chat: ChatLog,
+    /// Notices from whoever runs the server.
+    pub announcements: Vec<String>,
 }
 
 pub struct SendMessageEvent {
     pub value: String,
+    pub channel: ChatChannel,
+    pub in_character: bool,
+}
+
+/// Asks the server for the chat before the oldest message we have.
+pub struct LoadOlderChatEvent;
+
+pub struct UploadAssetEvent {
+    pub path: String,
+}
+
+/// The file the game master has picked to upload.
+#[derive(Default)]
+pub struct PendingUpload {
+    pub path: String,
+}
+
+/// The files uploaded to the campaign. Their contents are in the
+/// `AssetCache` once fetched.
+#[derive(Default)]
+pub struct CampaignAssets {
+    pub assets: BTreeMap<AssetHash, AssetInfo>,
+}
+
+/// Reads a file from disk into an upload, working out its type from the
+/// extension. The server checks the contents match.
+pub fn read_upload(path: &str) -> Result<ClientMessage, String> {
+    let path = Path::new(path.trim());
+    let mime = match path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => return Err(format!("{} isn't a png, jpeg, gif or webp", path.display())),
+    };
+    let data = std::fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    Ok(ClientMessage::UploadAsset {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        mime: mime.to_string(),
+        data: AssetData(data),
+    })
 }
@@ -4,6 +4,7 @@ mod map_construction;
 
 use bevy::{
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    log::{Level, LogSettings},
     pbr::wireframe::WireframePlugin,
     prelude::*,
     render::options::WgpuOptions,
@@ -24,15 +25,23 @@ pub fn run() {
 
     let mut app = App::new();
 
-    app.insert_resource(Msaa { samples: 4 })
-        .add_plugins(DefaultPlugins)
-        .add_plugin(EguiPlugin)
-        .add_plugin(MapConstructionPlugin)
-        .add_plugin(CommunicationsPlugin)
-        .add_plugin(camera::CameraPlugin)
-        .add_plugin(LogDiagnosticsPlugin::default())
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
-        .add_startup_system(setup);
+    // Bevy's log plugin collects the client library's tracing too, writing to
+    // the terminal natively and the browser console on the web.
+    app.insert_resource(LogSettings {
+        level: Level::INFO,
+        filter: String::from(
+            "wgpu=error,client_bevy=debug,client_lib=debug,server_lib=info,protocol=info",
+        ),
+    })
+    .insert_resource(Msaa { samples: 4 })
+    .add_plugins(DefaultPlugins)
+    .add_plugin(EguiPlugin)
+    .add_plugin(MapConstructionPlugin)
+    .add_plugin(CommunicationsPlugin)
+    .add_plugin(camera::CameraPlugin)
+    .add_plugin(LogDiagnosticsPlugin::default())
+    .add_plugin(FrameTimeDiagnosticsPlugin::default())
+    .add_startup_system(setup);
 
     #[cfg(not(target_arch = "wasm32"))]
     {
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use bevy::{
+    log::{debug, trace},
     math::{Vec2, Vec3},
     pbr::{wireframe::Wireframe, PbrBundle, StandardMaterial},
     prelude::{
@@ -158,12 +159,12 @@ fn generate_boundary_points(
         .iter()
         .fold(None, |prev, brush| brush.1.bounds(prev));
     if let Some(bounds) = bounds {
-        println!("Generating Boundary");
+        debug!("Generating Boundary");
         let mut points_to_query = vec![(bounds.1 - bounds.0) / 2. + bounds.0];
         let mut query_radius = (bounds.1 - bounds.0).max_element() / 2.;
         let inner_levels = zone_settings.boundary_width;
         while query_radius > inner_levels {
-            println!("Working through a level: {}", points_to_query.len());
+            trace!("Working through a level: {}", points_to_query.len());
             let internal_query = points_to_query.clone();
             let mut next_query = Vec::<Vec2>::new();
             let halfway = query_radius / 2.;
@@ -185,7 +186,7 @@ fn generate_boundary_points(
         let halfway = query_radius / 2.;
         let boundary_adjsutment = zone_settings.boundary_width / 2.;
 
-        println!(
+        debug!(
             "Getting points w/ radius {}, points {}",
             halfway,
             points_to_query.len()
@@ -238,7 +239,7 @@ fn triangulate_grid(
 ) {
     grids.for_each(|(entity, contents, _grid, parent)| {
         if let Some(diagram) = CentroidDiagram::<GridPoint>::new(&contents.points) {
-            println!("Triangulated a zone {:?}", entity);
+            debug!("Triangulated a zone {:?}", entity);
 
             let indices = diagram
                 .delaunay
@@ -348,7 +349,7 @@ mod tests {
         let result = find_center_point(Vec2::ZERO, 1., -0.5, 0.5, 0.5, 1.06066);
         assert!(result.is_some());
         if let Some((position, normal)) = result {
-            println!("position {} normal {}", position, normal);
+            trace!("position {} normal {}", position, normal);
             assert!(assert_eq_f32(position.x, 0.333_333_34));
             assert!(assert_eq_f32(position.y, 0.333_333_34));
             assert!(assert_eq_f32(normal.x, FRAC_1_SQRT_2));
@@ -2,6 +2,7 @@
 use std::collections::HashMap;
 
 use bevy::{
+    log::debug,
     math::{Vec2, Vec3, Vec4, Vec4Swizzles},
     prelude::{
         Bundle, Changed, Color, Commands, Component, CoreStage, Entity, GlobalTransform, Or,
@@ -178,7 +179,7 @@ fn adjust_zone_hierarchy(
         val.sort_by(|a, b| a.1.order.cmp(&b.1.order));
         new_child_map.insert(key, val.iter().map(|(e, _)| *e).collect::<_>());
     }
-    println!("Setting zone hierarchy");
+    debug!("Setting zone hierarchy");
     commands.insert_resource(ZoneHierarchy {
         zone_by_order_id,
         reverse_ordered_zones: {
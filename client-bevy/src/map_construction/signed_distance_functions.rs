@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use bevy::{
+    log::{trace, warn},
     math::{Mat4, Vec2, Vec3, Vec4},
     prelude::{
         AddAsset, Assets, Changed, Commands, Component, CoreStage, Entity, GlobalTransform, Handle,
@@ -429,7 +430,7 @@ fn generate_gpu_node(
 
         (new_id as i32, Some(new_node))
     } else {
-        println!("Where's my node?");
+        warn!("Couldn't find the node to add to");
         (-1, None)
     }
 }
@@ -474,7 +475,7 @@ mod tests {
 
         assert!(gpu_object.is_ok());
         if let Ok(tree) = gpu_object {
-            println!("GPU OBJECT: {:?}", tree);
+            trace!("GPU OBJECT: {:?}", tree);
             let tree = &tree.tree;
             assert!(!tree.is_empty());
             let root = &tree[0];
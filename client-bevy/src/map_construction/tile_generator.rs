@@ -75,7 +75,7 @@ fn mark_dirt_tiles(
         let min_y = (boundary.min.y / tile_size).floor() as i32;
         let max_x = (boundary.max.x / tile_size).ceil() as i32;
         let max_y = (boundary.max.y / tile_size).ceil() as i32;
-        println!(
+        debug!(
             "Updating zone! {} {} {},{} {},{}",
             zone.name, zone.level, min_x, min_y, max_x, max_y
         );
@@ -151,7 +151,7 @@ fn setup_dirty_tiles(
                 zones: tile_zones,
             });
         } else {
-            println!("Removing tile {:?}", position.index);
+            trace!("Removing tile {:?}", position.index);
             tile_grid.tiles.remove(&position.index);
             commands.entity(entity).despawn_recursive();
         }
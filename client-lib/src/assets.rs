@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use protocol::{
+    asset_hash, check_asset, AssetError, AssetHash, AssetInfo, ClientMessage, MAX_ASSET_SIZE,
+};
+
+/// How many bytes of assets a client keeps by default.
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How many assets a client asks for a second. Kept well under what the
+/// server lets a client send, so a campaign full of assets doesn't get it
+/// disconnected for flooding, and there's room left for everything else.
+const FETCHES_PER_SECOND: f64 = 5.0;
+/// How many assets can be asked for at once after a quiet spell.
+const FETCH_BURST: f64 = 10.0;
+/// How many assets can be on their way at once.
+const MAX_FETCHING: usize = 4;
+
+/// The campaign assets a client has downloaded, keyed by hash. The least
+/// recently fetched are dropped first once the cache is over its limit.
+///
+/// Assets that are wanted are queued and fetched a few at a time, see
+/// [`AssetCache::next_fetch`].
+#[derive(Debug)]
+pub struct AssetCache {
+    limit: usize,
+    used: usize,
+    assets: HashMap<AssetHash, (AssetInfo, Vec<u8>)>,
+    order: VecDeque<AssetHash>,
+    wanted: VecDeque<AssetInfo>,
+    requested: HashMap<AssetHash, AssetInfo>,
+    /// How many more fetches can be sent right now.
+    allowance: f64,
+}
+
+impl Default for AssetCache {
+    fn default() -> Self {
+        AssetCache::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl AssetCache {
+    pub fn new(limit: usize) -> Self {
+        AssetCache {
+            limit,
+            used: 0,
+            assets: HashMap::new(),
+            order: VecDeque::new(),
+            wanted: VecDeque::new(),
+            requested: HashMap::new(),
+            allowance: FETCH_BURST,
+        }
+    }
+
+    /// Queues an asset to be fetched, unless it's cached, already wanted or
+    /// too big to accept.
+    pub fn want(&mut self, info: &AssetInfo) {
+        if self.assets.contains_key(&info.hash)
+            || self.requested.contains_key(&info.hash)
+            || self.wanted.iter().any(|wanted| wanted.hash == info.hash)
+            || info.size > MAX_ASSET_SIZE.min(self.limit) as u64
+        {
+            return;
+        }
+        self.wanted.push_back(info.clone());
+    }
+
+    /// Lets more fetches be sent as time passes.
+    pub fn tick(&mut self, elapsed: Duration) {
+        self.allowance =
+            (self.allowance + elapsed.as_secs_f64() * FETCHES_PER_SECOND).min(FETCH_BURST);
+    }
+
+    /// The message asking for the next wanted asset, or `None` if there's
+    /// nothing wanted or it isn't time to ask for more yet.
+    pub fn next_fetch(&mut self) -> Option<ClientMessage> {
+        if self.allowance < 1.0 || self.requested.len() >= MAX_FETCHING {
+            return None;
+        }
+        let info = self.wanted.pop_front()?;
+        self.allowance -= 1.0;
+        let hash = info.hash.clone();
+        self.requested.insert(hash.clone(), info);
+        Some(ClientMessage::FetchAsset { hash })
+    }
+
+    /// Puts an asset back at the front of the queue after its fetch couldn't
+    /// be sent.
+    pub fn unsent(&mut self, hash: &str) {
+        if let Some(info) = self.requested.remove(hash) {
+            self.wanted.push_front(info);
+            self.allowance += 1.0;
+        }
+    }
+
+    /// Asks again for everything on its way, since the connection it was
+    /// asked for on has dropped.
+    pub fn retry_fetches(&mut self) {
+        for (_, info) in self.requested.drain() {
+            self.wanted.push_front(info);
+        }
+    }
+
+    /// Stops fetching anything, for when the client moves to another server.
+    /// What's already cached is kept.
+    pub fn cancel_fetches(&mut self) {
+        self.requested.clear();
+        self.wanted.clear();
+    }
+
+    /// Stores an asset the server sent, as long as it's one we asked for and
+    /// it is what the campaign said it would be.
+    pub fn receive(&mut self, hash: AssetHash, data: Vec<u8>) -> Result<(), AssetError> {
+        let info = self
+            .requested
+            .remove(&hash)
+            .ok_or_else(|| AssetError::Unknown(hash.clone()))?;
+        if asset_hash(&data) != hash {
+            return Err(AssetError::Corrupt(hash));
+        }
+        check_asset(&info.mime, &data)?;
+        self.used += data.len();
+        self.order.push_back(hash.clone());
+        self.assets.insert(hash, (info, data));
+        while self.used > self.limit {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    if let Some((_, data)) = self.assets.remove(&oldest) {
+                        self.used -= data.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives up on an asset the server couldn't send. It can be wanted again
+    /// later.
+    pub fn failed(&mut self, hash: &str) {
+        self.requested.remove(hash);
+    }
+
+    pub fn get(&self, hash: &str) -> Option<(&AssetInfo, &[u8])> {
+        self.assets
+            .get(hash)
+            .map(|(info, data)| (info, data.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(contents: &[u8]) -> (AssetInfo, Vec<u8>) {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(contents);
+        let info = AssetInfo {
+            hash: asset_hash(&data),
+            name: String::from("token.png"),
+            mime: String::from("image/png"),
+            size: data.len() as u64,
+        };
+        (info, data)
+    }
+
+    /// Fetches everything due, as the client would each frame.
+    fn fetch(cache: &mut AssetCache) -> Vec<AssetHash> {
+        std::iter::from_fn(|| cache.next_fetch())
+            .map(|msg| match msg {
+                ClientMessage::FetchAsset { hash } => hash,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assets_are_requested_once_and_checked() {
+        let mut cache = AssetCache::default();
+        let (info, data) = png(b"hero");
+        cache.want(&info);
+        cache.want(&info);
+        assert_eq!(fetch(&mut cache), [&info.hash[..]]);
+        cache.want(&info);
+        assert!(fetch(&mut cache).is_empty());
+        assert_eq!(
+            cache.receive(info.hash.clone(), b"tampered".to_vec()),
+            Err(AssetError::Corrupt(info.hash.clone()))
+        );
+        cache.want(&info);
+        assert_eq!(fetch(&mut cache), [&info.hash[..]]);
+        cache.receive(info.hash.clone(), data.clone()).unwrap();
+        assert_eq!(
+            cache.get(&info.hash).map(|(_, bytes)| bytes),
+            Some(&data[..])
+        );
+        cache.want(&info);
+        assert!(fetch(&mut cache).is_empty());
+
+        let (unasked, data) = png(b"villain");
+        assert!(cache.receive(unasked.hash, data).is_err());
+    }
+
+    #[test]
+    fn fetches_are_paced() {
+        let mut cache = AssetCache::default();
+        let assets: Vec<_> = (0..20).map(|i| png(&[i]).0).collect();
+        for info in &assets {
+            cache.want(info);
+        }
+        let hashes = |range: std::ops::Range<usize>| -> Vec<AssetHash> {
+            assets[range].iter().map(|info| info.hash.clone()).collect()
+        };
+        // Only a few at a time, and more once those have arrived.
+        assert_eq!(fetch(&mut cache), hashes(0..MAX_FETCHING));
+        for hash in hashes(0..MAX_FETCHING) {
+            cache.failed(&hash);
+        }
+        assert_eq!(fetch(&mut cache), hashes(4..8));
+        for hash in hashes(4..8) {
+            cache.failed(&hash);
+        }
+        // The burst is used up, so the rest wait their turn.
+        assert_eq!(fetch(&mut cache), hashes(8..10));
+        cache.failed(&assets[8].hash);
+        assert!(fetch(&mut cache).is_empty());
+        cache.tick(Duration::from_millis(200));
+        assert_eq!(fetch(&mut cache), hashes(10..11));
+
+        // Fetches that didn't go out, or went out on a dropped connection,
+        // are sent again first.
+        cache.unsent(&assets[10].hash);
+        cache.retry_fetches();
+        cache.tick(Duration::from_secs(10));
+        assert_eq!(fetch(&mut cache).len(), MAX_FETCHING);
+        assert!(cache.requested.contains_key(&assets[9].hash));
+        assert!(cache.requested.contains_key(&assets[10].hash));
+    }
+
+    #[test]
+    fn oldest_assets_make_room() {
+        let (first, first_data) = png(b"first");
+        let (second, second_data) = png(b"second");
+        let mut cache = AssetCache::new(first_data.len() + second_data.len() - 1);
+        cache.want(&first);
+        cache.want(&second);
+        fetch(&mut cache);
+        cache.receive(first.hash.clone(), first_data).unwrap();
+        cache.receive(second.hash.clone(), second_data).unwrap();
+        assert!(cache.get(&first.hash).is_none());
+        assert!(cache.get(&second.hash).is_some());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use protocol::{ChatEntry, ClientMessage};
+
+/// How many chat messages a client keeps by default.
+pub const DEFAULT_CHAT_LOG_SIZE: usize = 500;
+
+/// The chat a client has seen, oldest first. Once over its limit the oldest
+/// messages are dropped, and can be paged back in from the server.
+#[derive(Debug)]
+pub struct ChatLog {
+    limit: usize,
+    entries: BTreeMap<u64, ChatEntry>,
+    /// Whether the server may have older messages than we hold.
+    more: bool,
+    /// Whether we're waiting on a page of history.
+    paging: bool,
+    /// Whether that page is the latest, asked for after a snapshot.
+    catching_up: bool,
+}
+
+impl Default for ChatLog {
+    fn default() -> Self {
+        ChatLog::new(DEFAULT_CHAT_LOG_SIZE)
+    }
+}
+
+impl ChatLog {
+    pub fn new(limit: usize) -> Self {
+        ChatLog {
+            limit,
+            entries: BTreeMap::new(),
+            more: false,
+            paging: false,
+            catching_up: false,
+        }
+    }
+
+    /// Adds a message, replacing any we already had with the same id.
+    pub fn push(&mut self, entry: ChatEntry) {
+        self.entries.insert(entry.id, entry);
+        while self.entries.len() > self.limit {
+            self.entries.pop_first();
+            self.more = true;
+        }
+    }
+
+    /// Starts again from the chat in a snapshot. Snapshots only carry the
+    /// latest public chat, so this returns the message asking for the latest
+    /// page of everything we can read to go with it.
+    pub fn reload(&mut self, entries: Vec<ChatEntry>) -> ClientMessage {
+        self.entries.clear();
+        self.more = false;
+        self.paging = true;
+        self.catching_up = true;
+        for entry in entries {
+            self.push(entry);
+        }
+        ClientMessage::ChatHistory { before: None }
+    }
+
+    /// Adds a page of history sent by the server.
+    pub fn page(&mut self, entries: Vec<ChatEntry>, more: bool) {
+        // Private messages from before the latest page aren't in the
+        // snapshot, so public ones from then would show with gaps between
+        // them. They're paged back in along with the rest instead.
+        if std::mem::take(&mut self.catching_up) && more {
+            if let Some(oldest) = entries.first() {
+                self.entries = self.entries.split_off(&oldest.id);
+            }
+        }
+        self.paging = false;
+        self.more = more;
+        for entry in entries {
+            self.push(entry);
+        }
+    }
+
+    /// The message asking for the page before our oldest message, or `None`
+    /// if there's nothing older, a page is already on its way or there's no
+    /// room to keep it.
+    pub fn request_older(&mut self) -> Option<ClientMessage> {
+        if !self.can_load_older() {
+            return None;
+        }
+        self.paging = true;
+        Some(ClientMessage::ChatHistory {
+            before: self.entries.keys().next().copied(),
+        })
+    }
+
+    pub fn can_load_older(&self) -> bool {
+        self.more && !self.paging && self.entries.len() < self.limit
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ChatEntry> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64) -> ChatEntry {
+        ChatEntry {
+            id,
+            text: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(log: &ChatLog) -> Vec<u64> {
+        log.entries().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn the_oldest_messages_make_way_for_new_ones() {
+        let mut log = ChatLog::new(3);
+        for id in [2, 1, 3, 3, 4] {
+            log.push(entry(id));
+        }
+        assert_eq!(ids(&log), [2, 3, 4]);
+        // Full, so there's nowhere to put older messages.
+        assert_eq!(log.request_older(), None);
+    }
+
+    #[test]
+    fn history_is_paged_in_before_the_oldest_message() {
+        let mut log = ChatLog::new(10);
+        assert_eq!(
+            log.reload(vec![entry(5), entry(7)]),
+            ClientMessage::ChatHistory { before: None }
+        );
+        // Only one page at a time.
+        assert_eq!(log.request_older(), None);
+
+        // The latest page includes what the snapshot left out, and anything
+        // older is paged in again so none of it is missed.
+        log.page(vec![entry(6), entry(7)], true);
+        assert_eq!(ids(&log), [6, 7]);
+        assert_eq!(
+            log.request_older(),
+            Some(ClientMessage::ChatHistory { before: Some(6) })
+        );
+
+        log.page(vec![entry(3), entry(4), entry(5)], false);
+        assert_eq!(ids(&log), [3, 4, 5, 6, 7]);
+        assert_eq!(log.request_older(), None);
+    }
+}
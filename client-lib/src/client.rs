@@ -1,186 +1,370 @@
 use crossbeam_channel::unbounded;
 use crossbeam_channel::{Receiver, Sender};
-use serde::{de::DeserializeOwned, Serialize};
-
-#[cfg(any(feature = "native", feature = "web"))]
-use futures_util::{SinkExt, StreamExt};
-
-#[cfg(feature = "native")]
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-
+use futures_timer::Delay;
+use protocol::{
+    ClientMessage, Codec, CodecError, Credentials, Frame, Rejection, ResumeToken, ServerMessage,
+    SyncAction, SyncTracker, HEARTBEAT_TIMEOUT, PROTOCOL_VERSION,
+};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use url::Url;
-#[cfg(feature = "web")]
-use ws_stream_wasm::*;
+
+use crate::{ConnectionEvent, Incoming, ReconnectPolicy, Socket, Transport, WebSocket};
 
 #[derive(Debug, Clone)]
 pub enum ClientControl {
     Disconnect,
 }
 
+#[derive(Debug)]
 pub enum ClientError {
     Generic(String),
     InvalidAddress,
     FailedToConnect,
+    Rejected(Rejection),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Generic(message) => write!(f, "{}", message),
+            ClientError::InvalidAddress => write!(f, "that isn't a server address"),
+            ClientError::FailedToConnect => write!(f, "couldn't reach the server"),
+            ClientError::Rejected(rejection) => write!(f, "{}", rejection),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Why a connection to the server came to an end.
+enum Ended {
+    /// The game asked to disconnect.
+    Closed,
+    /// The socket went away underneath us.
+    Dropped,
 }
 
 #[derive(Debug)]
-pub struct Client<T>
-where
-    T: Clone + Send + Serialize + DeserializeOwned + 'static,
-{
+pub struct Client<T = WebSocket> {
     pub url: Url,
-    pub sender: tokio::sync::mpsc::Sender<T>,
-    pub receiver: Receiver<(usize, T)>,
+    /// The codec asked for during the handshake - the server may still fall
+    /// back to JSON.
+    pub codec: Codec,
+    /// Who to log in as - sent again each time the client reconnects.
+    pub login: Option<Credentials>,
+    /// The campaign to join, or `None` for the server's default.
+    pub campaign: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    pub sender: tokio::sync::mpsc::Sender<ClientMessage>,
+    pub receiver: Receiver<ServerMessage>,
+    pub events: Receiver<ConnectionEvent>,
     pub control_sender: tokio::sync::mpsc::Sender<ClientControl>,
-    sender_endpoint: tokio::sync::mpsc::Receiver<T>,
+    sender_endpoint: tokio::sync::mpsc::Receiver<ClientMessage>,
     control_receiver: tokio::sync::mpsc::Receiver<ClientControl>,
-    receiver_endpoint: Sender<(usize, T)>,
+    receiver_endpoint: Sender<ServerMessage>,
+    events_endpoint: Sender<ConnectionEvent>,
+    transport: T,
 }
 
-impl<T> Client<T>
-where
-    T: Clone + Send + Serialize + DeserializeOwned + 'static,
-{
+#[cfg(any(feature = "native", feature = "web"))]
+impl Client {
+    /// A client that connects over a websocket.
     pub fn new(url: String) -> Result<Self, ClientError> {
+        Client::with_transport(url, WebSocket)
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn with_transport(url: String, transport: T) -> Result<Self, ClientError> {
         let url = Url::parse(&url);
         if url.is_err() {
             return Err(ClientError::InvalidAddress);
         }
         let url = url.unwrap();
-        let (client_to_server_sender, client_to_server_receiver) = unbounded::<(usize, T)>();
+        let (client_to_server_sender, client_to_server_receiver) = unbounded::<ServerMessage>();
         let (server_to_client_sender, server_to_client_receiver) =
-            tokio::sync::mpsc::channel::<T>(100);
+            tokio::sync::mpsc::channel::<ClientMessage>(100);
         let (control_sender, control_receiver) = tokio::sync::mpsc::channel::<ClientControl>(100);
+        let (events_sender, events_receiver) = unbounded::<ConnectionEvent>();
         Ok(Client {
             url,
+            codec: Codec::MessagePack,
+            login: None,
+            campaign: None,
+            reconnect: ReconnectPolicy::default(),
             sender: server_to_client_sender,
             sender_endpoint: server_to_client_receiver,
             control_receiver,
             control_sender,
             receiver: client_to_server_receiver,
             receiver_endpoint: client_to_server_sender,
+            events: events_receiver,
+            events_endpoint: events_sender,
+            transport,
         })
     }
 
-    #[cfg(feature = "native")]
+    /// Connects to the server and keeps the connection going, reconnecting
+    /// with backoff whenever it drops, until the game disconnects or the
+    /// reconnect policy runs out of attempts. If the first connection can't be
+    /// made the client stops straight away, since the address is probably
+    /// wrong. Each step is reported through `events`.
     pub async fn start(self) -> Result<(), ClientError> {
-        let stream = connect_async(&self.url).await;
-        match stream {
-            Ok((stream, _)) => {
-                println!("Successfully Connected to {}", &self.url);
-                let (mut write, mut read) = stream.split();
-                let mut control_receiver = self.control_receiver;
-                let mut sender_endpoint = self.sender_endpoint;
-                let receiver_endpoint = self.receiver_endpoint;
-                loop {
-                    tokio::select! {
-                        msg = read.next() => {
-                            match msg {
-                                Some(msg) => {
-                                    if msg.is_err() {
-                                        continue;
-                                    }
-                                    let msg = msg.unwrap();
-                                    match msg {
-                                        Message::Text(msg) => {
-                                            if let Ok(value) = serde_json::from_str(&msg) {
-                                                if receiver_endpoint.send(value).is_err() {
-                                                    eprintln!("Failed to send message to game");
-                                                }
-                                            }
-                                        },
-                                        _ => {
-                                            eprintln!("Couldn't parse - message isn't text");
-                                        }
-                                    }
-                                },
-                                None => break,
-                            }
-                        },
-                        send_msg = sender_endpoint.recv() => {
-                            if let Some(msg) = send_msg {
-                                let msg = serde_json::to_string(&msg);
-                                if msg.is_err() {
-                                    continue;
-                                }
-                                let msg = msg.unwrap();
-                                println!("Sending message {}", msg);
-                                let result = write.send(Message::Text(msg)).await;
-                                if result.is_err() {
-                                    eprintln!("Failed to send message");
-                                }
-                            }
-                        },
-                        _ = control_receiver.recv() => {
-                            break;
-                        },
-                    }
+        let span = info_span!("connection", url = %self.url);
+        self.run().instrument(span).await
+    }
+
+    async fn run(mut self) -> Result<(), ClientError> {
+        let mut resume = None;
+        let mut sync = SyncTracker::default();
+        let mut attempt = 0;
+        self.notify(ConnectionEvent::Connecting);
+        loop {
+            match self.connect(&mut resume, &mut sync).await {
+                Ok(Ended::Closed) => {
+                    info!("Disconnected");
+                    self.notify(ConnectionEvent::Disconnected);
+                    return Ok(());
+                }
+                Ok(Ended::Dropped) => {
+                    warn!("Lost connection");
+                    attempt = 1;
+                }
+                // Only worth retrying if we've got in before.
+                Err(ClientError::FailedToConnect) if resume.is_some() => attempt += 1,
+                Err(error) => {
+                    self.notify(ConnectionEvent::Failed {
+                        reason: error.to_string(),
+                    });
+                    return Err(error);
                 }
-                Ok(())
             }
-            Err(_) => {
-                eprintln!("Failed to connect to {}", &self.url);
-                Err(ClientError::FailedToConnect)
+            let delay = match self.reconnect.delay(attempt) {
+                Some(delay) => delay,
+                None => {
+                    warn!("Giving up reconnecting");
+                    self.notify(ConnectionEvent::GaveUp);
+                    return Err(ClientError::FailedToConnect);
+                }
+            };
+            info!(attempt, ?delay, "Reconnecting");
+            self.notify(ConnectionEvent::Reconnecting { attempt, delay });
+            tokio::select! {
+                _ = Delay::new(delay) => {},
+                _ = self.control_receiver.recv() => {
+                    self.notify(ConnectionEvent::Disconnected);
+                    return Ok(());
+                },
             }
         }
     }
 
-    #[cfg(feature = "web")]
-    pub async fn start(mut self) -> Result<(), ClientError> {
-        let stream = WsMeta::connect(&self.url, None).await;
-        match stream {
-            Ok((_, stream)) => {
-                println!("Successfully Connected to {}", &self.url);
-                let (mut write, mut read) = stream.split();
-                let mut control_receiver = self.control_receiver;
-                let mut sender_endpoint = self.sender_endpoint;
-                let mut receiver_endpoint = self.receiver_endpoint;
-                loop {
-                    tokio::select! {
-                        msg = read.next() => {
-                            match msg {
-                                Some(msg) => {
-                                    match msg {
-                                        WsMessage::Text(msg) => {
-                                            if let Ok(value) = serde_json::from_str(&msg) {
-                                                if receiver_endpoint.send(value).is_err() {
-                                                    eprintln!("Failed to send message to game");
-                                                }
-                                            }
-                                        },
-                                        _ => {
-                                            eprintln!("Couldn't parse - message isn't text");
-                                        }
+    async fn connect(
+        &mut self,
+        resume: &mut Option<ResumeToken>,
+        sync: &mut SyncTracker,
+    ) -> Result<Ended, ClientError> {
+        let mut socket = match self.transport.connect(&self.url).await {
+            Ok(socket) => socket,
+            Err(error) => {
+                warn!(%error, "Failed to connect");
+                return Err(error);
+            }
+        };
+        info!("Connected");
+        let hello = hello(
+            self.codec,
+            resume.clone(),
+            self.login.clone(),
+            self.campaign.clone(),
+        )?;
+        if socket.send(hello).await.is_err() {
+            return Err(ClientError::FailedToConnect);
+        }
+        let welcome = loop {
+            match socket.receive().await {
+                Some(Incoming::Frame(frame)) => break frame.decode::<ServerMessage>(),
+                Some(Incoming::Heartbeat) => continue,
+                None => return Err(ClientError::FailedToConnect),
+            }
+        };
+        let codec = self.welcomed(welcome, resume)?;
+        let Client {
+            sender_endpoint,
+            control_receiver,
+            receiver_endpoint,
+            ..
+        } = self;
+        // The server pings every few seconds, so a long silence means the
+        // connection is dead even if the socket hasn't noticed yet. Where
+        // pings can't be seen, the latency updates the server sends after each
+        // one keep it going.
+        let mut idle = Delay::new(HEARTBEAT_TIMEOUT);
+        loop {
+            tokio::select! {
+                incoming = socket.receive() => {
+                    match incoming {
+                        Some(incoming) => {
+                            idle.reset(HEARTBEAT_TIMEOUT);
+                            if let Incoming::Frame(frame) = incoming {
+                                if let Some(resync) = forward(&frame, sync, receiver_endpoint) {
+                                    if let Ok(resync) = codec.encode(&resync) {
+                                        let _ = socket.send(resync).await;
                                     }
-                                },
-                                None => break,
-                            }
-                        },
-                        send_msg = sender_endpoint.recv() => {
-                            if let Some(msg) = send_msg {
-                                let msg = serde_json::to_string(&msg);
-                                if msg.is_err() {
-                                    continue;
-                                }
-                                let msg = msg.unwrap();
-                                println!("Sending message {}", msg);
-                                let result = write.send(WsMessage::Text(msg)).await;
-                                if result.is_err() {
-                                    eprintln!("Failed to send message");
                                 }
                             }
                         },
-                        _ = control_receiver.recv() => {
-                            break;
-                        },
+                        None => return Ok(Ended::Dropped),
                     }
+                },
+                send_msg = sender_endpoint.recv() => {
+                    if let Some(msg) = send_msg {
+                        debug!(kind = msg.kind(), "Sending message");
+                        trace!(?msg, "Message body");
+                        let msg = codec.encode(&msg);
+                        if msg.is_err() {
+                            continue;
+                        }
+                        let msg = msg.unwrap();
+                        let result = socket.send(msg).await;
+                        if result.is_err() {
+                            warn!("Failed to send message");
+                        }
+                    }
+                },
+                _ = &mut idle => {
+                    warn!("Server stopped responding");
+                    return Ok(Ended::Dropped);
+                },
+                _ = control_receiver.recv() => {
+                    return Ok(Ended::Closed);
+                },
+            }
+        }
+    }
+
+    /// Checks the server's reply to our hello and remembers the token to
+    /// resume with next time, letting the UI know how the connection went.
+    /// Switches to the password the server gives out when an invite signs us
+    /// up, since the invite won't let us back in.
+    fn welcomed(
+        &mut self,
+        welcome: Result<ServerMessage, CodecError>,
+        resume: &mut Option<ResumeToken>,
+    ) -> Result<Codec, ClientError> {
+        if let Ok(ServerMessage::Welcome {
+            name,
+            password: Some(password),
+            ..
+        }) = &welcome
+        {
+            self.login = Some(Credentials::Password {
+                username: name.clone(),
+                password: password.clone(),
+            });
+        }
+        let (codec, token, resumed) = check_welcome(welcome, &self.receiver_endpoint)?;
+        self.notify(match (resume.is_some(), resumed) {
+            (false, _) => ConnectionEvent::Connected,
+            (true, true) => ConnectionEvent::Resumed,
+            (true, false) => ConnectionEvent::Rejoined,
+        });
+        *resume = Some(token);
+        Ok(codec)
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        if self.events_endpoint.send(event).is_err() {
+            error!("Failed to send connection event to game");
+        }
+    }
+}
+
+fn hello(
+    codec: Codec,
+    resume: Option<ResumeToken>,
+    login: Option<Credentials>,
+    campaign: Option<String>,
+) -> Result<Frame, ClientError> {
+    Codec::Json
+        .encode(&ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            codecs: vec![codec, Codec::Json],
+            resume,
+            login,
+            campaign,
+        })
+        .map_err(|e| ClientError::Generic(e.to_string()))
+}
+
+/// Hands the server's reply to our hello on to the game, turning a rejection
+/// into an error so the caller knows the connection was refused. Returns the
+/// codec the server picked for the rest of the connection, the token to resume
+/// with, and whether this connection resumed an earlier one.
+fn check_welcome(
+    welcome: Result<ServerMessage, CodecError>,
+    receiver_endpoint: &Sender<ServerMessage>,
+) -> Result<(Codec, ResumeToken, bool), ClientError> {
+    match welcome {
+        Ok(ServerMessage::Rejected(rejection)) => {
+            warn!(%rejection, "Server rejected connection");
+            let _ = receiver_endpoint.send(ServerMessage::Rejected(rejection.clone()));
+            Err(ClientError::Rejected(rejection))
+        }
+        Ok(welcome) => match &welcome {
+            ServerMessage::Welcome {
+                codec,
+                resume,
+                resumed,
+                ..
+            } => {
+                let accepted = (*codec, resume.clone(), *resumed);
+                if receiver_endpoint.send(welcome).is_err() {
+                    error!("Failed to send message to game");
                 }
-                Ok(())
+                Ok(accepted)
             }
-            Err(_) => {
-                eprintln!("Failed to connect to {}", &self.url);
-                Err(ClientError::FailedToConnect)
+            other => Err(ClientError::Generic(format!(
+                "Expected a welcome, got {:?}",
+                other
+            ))),
+        },
+        Err(error) => {
+            warn!(%error, "Couldn't decode server handshake");
+            Err(ClientError::Generic(error.to_string()))
+        }
+    }
+}
+
+/// Passes a message from the server on to the game as long as it's the next
+/// change expected. Returns a resync request to send if some went missing.
+fn forward(
+    frame: &Frame,
+    sync: &mut SyncTracker,
+    receiver_endpoint: &Sender<ServerMessage>,
+) -> Option<ClientMessage> {
+    match frame.decode::<ServerMessage>() {
+        Ok(value) => {
+            debug!(kind = value.kind(), "Received message");
+            trace!(?value, "Message body");
+            match sync.observe(&value) {
+                SyncAction::Apply => {
+                    if receiver_endpoint.send(value).is_err() {
+                        error!("Failed to send message to game");
+                    }
+                    None
+                }
+                SyncAction::Ignore => None,
+                SyncAction::Resync { since } => {
+                    info!(since, "Missed changes - asking to resync");
+                    Some(ClientMessage::Resync { since })
+                }
             }
         }
+        Err(error) => {
+            warn!(%error, "Couldn't decode message from server");
+            trace!(?frame, "Undecodable frame");
+            None
+        }
     }
 }
@@ -0,0 +1,122 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+use protocol::{SessionAdvert, ADVERT_TIMEOUT, DISCOVERY_PORT};
+
+/// A game someone is hosting on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredSession {
+    /// Where the host's server is listening.
+    pub address: SocketAddr,
+    pub campaign: String,
+    pub players: usize,
+    last_seen: Instant,
+}
+
+impl DiscoveredSession {
+    /// The address to connect a client to.
+    pub fn url(&self) -> String {
+        format!("ws://{}/server", self.address)
+    }
+}
+
+/// Listens for hosts advertising their games on the local network.
+#[derive(Debug)]
+pub struct Discovery {
+    socket: UdpSocket,
+    sessions: BTreeMap<SocketAddr, DiscoveredSession>,
+}
+
+impl Discovery {
+    /// Listens on the port hosts broadcast to.
+    pub fn listen() -> io::Result<Self> {
+        Discovery::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)))
+    }
+
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Discovery {
+            socket,
+            sessions: BTreeMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Reads whatever adverts have arrived and forgets hosts that have gone
+    /// quiet. Never waits, so it can be called every frame.
+    pub fn poll(&mut self, now: Instant) {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    if let Some(advert) = SessionAdvert::decode(&buffer[..len]) {
+                        let address = SocketAddr::new(from.ip(), advert.port);
+                        self.sessions.insert(
+                            address,
+                            DiscoveredSession {
+                                address,
+                                campaign: advert.campaign,
+                                players: advert.players,
+                                last_seen: now,
+                            },
+                        );
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                // Errors from earlier sends can turn up here - they don't
+                // stop anything else arriving.
+                Err(_) => continue,
+            }
+        }
+        self.sessions
+            .retain(|_, session| now.saturating_duration_since(session.last_seen) < ADVERT_TIMEOUT);
+    }
+
+    /// The sessions heard from lately, ordered by address.
+    pub fn sessions(&self) -> impl Iterator<Item = &DiscoveredSession> {
+        self.sessions.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn adverts_are_listed_until_they_stop() {
+        let mut discovery = Discovery::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let advert = SessionAdvert::new(String::from("shire"), 4867, 2);
+        host.send_to(&advert.encode(), discovery.local_addr().unwrap())
+            .unwrap();
+        host.send_to(b"not an advert", discovery.local_addr().unwrap())
+            .unwrap();
+
+        let start = Instant::now();
+        let mut sessions = Vec::new();
+        for _ in 0..50 {
+            discovery.poll(start);
+            sessions = discovery.sessions().cloned().collect();
+            if !sessions.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].campaign, "shire");
+        assert_eq!(sessions[0].players, 2);
+        assert_eq!(sessions[0].url(), "ws://127.0.0.1:4867/server");
+
+        discovery.poll(start + ADVERT_TIMEOUT);
+        assert_eq!(discovery.sessions().count(), 0);
+    }
+}
@@ -1,3 +1,15 @@
+mod assets;
+mod chat;
 mod client;
+#[cfg(feature = "native")]
+mod discovery;
+mod reconnect;
+mod transport;
 
+pub use assets::*;
+pub use chat::*;
 pub use client::*;
+#[cfg(feature = "native")]
+pub use discovery::*;
+pub use reconnect::*;
+pub use transport::*;
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+/// What the UI hears about the state of the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// Trying to reach the server for the first time.
+    Connecting,
+    /// The server has let us in.
+    Connected,
+    /// The connection dropped or couldn't be made, and another try is
+    /// coming after `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// Reconnected, and the server recognised us as the same client.
+    Resumed,
+    /// Reconnected, but as a new client - the old session had expired.
+    Rejoined,
+    /// Ran out of attempts, so the client has stopped.
+    GaveUp,
+    /// Stopped without getting in, because the server couldn't be reached
+    /// or turned us away. Not retried, since trying again wouldn't help.
+    Failed { reason: String },
+    /// The game asked to disconnect, and the client has stopped.
+    Disconnected,
+}
+
+/// How hard to try to get a dropped connection back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Gives up after this many failed attempts in a row, or never if `None`.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Never tries again once the connection is lost.
+    pub fn never() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before attempt number `attempt` (counting from one),
+    /// doubling each time up to `max_delay`. `None` means give up.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || self.max_attempts.is_some_and(|max| attempt > max) {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt - 1);
+        Some(
+            self.initial_delay
+                .checked_mul(factor)
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        };
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 10, 10]
+                .iter()
+                .map(|secs| Some(Duration::from_secs(*secs)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(policy.delay(200), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(2),
+            ..Default::default()
+        };
+        assert!(policy.delay(2).is_some());
+        assert_eq!(policy.delay(3), None);
+        assert_eq!(ReconnectPolicy::never().delay(1), None);
+    }
+}
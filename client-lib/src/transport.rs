@@ -0,0 +1,221 @@
+use std::{future::Future, io};
+
+use futures_util::{SinkExt, StreamExt};
+use protocol::Frame;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message},
+    WebSocketStream,
+};
+use url::Url;
+#[cfg(feature = "web")]
+use ws_stream_wasm::*;
+
+use crate::ClientError;
+
+/// Something that arrived from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incoming {
+    Frame(Frame),
+    /// A ping or pong - not a message, but proof the server is still there.
+    Heartbeat,
+}
+
+/// An open connection to the server that carries whole messages.
+pub trait Socket {
+    fn send(&mut self, frame: Frame) -> impl Future<Output = Result<(), ClientError>>;
+
+    /// Waits for the next thing the server sends. `None` means the connection
+    /// is gone.
+    fn receive(&mut self) -> impl Future<Output = Option<Incoming>>;
+}
+
+/// How the client reaches a server. Used again each time it reconnects.
+pub trait Transport {
+    type Socket: Socket;
+
+    fn connect(&mut self, url: &Url) -> impl Future<Output = Result<Self::Socket, ClientError>>;
+}
+
+/// A real websocket - tokio-tungstenite natively, or the browser's own on the
+/// web.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WebSocket;
+
+#[cfg(feature = "native")]
+impl Transport for WebSocket {
+    type Socket = WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn connect(&mut self, url: &Url) -> Result<Self::Socket, ClientError> {
+        match tokio_tungstenite::connect_async(url).await {
+            Ok((stream, _)) => Ok(stream),
+            Err(_) => Err(ClientError::FailedToConnect),
+        }
+    }
+}
+
+#[cfg(feature = "web")]
+impl Transport for WebSocket {
+    type Socket = WsStream;
+
+    async fn connect(&mut self, url: &Url) -> Result<Self::Socket, ClientError> {
+        match WsMeta::connect(url, None).await {
+            Ok((_, stream)) => Ok(stream),
+            Err(_) => Err(ClientError::FailedToConnect),
+        }
+    }
+}
+
+/// Reaches a server running in the same process through an in-memory pipe, so
+/// tests and bots can play without opening sockets. `connect` is called for
+/// each connection and returns the client's end of a new pipe, which is what
+/// `server_lib::LocalConnector::connect` does.
+pub struct Loopback<F> {
+    connect: F,
+}
+
+impl<F> Loopback<F>
+where
+    F: FnMut() -> io::Result<DuplexStream>,
+{
+    pub fn new(connect: F) -> Self {
+        Loopback { connect }
+    }
+}
+
+impl<F> Transport for Loopback<F>
+where
+    F: FnMut() -> io::Result<DuplexStream>,
+{
+    type Socket = WebSocketStream<DuplexStream>;
+
+    async fn connect(&mut self, _url: &Url) -> Result<Self::Socket, ClientError> {
+        let stream = (self.connect)().map_err(|_| ClientError::FailedToConnect)?;
+        Ok(WebSocketStream::from_raw_socket(stream, Role::Client, None).await)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socket for WebSocketStream<S> {
+    async fn send(&mut self, frame: Frame) -> Result<(), ClientError> {
+        let msg = match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(bytes) => Message::Binary(bytes),
+        };
+        SinkExt::send(self, msg)
+            .await
+            .map_err(|error| ClientError::Generic(error.to_string()))
+    }
+
+    async fn receive(&mut self) -> Option<Incoming> {
+        loop {
+            match StreamExt::next(self).await? {
+                Ok(Message::Text(text)) => return Some(Incoming::Frame(Frame::Text(text))),
+                Ok(Message::Binary(bytes)) => return Some(Incoming::Frame(Frame::Binary(bytes))),
+                Ok(Message::Ping(_) | Message::Pong(_)) => return Some(Incoming::Heartbeat),
+                Ok(_) => continue,
+                // Tungstenite can't carry on after an error.
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Browsers answer pings without telling us, so only messages come through.
+#[cfg(feature = "web")]
+impl Socket for WsStream {
+    async fn send(&mut self, frame: Frame) -> Result<(), ClientError> {
+        let msg = match frame {
+            Frame::Text(text) => WsMessage::Text(text),
+            Frame::Binary(bytes) => WsMessage::Binary(bytes),
+        };
+        SinkExt::send(self, msg)
+            .await
+            .map_err(|error| ClientError::Generic(error.to_string()))
+    }
+
+    async fn receive(&mut self) -> Option<Incoming> {
+        StreamExt::next(self).await.map(|msg| {
+            Incoming::Frame(match msg {
+                WsMessage::Text(text) => Frame::Text(text),
+                WsMessage::Binary(bytes) => Frame::Binary(bytes),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, ConnectionEvent};
+    use crossbeam_channel::Receiver;
+    use protocol::{ChatChannel, ClientMessage, Credentials, Role, ServerMessage};
+    use server_lib::{CampaignRegistry, Game, Rooms, Server, UserStore};
+    use std::time::Duration;
+
+    /// Waits for the first message `found` picks something out of.
+    async fn wait_for<T, U>(receiver: &Receiver<T>, found: impl Fn(T) -> Option<U>) -> U {
+        for _ in 0..250 {
+            while let Ok(value) = receiver.try_recv() {
+                if let Some(value) = found(value) {
+                    return value;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out waiting for the server");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_play_through_a_loopback() {
+        let users = UserStore::temporary().unwrap();
+        let frodo = users.add("Frodo", Some("ring")).unwrap();
+        users.set_role("shire", frodo, Role::Player).unwrap();
+        let campaigns = CampaignRegistry::temporary().unwrap();
+        campaigns.ensure("shire").unwrap();
+        let server = Server::new(
+            String::from("127.0.0.1:0"),
+            String::from("shire"),
+            users,
+            campaigns,
+        )
+        .unwrap();
+        let connector = server.local_connector();
+        let game = Game::new(Rooms::temporary().unwrap(), server.clients.clone());
+        let events = server.reciever.clone();
+        tokio::spawn(server.start_local());
+        std::thread::spawn(move || game.run(events));
+
+        let mut client = Client::with_transport(
+            String::from("memory://shire"),
+            Loopback::new(move || connector.connect()),
+        )
+        .unwrap();
+        client.login = Some(Credentials::Password {
+            username: String::from("Frodo"),
+            password: String::from("ring"),
+        });
+        let sender = client.sender.clone();
+        let receiver = client.receiver.clone();
+        let events = client.events.clone();
+        tokio::spawn(client.start());
+
+        wait_for(&events, |event| {
+            (event == ConnectionEvent::Connected).then_some(())
+        })
+        .await;
+        sender
+            .send(ClientMessage::Chat {
+                text: String::from("Second breakfast?"),
+                channel: ChatChannel::Everyone,
+                in_character: false,
+            })
+            .await
+            .unwrap();
+        let text = wait_for(&receiver, |msg| match msg {
+            ServerMessage::Chat { entry, .. } => Some(entry.text),
+            _ => None,
+        })
+        .await;
+        assert_eq!(text, "Second breakfast?");
+    }
+}
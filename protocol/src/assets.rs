@@ -0,0 +1,234 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// The hex SHA-256 of an asset's contents, which is also its name on the
+/// server - so the same image uploaded twice is only stored once.
+pub type AssetHash = String;
+
+/// The largest file that can be uploaded or will be accepted from the server.
+pub const MAX_ASSET_SIZE: usize = 8 * 1024 * 1024;
+
+/// The kinds of file that can be uploaded, with the bytes they start with and
+/// the extension they're stored under.
+const ASSET_TYPES: [(&str, &[u8], &str); 4] = [
+    ("image/png", b"\x89PNG\r\n\x1a\n", "png"),
+    ("image/jpeg", b"\xff\xd8\xff", "jpg"),
+    ("image/gif", b"GIF8", "gif"),
+    ("image/webp", b"RIFF", "webp"),
+];
+
+/// An uploaded file, as listed in the campaign.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetInfo {
+    pub hash: AssetHash,
+    /// The name it was uploaded with, for showing to people.
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+}
+
+impl AssetInfo {
+    /// What the asset is stored as on the server, and served as over HTTP
+    /// under `/assets/`.
+    pub fn file_name(&self) -> String {
+        let extension = ASSET_TYPES
+            .iter()
+            .find(|(mime, _, _)| *mime == self.mime)
+            .map(|(_, _, extension)| *extension)
+            .unwrap_or("bin");
+        format!("{}.{}", self.hash, extension)
+    }
+}
+
+/// The contents of an asset. Only its size shows up in debug output, so
+/// logging a message doesn't dump the whole file. Binary codecs carry it as
+/// raw bytes and JSON as base64, which keeps even the largest upload within
+/// a third more than its size.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct AssetData(pub Vec<u8>);
+
+/// The most an asset's contents grow by when sent as JSON.
+pub const fn encoded_asset_size(size: usize) -> usize {
+    size.div_ceil(3) * 4
+}
+
+impl Serialize for AssetData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AssetData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            base64::decode(text)
+                .map(AssetData)
+                .map_err(de::Error::custom)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = AssetData;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the contents of an asset")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<AssetData, E> {
+        Ok(AssetData(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<AssetData, E> {
+        Ok(AssetData(bytes))
+    }
+}
+
+impl std::fmt::Debug for AssetData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+impl std::ops::Deref for AssetData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Why an asset was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AssetError {
+    TooLarge {
+        size: u64,
+        limit: u64,
+    },
+    UnsupportedType(String),
+    /// The contents don't look like the type they claim to be.
+    WrongType {
+        claimed: String,
+    },
+    /// The contents don't match the hash they were sent under.
+    Corrupt(AssetHash),
+    Unknown(AssetHash),
+    /// The server couldn't read or write its copy.
+    Unavailable,
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetError::TooLarge { size, limit } => {
+                write!(f, "{} bytes is over the {} byte limit", size, limit)
+            }
+            AssetError::UnsupportedType(mime) => write!(f, "{} files can't be uploaded", mime),
+            AssetError::WrongType { claimed } => {
+                write!(f, "the file's contents aren't {}", claimed)
+            }
+            AssetError::Corrupt(hash) => write!(f, "asset {} didn't match its hash", hash),
+            AssetError::Unknown(hash) => write!(f, "there is no asset {}", hash),
+            AssetError::Unavailable => write!(f, "the server couldn't store or read the asset"),
+        }
+    }
+}
+
+pub fn asset_hash(data: &[u8]) -> AssetHash {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks a file is small enough and really is the type it claims to be.
+pub fn check_asset(mime: &str, data: &[u8]) -> Result<(), AssetError> {
+    if data.len() > MAX_ASSET_SIZE {
+        return Err(AssetError::TooLarge {
+            size: data.len() as u64,
+            limit: MAX_ASSET_SIZE as u64,
+        });
+    }
+    let magic = ASSET_TYPES
+        .iter()
+        .find(|(known, _, _)| *known == mime)
+        .map(|(_, magic, _)| *magic)
+        .ok_or_else(|| AssetError::UnsupportedType(mime.to_string()))?;
+    let webp = mime != "image/webp" || data.get(8..12) == Some(b"WEBP");
+    if data.starts_with(magic) && webp {
+        Ok(())
+    } else {
+        Err(AssetError::WrongType {
+            claimed: mime.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn hashes_are_hex_sha256() {
+        let hash = asset_hash(b"abc");
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn assets_must_be_what_they_claim() {
+        assert_eq!(check_asset("image/png", PNG), Ok(()));
+        assert_eq!(
+            check_asset("image/jpeg", PNG),
+            Err(AssetError::WrongType {
+                claimed: String::from("image/jpeg")
+            })
+        );
+        assert_eq!(
+            check_asset("text/html", b"<script>"),
+            Err(AssetError::UnsupportedType(String::from("text/html")))
+        );
+        assert_eq!(
+            check_asset("image/webp", b"RIFF\0\0\0\0AVI "),
+            Err(AssetError::WrongType {
+                claimed: String::from("image/webp")
+            })
+        );
+    }
+
+    #[test]
+    fn contents_are_sent_compactly() {
+        let data = AssetData(vec![0xff; 300]);
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(json.len(), encoded_asset_size(300) + 2);
+        assert_eq!(serde_json::from_str::<AssetData>(&json).unwrap(), data);
+        assert!(serde_json::from_str::<AssetData>("\"not base64!\"").is_err());
+
+        let packed = rmp_serde::to_vec(&data).unwrap();
+        assert!(packed.len() < 310);
+        assert_eq!(rmp_serde::from_slice::<AssetData>(&packed).unwrap(), data);
+    }
+
+    #[test]
+    fn large_assets_are_refused() {
+        let mut data = PNG.to_vec();
+        data.resize(MAX_ASSET_SIZE + 1, 0);
+        assert!(matches!(
+            check_asset("image/png", &data),
+            Err(AssetError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// How messages are encoded on the wire once the handshake is done. The
+/// handshake itself is always JSON text so either side can read it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+/// A single websocket payload, independent of the websocket library in use.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "json: {}", e),
+            CodecError::Encode(e) => write!(f, "messagepack: {}", e),
+            CodecError::Decode(e) => write!(f, "messagepack: {}", e),
+        }
+    }
+}
+
+impl Codec {
+    pub fn encode<M: Serialize>(&self, msg: &M) -> Result<Frame, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_string(msg)
+                .map(Frame::Text)
+                .map_err(CodecError::Json),
+            Codec::MessagePack => rmp_serde::to_vec_named(msg)
+                .map(Frame::Binary)
+                .map_err(CodecError::Encode),
+        }
+    }
+
+    /// Picks the first of the client's preferred codecs, falling back to JSON
+    /// which every peer understands.
+    pub fn negotiate(offered: &[Codec]) -> Codec {
+        offered.first().copied().unwrap_or_default()
+    }
+}
+
+impl Frame {
+    /// Text frames are always JSON and binary frames always MessagePack, so a
+    /// peer can decode whatever it is sent regardless of what it negotiated.
+    pub fn decode<M: DeserializeOwned>(&self) -> Result<M, CodecError> {
+        match self {
+            Frame::Text(text) => serde_json::from_str(text).map_err(CodecError::Json),
+            Frame::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(CodecError::Decode),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Brush, ChatChannel, ChatEntry, ClientMessage, MapChange, MapEdit, Operation, Presence,
+        ServerMessage, Shape, Token,
+    };
+
+    fn messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::Chat {
+                seq: 1,
+                entry: ChatEntry {
+                    id: 1,
+                    from: 4,
+                    name: String::from("Sam"),
+                    sent: 1_600_000_000_000,
+                    channel: ChatChannel::Everyone,
+                    in_character: true,
+                    text: String::from("hello"),
+                },
+            },
+            ServerMessage::MapChanged {
+                seq: 2,
+                from: 1,
+                change: MapChange::BrushSet {
+                    id: 7,
+                    brush: Brush {
+                        zone: 2,
+                        shape: Shape::Curve([0., 0.], [1., 1.], [2., 0.], 1.),
+                        operation: Operation::Union,
+                        order: 0.,
+                        position: [1., -1.],
+                        rotation: 45.,
+                    },
+                },
+            },
+            ServerMessage::TokenPlaced {
+                seq: 3,
+                from: 3,
+                id: 8,
+                token: Token {
+                    name: String::from("Goblin"),
+                    position: [1., 0., 2.],
+                    owner: Some(3),
+                },
+            },
+            ServerMessage::Presence(Presence::Left { user: 9 }),
+        ]
+    }
+
+    #[test]
+    fn json_round_trips_as_text() {
+        for msg in messages() {
+            let frame = Codec::Json.encode(&msg).unwrap();
+            assert!(matches!(frame, Frame::Text(_)));
+            assert_eq!(frame.decode::<ServerMessage>().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn messagepack_round_trips_as_binary() {
+        for msg in messages() {
+            let frame = Codec::MessagePack.encode(&msg).unwrap();
+            assert!(matches!(frame, Frame::Binary(_)));
+            assert_eq!(frame.decode::<ServerMessage>().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn messagepack_is_smaller_than_json() {
+        let msg = ClientMessage::MapEdit(MapEdit::RemoveZone { id: 12 });
+        let json = Codec::Json.encode(&msg).unwrap();
+        let binary = Codec::MessagePack.encode(&msg).unwrap();
+        assert!(binary.len() < json.len());
+    }
+
+    #[test]
+    fn negotiation_prefers_the_clients_first_choice() {
+        assert_eq!(
+            Codec::negotiate(&[Codec::MessagePack, Codec::Json]),
+            Codec::MessagePack
+        );
+        assert_eq!(Codec::negotiate(&[]), Codec::Json);
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::PROTOCOL_VERSION;
+
+/// The UDP port hosts broadcast their sessions to and players listen on.
+pub const DISCOVERY_PORT: u16 = 4868;
+
+/// How often a host tells the local network about its session.
+pub const ADVERT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a session stays listed after its last advert - a few missed ones.
+pub const ADVERT_TIMEOUT: Duration = Duration::from_secs(7);
+
+/// Marks our adverts apart from anything else sent to the port.
+const ADVERT_MAGIC: &str = "vtt-session";
+
+/// A game hosted on the local network, as broadcast by its host. The host's
+/// address is wherever the advert came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionAdvert {
+    magic: String,
+    pub version: u32,
+    pub campaign: String,
+    /// The port the host's websocket server listens on.
+    pub port: u16,
+    pub players: usize,
+}
+
+impl SessionAdvert {
+    pub fn new(campaign: String, port: u16, players: usize) -> Self {
+        SessionAdvert {
+            magic: ADVERT_MAGIC.to_string(),
+            version: PROTOCOL_VERSION,
+            campaign,
+            port,
+            players,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    /// Reads an advert, ignoring anything that isn't one of ours or comes
+    /// from a host we couldn't talk to.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<SessionAdvert>(bytes)
+            .ok()
+            .filter(|advert| advert.magic == ADVERT_MAGIC && advert.version == PROTOCOL_VERSION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adverts_round_trip_and_strangers_are_ignored() {
+        let advert = SessionAdvert::new(String::from("shire"), 4867, 3);
+        assert_eq!(
+            SessionAdvert::decode(&advert.encode()),
+            Some(advert.clone())
+        );
+
+        let mut old = advert;
+        old.version -= 1;
+        assert_eq!(SessionAdvert::decode(&old.encode()), None);
+        assert_eq!(SessionAdvert::decode(b"{\"campaign\":\"shire\"}"), None);
+        assert_eq!(SessionAdvert::decode(b"hello"), None);
+    }
+}
@@ -0,0 +1,17 @@
+mod assets;
+mod codec;
+mod discovery;
+mod map;
+mod messages;
+mod roles;
+mod state;
+mod sync;
+
+pub use assets::*;
+pub use codec::*;
+pub use discovery::*;
+pub use map::*;
+pub use messages::*;
+pub use roles::*;
+pub use state::*;
+pub use sync::*;
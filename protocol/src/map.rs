@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::UserId;
+
+pub type MapId = u64;
+pub type ZoneId = u64;
+pub type BrushId = u64;
+pub type TokenId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    Circle(f32),
+    Square(f32, f32),
+    Segment([f32; 2], [f32; 2], f32),
+    Curve([f32; 2], [f32; 2], [f32; 2], f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    Union,
+    Subtraction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Map {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Zone {
+    pub map: MapId,
+    pub name: String,
+    pub parent: Option<ZoneId>,
+    pub order: u32,
+    pub level: i32,
+    pub color: Option<[f32; 3]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Brush {
+    pub zone: ZoneId,
+    pub shape: Shape,
+    pub operation: Operation,
+    pub order: f32,
+    pub position: [f32; 2],
+    pub rotation: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub name: String,
+    pub position: [f32; 3],
+    /// The player who can move it. Only the game master can move unowned tokens.
+    #[serde(default)]
+    pub owner: Option<UserId>,
+}
+
+/// An edit requested by a client. Anything it creates doesn't have an id yet -
+/// the server allocates one when it turns the edit into a `MapChange`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "edit")]
+pub enum MapEdit {
+    CreateMap { map: Map },
+    UpdateMap { id: MapId, map: Map },
+    RemoveMap { id: MapId },
+    CreateZone { zone: Zone },
+    UpdateZone { id: ZoneId, zone: Zone },
+    RemoveZone { id: ZoneId },
+    CreateBrush { brush: Brush },
+    UpdateBrush { id: BrushId, brush: Brush },
+    RemoveBrush { id: BrushId },
+}
+
+/// A change the server has applied to the campaign.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change")]
+pub enum MapChange {
+    MapSet { id: MapId, map: Map },
+    MapRemoved { id: MapId },
+    ZoneSet { id: ZoneId, zone: Zone },
+    ZoneRemoved { id: ZoneId },
+    BrushSet { id: BrushId, brush: Brush },
+    BrushRemoved { id: BrushId },
+}
+
+impl MapEdit {
+    /// Turns the edit into the change it makes, calling `allocate` for the id
+    /// of anything it creates.
+    pub fn resolve(self, allocate: impl FnOnce() -> u64) -> MapChange {
+        match self {
+            MapEdit::CreateMap { map } => MapChange::MapSet {
+                id: allocate(),
+                map,
+            },
+            MapEdit::UpdateMap { id, map } => MapChange::MapSet { id, map },
+            MapEdit::RemoveMap { id } => MapChange::MapRemoved { id },
+            MapEdit::CreateZone { zone } => MapChange::ZoneSet {
+                id: allocate(),
+                zone,
+            },
+            MapEdit::UpdateZone { id, zone } => MapChange::ZoneSet { id, zone },
+            MapEdit::RemoveZone { id } => MapChange::ZoneRemoved { id },
+            MapEdit::CreateBrush { brush } => MapChange::BrushSet {
+                id: allocate(),
+                brush,
+            },
+            MapEdit::UpdateBrush { id, brush } => MapChange::BrushSet { id, brush },
+            MapEdit::RemoveBrush { id } => MapChange::BrushRemoved { id },
+        }
+    }
+}
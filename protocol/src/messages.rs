@@ -0,0 +1,506 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    AssetData, AssetError, AssetHash, AssetInfo, CampaignState, ChatChannel, ChatEntry, Codec,
+    Forbidden, InvalidChat, InvalidEdit, MapChange, MapEdit, MapId, Role, Token, TokenId,
+};
+
+/// Bumped whenever a change to the messages below would stop an older peer
+/// from understanding a newer one.
+pub const PROTOCOL_VERSION: u32 = 16;
+
+/// How often the server pings each connection.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long either side waits without hearing anything before it gives up on
+/// the connection - a few missed heartbeats.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A single connection to the server.
+pub type ClientId = usize;
+
+/// A registered user, the same across connections and server restarts.
+pub type UserId = u64;
+
+/// Position of a change in the campaign's history. Every change the server
+/// broadcasts gets the next one, so a client can tell if it missed any.
+pub type Seq = u64;
+
+/// Handed out in the welcome so a client that drops can reconnect as the same
+/// client rather than being treated as someone new.
+pub type ResumeToken = String;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        #[serde(default)]
+        codecs: Vec<Codec>,
+        #[serde(default)]
+        resume: Option<ResumeToken>,
+        #[serde(default)]
+        login: Option<Credentials>,
+        /// The campaign to join, or the server's default if `None`.
+        #[serde(default)]
+        campaign: Option<String>,
+    },
+    Chat {
+        text: String,
+        #[serde(default)]
+        channel: ChatChannel,
+        #[serde(default)]
+        in_character: bool,
+    },
+    /// Asks for the page of chat before message `before`, or the latest page
+    /// if `None`.
+    ChatHistory {
+        before: Option<u64>,
+    },
+    MapEdit(MapEdit),
+    PlaceToken {
+        token: Token,
+    },
+    MoveToken {
+        id: TokenId,
+        position: [f32; 3],
+    },
+    /// Asks for every change after `since`, sent when a client notices a gap.
+    Resync {
+        since: Seq,
+    },
+    /// Adds a file to the campaign's assets. Game master only.
+    UploadAsset {
+        name: String,
+        mime: String,
+        data: AssetData,
+    },
+    /// Asks for the contents of one of the campaign's assets.
+    FetchAsset {
+        hash: AssetHash,
+    },
+}
+
+impl ClientMessage {
+    /// The message's name without its contents, for logs that shouldn't
+    /// carry whole message bodies.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello { .. } => "Hello",
+            ClientMessage::Chat { .. } => "Chat",
+            ClientMessage::ChatHistory { .. } => "ChatHistory",
+            ClientMessage::MapEdit(_) => "MapEdit",
+            ClientMessage::PlaceToken { .. } => "PlaceToken",
+            ClientMessage::MoveToken { .. } => "MoveToken",
+            ClientMessage::Resync { .. } => "Resync",
+            ClientMessage::UploadAsset { .. } => "UploadAsset",
+            ClientMessage::FetchAsset { .. } => "FetchAsset",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerMessage {
+    Welcome {
+        version: u32,
+        client: ClientId,
+        codec: Codec,
+        user: UserId,
+        name: String,
+        role: Role,
+        campaign: String,
+        resume: ResumeToken,
+        /// Whether `client` is the id this client had before reconnecting.
+        resumed: bool,
+        /// Set when the client has just signed up with an invite - the
+        /// password to log in with from now on.
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Rejected(Rejection),
+    /// A chat message to everyone in the campaign.
+    Chat {
+        seq: Seq,
+        entry: ChatEntry,
+    },
+    /// A whisper or a message to the game masters. Only some clients get
+    /// these, so they aren't part of the campaign's sequence of changes.
+    PrivateChat {
+        entry: ChatEntry,
+    },
+    /// A page of chat the client is allowed to read, oldest first. `more` is
+    /// set if there's older chat still.
+    ChatHistory {
+        entries: Vec<ChatEntry>,
+        more: bool,
+    },
+    MapChanged {
+        seq: Seq,
+        from: UserId,
+        change: MapChange,
+    },
+    TokenPlaced {
+        seq: Seq,
+        from: UserId,
+        id: TokenId,
+        token: Token,
+    },
+    TokenMoved {
+        seq: Seq,
+        from: UserId,
+        id: TokenId,
+        position: [f32; 3],
+    },
+    /// Changes `from` through `to` were overtaken by later ones before they
+    /// could be sent, so there's nothing to apply for them.
+    Superseded {
+        from: Seq,
+        to: Seq,
+    },
+    AssetAdded {
+        seq: Seq,
+        from: UserId,
+        info: AssetInfo,
+    },
+    /// The contents of an asset the client asked for.
+    Asset {
+        hash: AssetHash,
+        data: AssetData,
+    },
+    AssetRejected {
+        reason: AssetError,
+    },
+    /// Everyone is now shown `map`.
+    MapActivated {
+        seq: Seq,
+        map: MapId,
+    },
+    /// A notice from the server's admin to everyone connected.
+    Announcement {
+        text: String,
+    },
+    Presence(Presence),
+    /// The whole campaign as of change `seq`.
+    Snapshot {
+        seq: Seq,
+        state: CampaignState,
+    },
+    EditRejected {
+        reason: InvalidEdit,
+    },
+    ChatRejected {
+        reason: InvalidChat,
+    },
+    PermissionDenied {
+        reason: Forbidden,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    /// The message's name without its contents, for logs that shouldn't
+    /// carry whole message bodies.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerMessage::Welcome { .. } => "Welcome",
+            ServerMessage::Rejected(_) => "Rejected",
+            ServerMessage::Chat { .. } => "Chat",
+            ServerMessage::PrivateChat { .. } => "PrivateChat",
+            ServerMessage::ChatHistory { .. } => "ChatHistory",
+            ServerMessage::MapChanged { .. } => "MapChanged",
+            ServerMessage::TokenPlaced { .. } => "TokenPlaced",
+            ServerMessage::TokenMoved { .. } => "TokenMoved",
+            ServerMessage::Superseded { .. } => "Superseded",
+            ServerMessage::AssetAdded { .. } => "AssetAdded",
+            ServerMessage::Asset { .. } => "Asset",
+            ServerMessage::AssetRejected { .. } => "AssetRejected",
+            ServerMessage::MapActivated { .. } => "MapActivated",
+            ServerMessage::Announcement { .. } => "Announcement",
+            ServerMessage::Presence(_) => "Presence",
+            ServerMessage::Snapshot { .. } => "Snapshot",
+            ServerMessage::EditRejected { .. } => "EditRejected",
+            ServerMessage::ChatRejected { .. } => "ChatRejected",
+            ServerMessage::PermissionDenied { .. } => "PermissionDenied",
+            ServerMessage::Error { .. } => "Error",
+        }
+    }
+
+    /// The sequence number of a change to the campaign, or `None` for
+    /// messages that don't change it.
+    pub fn seq(&self) -> Option<Seq> {
+        match self {
+            ServerMessage::Chat { seq, .. }
+            | ServerMessage::MapChanged { seq, .. }
+            | ServerMessage::TokenPlaced { seq, .. }
+            | ServerMessage::TokenMoved { seq, .. }
+            | ServerMessage::AssetAdded { seq, .. }
+            | ServerMessage::MapActivated { seq, .. } => Some(*seq),
+            ServerMessage::Superseded { to, .. } => Some(*to),
+            _ => None,
+        }
+    }
+
+    /// Stamps a change with its place in the campaign's history.
+    pub fn with_seq(mut self, next: Seq) -> Self {
+        match &mut self {
+            ServerMessage::Chat { seq, .. }
+            | ServerMessage::MapChanged { seq, .. }
+            | ServerMessage::TokenPlaced { seq, .. }
+            | ServerMessage::TokenMoved { seq, .. }
+            | ServerMessage::AssetAdded { seq, .. }
+            | ServerMessage::MapActivated { seq, .. }
+            | ServerMessage::Snapshot { seq, .. } => *seq = next,
+            _ => {}
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Presence {
+    Joined {
+        user: UserId,
+        name: String,
+    },
+    Left {
+        user: UserId,
+    },
+    /// How long a round trip to the server took for one of the players.
+    Latency {
+        user: UserId,
+        millis: u32,
+    },
+}
+
+/// How a client proves who it is during the handshake.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A registered user's name and password.
+    Password { username: String, password: String },
+    /// A campaign's invite token, which signs `username` up on first use.
+    Invite { username: String, token: String },
+}
+
+/// Keeps passwords and invite tokens out of the logs.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Password { username, .. } => {
+                write!(f, "Password {{ username: {:?}, .. }}", username)
+            }
+            Credentials::Invite { username, .. } => {
+                write!(f, "Invite {{ username: {:?}, .. }}", username)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Rejection {
+    VersionMismatch {
+        server: u32,
+        client: u32,
+    },
+    HandshakeExpected,
+    LoginRequired,
+    BadCredentials,
+    UnknownCampaign(String),
+    /// The server only lets in users with a password.
+    InvitesDisabled,
+    ServerFull,
+    CampaignFull(String),
+    Banned,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::VersionMismatch { server, client } => write!(
+                f,
+                "protocol version mismatch - server speaks {}, client speaks {}",
+                server, client
+            ),
+            Rejection::HandshakeExpected => write!(f, "expected a hello before any other message"),
+            Rejection::LoginRequired => write!(f, "a username and password or invite is needed"),
+            Rejection::BadCredentials => write!(f, "unknown user, wrong password or bad invite"),
+            Rejection::UnknownCampaign(name) => {
+                write!(f, "there is no open campaign called {:?}", name)
+            }
+            Rejection::InvitesDisabled => write!(f, "this server doesn't accept invites"),
+            Rejection::ServerFull => write!(f, "the server is full"),
+            Rejection::CampaignFull(name) => write!(f, "campaign {:?} is full", name),
+            Rejection::Banned => write!(f, "you are banned from this server"),
+        }
+    }
+}
+
+/// Checks the first message of a connection, returning the rejection to send
+/// back if the client can't be accepted. The credentials themselves are left
+/// for the server to verify.
+pub fn check_hello(msg: &ClientMessage) -> Result<(), Rejection> {
+    match msg {
+        ClientMessage::Hello {
+            version,
+            login: None,
+            campaign: None,
+            ..
+        } if *version == PROTOCOL_VERSION => Err(Rejection::LoginRequired),
+        ClientMessage::Hello { version, .. } if *version == PROTOCOL_VERSION => Ok(()),
+        ClientMessage::Hello { version, .. } => Err(Rejection::VersionMismatch {
+            server: PROTOCOL_VERSION,
+            client: *version,
+        }),
+        _ => Err(Rejection::HandshakeExpected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Brush, Frame, Operation, Shape};
+
+    #[test]
+    fn hello_with_current_version_is_accepted() {
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            codecs: vec![],
+            resume: None,
+            login: Some(Credentials::Invite {
+                username: String::from("Bilbo"),
+                token: String::from("abc"),
+            }),
+            campaign: None,
+        };
+        assert_eq!(check_hello(&hello), Ok(()));
+    }
+
+    #[test]
+    fn hello_without_login_is_rejected() {
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            codecs: vec![],
+            resume: None,
+            login: None,
+            campaign: None,
+        };
+        assert_eq!(check_hello(&hello), Err(Rejection::LoginRequired));
+    }
+
+    #[test]
+    fn credentials_are_not_logged() {
+        let login = Credentials::Password {
+            username: String::from("Bilbo"),
+            password: String::from("precious"),
+        };
+        assert!(!format!("{:?}", login).contains("precious"));
+    }
+
+    #[test]
+    fn hello_with_other_version_is_rejected() {
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION + 1,
+            codecs: vec![Codec::Json],
+            resume: None,
+            login: None,
+            campaign: None,
+        };
+        assert_eq!(
+            check_hello(&hello),
+            Err(Rejection::VersionMismatch {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn hello_from_older_clients_defaults_to_no_codecs_resume_or_login() {
+        let frame = Frame::Text(String::from(r#"{"type":"Hello","data":{"version":1}}"#));
+        let hello = frame.decode::<ClientMessage>().unwrap();
+        assert_eq!(
+            hello,
+            ClientMessage::Hello {
+                version: 1,
+                codecs: vec![],
+                resume: None,
+                login: None,
+                campaign: None
+            }
+        );
+    }
+
+    #[test]
+    fn non_hello_first_message_is_rejected() {
+        let chat = ClientMessage::Chat {
+            text: String::from("hi"),
+            channel: ChatChannel::Everyone,
+            in_character: false,
+        };
+        assert_eq!(check_hello(&chat), Err(Rejection::HandshakeExpected));
+    }
+
+    #[test]
+    fn plain_chat_goes_to_everyone_out_of_character() {
+        let frame = Frame::Text(String::from(r#"{"type":"Chat","data":{"text":"hi"}}"#));
+        assert_eq!(
+            frame.decode::<ClientMessage>().unwrap(),
+            ClientMessage::Chat {
+                text: String::from("hi"),
+                channel: ChatChannel::Everyone,
+                in_character: false,
+            }
+        );
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let msg = ClientMessage::MapEdit(MapEdit::CreateBrush {
+            brush: Brush {
+                zone: 3,
+                shape: Shape::Segment([0., 0.], [1., 1.], 0.5),
+                operation: Operation::Subtraction,
+                order: 1.,
+                position: [2., 3.],
+                rotation: 90.,
+            },
+        });
+        let encoded = Codec::Json.encode(&msg).unwrap();
+        let decoded: ClientMessage = encoded.decode().unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn server_messages_are_tagged() {
+        let msg = ServerMessage::PrivateChat {
+            entry: ChatEntry {
+                id: 3,
+                from: 2,
+                name: String::from("Frodo"),
+                sent: 1000,
+                channel: ChatChannel::Whisper { to: 1 },
+                in_character: true,
+                text: String::from("hello"),
+            },
+        };
+        let encoded = Codec::Json.encode(&msg).unwrap();
+        assert_eq!(
+            encoded,
+            Frame::Text(String::from(
+                r#"{"type":"PrivateChat","data":{"entry":{"id":3,"from":2,"name":"Frodo","sent":1000,"channel":{"Whisper":{"to":1}},"in_character":true,"text":"hello"}}}"#
+            ))
+        );
+        let decoded: ServerMessage = encoded.decode().unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn unknown_messages_fail_to_decode() {
+        let frame = Frame::Text(String::from(r#"{"type":"Teleport","data":{}}"#));
+        let decoded = frame.decode::<ServerMessage>();
+        assert!(decoded.is_err());
+    }
+}
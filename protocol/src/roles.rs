@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{CampaignState, ChatChannel, ClientMessage, TokenId, UserId};
+
+/// What a user is allowed to do in a campaign.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// Runs the game - can edit the maps and move anything.
+    GameMaster,
+    /// Can chat, place tokens and move the tokens they own.
+    #[default]
+    Player,
+    /// Can only watch.
+    Spectator,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gm" | "game-master" | "gamemaster" => Ok(Role::GameMaster),
+            "player" => Ok(Role::Player),
+            "spectator" => Ok(Role::Spectator),
+            other => Err(format!(
+                "unknown role {:?} - expected gm, player or spectator",
+                other
+            )),
+        }
+    }
+}
+
+/// Why the server refused to do something a client asked for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Forbidden {
+    ReadOnly,
+    GameMasterOnly,
+    NotYourToken(TokenId),
+}
+
+impl std::fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Forbidden::ReadOnly => write!(f, "spectators can't change anything"),
+            Forbidden::GameMasterOnly => write!(f, "only the game master can do that"),
+            Forbidden::NotYourToken(id) => write!(f, "token {} belongs to someone else", id),
+        }
+    }
+}
+
+impl CampaignState {
+    /// Checks whether `user`, playing as `role`, may send `msg`.
+    pub fn permits(&self, user: UserId, role: Role, msg: &ClientMessage) -> Result<(), Forbidden> {
+        match (role, msg) {
+            (
+                _,
+                ClientMessage::Hello { .. }
+                | ClientMessage::Resync { .. }
+                | ClientMessage::ChatHistory { .. }
+                | ClientMessage::FetchAsset { .. },
+            ) => Ok(()),
+            (Role::GameMaster, _) => Ok(()),
+            (Role::Spectator, _) => Err(Forbidden::ReadOnly),
+            (
+                Role::Player,
+                ClientMessage::MapEdit(_)
+                | ClientMessage::UploadAsset { .. }
+                | ClientMessage::Chat {
+                    channel: ChatChannel::GameMasters,
+                    ..
+                },
+            ) => Err(Forbidden::GameMasterOnly),
+            (Role::Player, ClientMessage::MoveToken { id, .. }) => match self.tokens.get(id) {
+                Some(token) if token.owner != Some(user) => Err(Forbidden::NotYourToken(*id)),
+                _ => Ok(()),
+            },
+            (Role::Player, _) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MapEdit, Token};
+
+    fn state() -> CampaignState {
+        let mut state = CampaignState::default();
+        state.tokens.insert(
+            1,
+            Token {
+                name: String::from("Hero"),
+                position: [0., 0., 0.],
+                owner: Some(7),
+            },
+        );
+        state
+    }
+
+    fn move_token(id: TokenId) -> ClientMessage {
+        ClientMessage::MoveToken {
+            id,
+            position: [1., 0., 1.],
+        }
+    }
+
+    fn chat(channel: ChatChannel) -> ClientMessage {
+        ClientMessage::Chat {
+            text: String::from("hi"),
+            channel,
+            in_character: false,
+        }
+    }
+
+    #[test]
+    fn only_game_masters_edit_maps_upload_and_talk_among_themselves() {
+        let edit = ClientMessage::MapEdit(MapEdit::RemoveMap { id: 1 });
+        let upload = ClientMessage::UploadAsset {
+            name: String::from("map.png"),
+            mime: String::from("image/png"),
+            data: Default::default(),
+        };
+        let gm_chat = chat(ChatChannel::GameMasters);
+        let state = state();
+        assert_eq!(state.permits(1, Role::GameMaster, &edit), Ok(()));
+        assert_eq!(state.permits(1, Role::GameMaster, &upload), Ok(()));
+        assert_eq!(state.permits(1, Role::GameMaster, &gm_chat), Ok(()));
+        let whisper = chat(ChatChannel::Whisper { to: 1 });
+        assert_eq!(state.permits(2, Role::Player, &whisper), Ok(()));
+        for msg in [edit, upload, gm_chat] {
+            assert_eq!(
+                state.permits(1, Role::Player, &msg),
+                Err(Forbidden::GameMasterOnly)
+            );
+        }
+    }
+
+    #[test]
+    fn players_only_move_their_own_tokens() {
+        let state = state();
+        assert_eq!(state.permits(7, Role::Player, &move_token(1)), Ok(()));
+        assert_eq!(
+            state.permits(8, Role::Player, &move_token(1)),
+            Err(Forbidden::NotYourToken(1))
+        );
+        assert_eq!(state.permits(8, Role::GameMaster, &move_token(1)), Ok(()));
+    }
+
+    #[test]
+    fn spectators_can_only_catch_up() {
+        let state = state();
+        assert_eq!(
+            state.permits(9, Role::Spectator, &chat(ChatChannel::Everyone)),
+            Err(Forbidden::ReadOnly)
+        );
+        let history = ClientMessage::ChatHistory { before: None };
+        assert_eq!(state.permits(9, Role::Spectator, &history), Ok(()));
+        assert_eq!(
+            state.permits(9, Role::Spectator, &ClientMessage::Resync { since: 0 }),
+            Ok(())
+        );
+        let fetch = ClientMessage::FetchAsset {
+            hash: String::from("ab"),
+        };
+        assert_eq!(state.permits(9, Role::Spectator, &fetch), Ok(()));
+    }
+
+    #[test]
+    fn roles_parse_from_the_command_line() {
+        assert_eq!("GM".parse(), Ok(Role::GameMaster));
+        assert_eq!("spectator".parse(), Ok(Role::Spectator));
+        assert!("wizard".parse::<Role>().is_err());
+    }
+}
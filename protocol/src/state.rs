@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AssetHash, AssetInfo, Brush, BrushId, Map, MapChange, MapEdit, MapId, Role, Token, TokenId,
+    UserId, Zone, ZoneId,
+};
+
+/// Why the server refused to apply an edit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InvalidEdit {
+    UnknownMap(MapId),
+    UnknownZone(ZoneId),
+    UnknownBrush(BrushId),
+    UnknownToken(TokenId),
+    ParentOnAnotherMap { zone: ZoneId, parent: ZoneId },
+    ZoneCycle(ZoneId),
+}
+
+impl std::fmt::Display for InvalidEdit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidEdit::UnknownMap(id) => write!(f, "there is no map {}", id),
+            InvalidEdit::UnknownZone(id) => write!(f, "there is no zone {}", id),
+            InvalidEdit::UnknownBrush(id) => write!(f, "there is no brush {}", id),
+            InvalidEdit::UnknownToken(id) => write!(f, "there is no token {}", id),
+            InvalidEdit::ParentOnAnotherMap { zone, parent } => write!(
+                f,
+                "zone {} can't be inside zone {} - they are on different maps",
+                zone, parent
+            ),
+            InvalidEdit::ZoneCycle(id) => write!(f, "zone {} can't be inside itself", id),
+        }
+    }
+}
+
+/// Who a chat message is for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatChannel {
+    /// Everyone in the campaign.
+    #[default]
+    Everyone,
+    /// Only the game masters.
+    GameMasters,
+    /// Only the sender and `to`.
+    Whisper { to: UserId },
+}
+
+/// The longest chat message the server accepts, in characters.
+pub const MAX_CHAT_LENGTH: usize = 2000;
+
+/// Why a chat message wasn't sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvalidChat {
+    Empty,
+    TooLong {
+        length: usize,
+        limit: usize,
+    },
+    /// The whisper was to someone who isn't in the campaign.
+    UnknownRecipient(UserId),
+}
+
+impl std::fmt::Display for InvalidChat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidChat::Empty => write!(f, "there's nothing to send"),
+            InvalidChat::TooLong { length, limit } => write!(
+                f,
+                "message is {} characters - the limit is {}",
+                length, limit
+            ),
+            InvalidChat::UnknownRecipient(id) => {
+                write!(f, "user {} isn't in this campaign", id)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatEntry {
+    /// Where it is in the campaign's chat log - later messages have higher
+    /// ids.
+    #[serde(default)]
+    pub id: u64,
+    pub from: UserId,
+    /// The sender's name when they sent it.
+    #[serde(default)]
+    pub name: String,
+    /// When the server got it, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub sent: u64,
+    #[serde(default)]
+    pub channel: ChatChannel,
+    /// Said by the sender's character rather than by the player.
+    #[serde(default)]
+    pub in_character: bool,
+    pub text: String,
+}
+
+impl ChatEntry {
+    /// Whether `user`, playing as `role`, may read this.
+    pub fn visible_to(&self, user: UserId, role: Role) -> bool {
+        match self.channel {
+            ChatChannel::Everyone => true,
+            ChatChannel::GameMasters => role == Role::GameMaster,
+            ChatChannel::Whisper { to } => user == self.from || user == to,
+        }
+    }
+}
+
+/// Everything that makes up a campaign. The server keeps the authoritative copy
+/// and clients mirror it by applying the same changes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CampaignState {
+    pub maps: BTreeMap<MapId, Map>,
+    pub zones: BTreeMap<ZoneId, Zone>,
+    pub brushes: BTreeMap<BrushId, Brush>,
+    pub tokens: BTreeMap<TokenId, Token>,
+    pub chat: Vec<ChatEntry>,
+    /// Files uploaded to the campaign. Clients fetch the contents as needed.
+    #[serde(default)]
+    pub assets: BTreeMap<AssetHash, AssetInfo>,
+    /// The map everyone is shown, picked by the server's admin.
+    #[serde(default)]
+    pub active_map: Option<MapId>,
+}
+
+impl CampaignState {
+    /// The largest id in use, so new ids can be allocated after it.
+    pub fn max_id(&self) -> u64 {
+        [
+            self.maps.keys().last(),
+            self.zones.keys().last(),
+            self.brushes.keys().last(),
+            self.tokens.keys().last(),
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .copied()
+        .unwrap_or(0)
+    }
+
+    /// Checks an edit makes sense against the current state before it's applied.
+    pub fn validate(&self, edit: &MapEdit) -> Result<(), InvalidEdit> {
+        match edit {
+            MapEdit::CreateMap { .. } => Ok(()),
+            MapEdit::UpdateMap { id, .. } | MapEdit::RemoveMap { id } => self.check_map(*id),
+            MapEdit::CreateZone { zone } => self.check_zone(None, zone),
+            MapEdit::UpdateZone { id, zone } => {
+                self.check_known_zone(*id)?;
+                self.check_zone(Some(*id), zone)
+            }
+            MapEdit::RemoveZone { id } => self.check_known_zone(*id),
+            MapEdit::CreateBrush { brush } => self.check_known_zone(brush.zone),
+            MapEdit::UpdateBrush { id, brush } => {
+                self.check_known_brush(*id)?;
+                self.check_known_zone(brush.zone)
+            }
+            MapEdit::RemoveBrush { id } => self.check_known_brush(*id),
+        }
+    }
+
+    fn check_map(&self, id: MapId) -> Result<(), InvalidEdit> {
+        match self.maps.contains_key(&id) {
+            true => Ok(()),
+            false => Err(InvalidEdit::UnknownMap(id)),
+        }
+    }
+
+    fn check_known_zone(&self, id: ZoneId) -> Result<(), InvalidEdit> {
+        match self.zones.contains_key(&id) {
+            true => Ok(()),
+            false => Err(InvalidEdit::UnknownZone(id)),
+        }
+    }
+
+    fn check_known_brush(&self, id: BrushId) -> Result<(), InvalidEdit> {
+        match self.brushes.contains_key(&id) {
+            true => Ok(()),
+            false => Err(InvalidEdit::UnknownBrush(id)),
+        }
+    }
+
+    /// A zone has to be on a real map, and its parent has to exist on the same
+    /// map without the zone ending up as its own ancestor.
+    fn check_zone(&self, id: Option<ZoneId>, zone: &Zone) -> Result<(), InvalidEdit> {
+        self.check_map(zone.map)?;
+        let mut parent = zone.parent;
+        while let Some(parent_id) = parent {
+            if Some(parent_id) == id {
+                return Err(InvalidEdit::ZoneCycle(parent_id));
+            }
+            let parent_zone = self
+                .zones
+                .get(&parent_id)
+                .ok_or(InvalidEdit::UnknownZone(parent_id))?;
+            if parent_zone.map != zone.map {
+                return Err(InvalidEdit::ParentOnAnotherMap {
+                    zone: id.unwrap_or_default(),
+                    parent: parent_id,
+                });
+            }
+            parent = parent_zone.parent;
+        }
+        Ok(())
+    }
+
+    /// Applies a change, returning every change that actually happened -
+    /// removing a map or zone also removes the zones and brushes inside it.
+    pub fn apply(&mut self, change: MapChange) -> Vec<MapChange> {
+        match change {
+            MapChange::MapSet { id, map } => {
+                self.maps.insert(id, map.clone());
+                vec![MapChange::MapSet { id, map }]
+            }
+            MapChange::MapRemoved { id } => {
+                let zones: Vec<ZoneId> = self
+                    .zones
+                    .iter()
+                    .filter(|(_, zone)| zone.map == id && zone.parent.is_none())
+                    .map(|(id, _)| *id)
+                    .collect();
+                let mut changes: Vec<MapChange> = zones
+                    .into_iter()
+                    .flat_map(|zone| self.apply(MapChange::ZoneRemoved { id: zone }))
+                    .collect();
+                if self.maps.remove(&id).is_some() {
+                    changes.push(MapChange::MapRemoved { id });
+                }
+                if self.active_map == Some(id) {
+                    self.active_map = None;
+                }
+                changes
+            }
+            MapChange::ZoneSet { id, zone } => {
+                self.zones.insert(id, zone.clone());
+                vec![MapChange::ZoneSet { id, zone }]
+            }
+            MapChange::ZoneRemoved { id } => {
+                let children: Vec<ZoneId> = self
+                    .zones
+                    .iter()
+                    .filter(|(_, zone)| zone.parent == Some(id))
+                    .map(|(id, _)| *id)
+                    .collect();
+                let brushes: Vec<BrushId> = self
+                    .brushes
+                    .iter()
+                    .filter(|(_, brush)| brush.zone == id)
+                    .map(|(id, _)| *id)
+                    .collect();
+                let mut changes: Vec<MapChange> = children
+                    .into_iter()
+                    .flat_map(|child| self.apply(MapChange::ZoneRemoved { id: child }))
+                    .collect();
+                for brush in brushes {
+                    changes.extend(self.apply(MapChange::BrushRemoved { id: brush }));
+                }
+                if self.zones.remove(&id).is_some() {
+                    changes.push(MapChange::ZoneRemoved { id });
+                }
+                changes
+            }
+            MapChange::BrushSet { id, brush } => {
+                self.brushes.insert(id, brush.clone());
+                vec![MapChange::BrushSet { id, brush }]
+            }
+            MapChange::BrushRemoved { id } => match self.brushes.remove(&id) {
+                Some(_) => vec![MapChange::BrushRemoved { id }],
+                None => vec![],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Operation, Shape};
+
+    fn zone(map: MapId, parent: Option<ZoneId>) -> Zone {
+        Zone {
+            map,
+            name: String::from("Zone"),
+            parent,
+            order: 0,
+            level: 0,
+            color: None,
+        }
+    }
+
+    fn brush(zone: ZoneId) -> Brush {
+        Brush {
+            zone,
+            shape: Shape::Circle(1.),
+            operation: Operation::Union,
+            order: 0.,
+            position: [0., 0.],
+            rotation: 0.,
+        }
+    }
+
+    fn campaign() -> CampaignState {
+        let mut state = CampaignState::default();
+        state.apply(MapChange::MapSet {
+            id: 1,
+            map: Map {
+                name: String::from("Dungeon"),
+            },
+        });
+        state.apply(MapChange::ZoneSet {
+            id: 2,
+            zone: zone(1, None),
+        });
+        state.apply(MapChange::ZoneSet {
+            id: 3,
+            zone: zone(1, Some(2)),
+        });
+        state.apply(MapChange::BrushSet {
+            id: 4,
+            brush: brush(3),
+        });
+        state.apply(MapChange::BrushSet {
+            id: 5,
+            brush: brush(2),
+        });
+        state
+    }
+
+    #[test]
+    fn max_id_covers_every_collection() {
+        assert_eq!(CampaignState::default().max_id(), 0);
+        assert_eq!(campaign().max_id(), 5);
+    }
+
+    #[test]
+    fn removing_a_zone_removes_its_children_and_brushes() {
+        let mut state = campaign();
+        let changes = state.apply(MapChange::ZoneRemoved { id: 2 });
+        assert!(state.zones.is_empty());
+        assert!(state.brushes.is_empty());
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes.last(), Some(&MapChange::ZoneRemoved { id: 2 }));
+    }
+
+    #[test]
+    fn removing_a_map_removes_its_zones() {
+        let mut state = campaign();
+        let changes = state.apply(MapChange::MapRemoved { id: 1 });
+        assert!(state.maps.is_empty());
+        assert!(state.zones.is_empty());
+        assert_eq!(changes.last(), Some(&MapChange::MapRemoved { id: 1 }));
+    }
+
+    #[test]
+    fn edits_to_missing_things_are_invalid() {
+        let state = campaign();
+        assert_eq!(
+            state.validate(&MapEdit::RemoveMap { id: 9 }),
+            Err(InvalidEdit::UnknownMap(9))
+        );
+        assert_eq!(
+            state.validate(&MapEdit::CreateBrush { brush: brush(9) }),
+            Err(InvalidEdit::UnknownZone(9))
+        );
+        assert_eq!(
+            state.validate(&MapEdit::UpdateBrush {
+                id: 9,
+                brush: brush(2)
+            }),
+            Err(InvalidEdit::UnknownBrush(9))
+        );
+        assert_eq!(
+            state.validate(&MapEdit::CreateZone {
+                zone: zone(9, None)
+            }),
+            Err(InvalidEdit::UnknownMap(9))
+        );
+        assert_eq!(state.validate(&MapEdit::RemoveZone { id: 3 }), Ok(()));
+    }
+
+    #[test]
+    fn zones_cant_contain_themselves() {
+        let state = campaign();
+        assert_eq!(
+            state.validate(&MapEdit::UpdateZone {
+                id: 2,
+                zone: zone(1, Some(3))
+            }),
+            Err(InvalidEdit::ZoneCycle(2))
+        );
+    }
+
+    #[test]
+    fn zones_cant_have_parents_on_other_maps() {
+        let mut state = campaign();
+        state.apply(MapChange::MapSet {
+            id: 6,
+            map: Map {
+                name: String::from("Tower"),
+            },
+        });
+        assert_eq!(
+            state.validate(&MapEdit::CreateZone {
+                zone: zone(6, Some(2))
+            }),
+            Err(InvalidEdit::ParentOnAnotherMap { zone: 0, parent: 2 })
+        );
+    }
+
+    #[test]
+    fn removing_something_missing_changes_nothing() {
+        let mut state = campaign();
+        assert!(state.apply(MapChange::BrushRemoved { id: 42 }).is_empty());
+        assert_eq!(state, campaign());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{Seq, ServerMessage};
+
+/// What a client should do with a message it has just received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    /// The message is next in line (or doesn't change the campaign).
+    Apply,
+    /// Already seen, or sent before the snapshot it's included in.
+    Ignore,
+    /// Changes went missing - ask the server for everything after `since`
+    /// and drop this message, since the resync will send it again.
+    Resync { since: Seq },
+}
+
+/// Follows the sequence numbers a client is sent to spot changes that never
+/// arrived, for instance because the server dropped them for a slow client.
+#[derive(Debug, Default)]
+pub struct SyncTracker {
+    last: Option<Seq>,
+    resyncing: bool,
+}
+
+impl SyncTracker {
+    pub fn observe(&mut self, msg: &ServerMessage) -> SyncAction {
+        if let ServerMessage::Snapshot { seq, .. } = msg {
+            self.last = Some(*seq);
+            self.resyncing = false;
+            return SyncAction::Apply;
+        }
+        let seq = match msg.seq() {
+            Some(seq) => seq,
+            None => return SyncAction::Apply,
+        };
+        // A run of superseded changes moves us on as if each had arrived.
+        let first = match msg {
+            ServerMessage::Superseded { from, .. } => *from,
+            _ => seq,
+        };
+        match self.last {
+            None => SyncAction::Ignore,
+            Some(last) if seq <= last => SyncAction::Ignore,
+            Some(last) if first <= last + 1 => {
+                self.last = Some(seq);
+                self.resyncing = false;
+                SyncAction::Apply
+            }
+            Some(_) if self.resyncing => SyncAction::Ignore,
+            Some(last) => {
+                self.resyncing = true;
+                SyncAction::Resync { since: last }
+            }
+        }
+    }
+
+    /// The last change applied, if the client has caught up at all.
+    pub fn last(&self) -> Option<Seq> {
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CampaignState, ChatEntry};
+
+    fn chat(seq: Seq) -> ServerMessage {
+        ServerMessage::Chat {
+            seq,
+            entry: ChatEntry {
+                from: 1,
+                text: String::from("hi"),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn snapshot(seq: Seq) -> ServerMessage {
+        ServerMessage::Snapshot {
+            seq,
+            state: CampaignState::default(),
+        }
+    }
+
+    #[test]
+    fn changes_before_the_snapshot_are_ignored() {
+        let mut sync = SyncTracker::default();
+        assert_eq!(sync.observe(&chat(3)), SyncAction::Ignore);
+        assert_eq!(sync.observe(&snapshot(3)), SyncAction::Apply);
+        assert_eq!(sync.observe(&chat(3)), SyncAction::Ignore);
+        assert_eq!(sync.observe(&chat(4)), SyncAction::Apply);
+        assert_eq!(sync.last(), Some(4));
+    }
+
+    #[test]
+    fn a_gap_asks_for_one_resync() {
+        let mut sync = SyncTracker::default();
+        sync.observe(&snapshot(0));
+        sync.observe(&chat(1));
+        assert_eq!(sync.observe(&chat(3)), SyncAction::Resync { since: 1 });
+        assert_eq!(sync.observe(&chat(4)), SyncAction::Ignore);
+        assert_eq!(sync.observe(&chat(2)), SyncAction::Apply);
+        assert_eq!(sync.observe(&chat(3)), SyncAction::Apply);
+        assert_eq!(sync.last(), Some(3));
+    }
+
+    #[test]
+    fn superseded_changes_fill_the_gap() {
+        let mut sync = SyncTracker::default();
+        sync.observe(&snapshot(0));
+        sync.observe(&chat(1));
+        let skipped = ServerMessage::Superseded { from: 2, to: 4 };
+        assert_eq!(sync.observe(&skipped), SyncAction::Apply);
+        assert_eq!(sync.observe(&chat(5)), SyncAction::Apply);
+        assert_eq!(sync.last(), Some(5));
+    }
+
+    #[test]
+    fn messages_without_a_sequence_always_apply() {
+        let mut sync = SyncTracker::default();
+        let error = ServerMessage::Error {
+            message: String::from("oops"),
+        };
+        assert_eq!(sync.observe(&error), SyncAction::Apply);
+        assert_eq!(sync.last(), None);
+    }
+}
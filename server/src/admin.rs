@@ -0,0 +1,252 @@
+use std::{
+    io::{self, BufRead},
+    str::FromStr,
+};
+
+use crossbeam_channel::Sender;
+use protocol::{MapId, ServerMessage};
+use server_lib::{kick, CampaignRegistry, Clients, Game, Recipients, ServerControl, UserStore};
+
+const HELP: &str = "\
+list                      Lists who is connected
+kick <user>               Disconnects a user - they can come back
+ban <user>                Disconnects a user and stops them logging in
+unban <user>              Lets a banned user log in again
+announce <text>           Sends a notice to everyone connected
+save                      Writes every open campaign to disk
+map <campaign> <map>      Shows everyone in a campaign one of its maps, by id or name
+close                     Disconnects everyone and stops the server
+help                      Shows this list";
+
+/// Something typed into the server's console.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    List,
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    Announce(String),
+    Save,
+    Map { campaign: String, map: String },
+    Close,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let needs = |what: &str| {
+            if rest.is_empty() {
+                Err(format!("{} needs {}", name, what))
+            } else {
+                Ok(rest.to_string())
+            }
+        };
+        Ok(match name {
+            "list" => Command::List,
+            "kick" => Command::Kick(needs("a user")?),
+            "ban" => Command::Ban(needs("a user")?),
+            "unban" => Command::Unban(needs("a user")?),
+            "announce" => Command::Announce(needs("something to say")?),
+            "save" => Command::Save,
+            "map" => match rest.split_once(char::is_whitespace) {
+                Some((campaign, map)) => Command::Map {
+                    campaign: campaign.to_string(),
+                    map: map.trim().to_string(),
+                },
+                None => return Err(String::from("map needs a campaign and a map")),
+            },
+            "close" => Command::Close,
+            "help" => Command::Help,
+            other => return Err(format!("unknown command {:?} - try help", other)),
+        })
+    }
+}
+
+/// Reads commands from stdin until it closes, passing them to the game loop
+/// to run. Lines that aren't commands are answered straight away. Blocks, so
+/// it needs a thread of its own - a plain one, since a read the runtime is
+/// waiting on would keep the server from exiting until another line came.
+pub fn read_commands(commands: Sender<Command>) {
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(command) => {
+                if commands.send(command).is_err() {
+                    break;
+                }
+            }
+            Err(error) => println!("{}", error),
+        }
+    }
+}
+
+/// What the console's commands act on, besides the campaigns being played.
+pub struct Admin {
+    pub users: UserStore,
+    pub campaigns: CampaignRegistry,
+    pub clients: Clients,
+    pub control: tokio::sync::mpsc::Sender<ServerControl>,
+}
+
+impl Admin {
+    /// Runs a command, returning what to tell the admin.
+    pub fn run(&self, command: Command, game: &mut Game) -> String {
+        match command {
+            Command::List => self.list(),
+            Command::Kick(name) => self.kick(&name, "Kicked by the server admin"),
+            Command::Ban(name) => match self.users.find(&name) {
+                Ok(Some((id, _))) => match self.users.ban(id) {
+                    Ok(_) => format!(
+                        "Banned {}. {}",
+                        name,
+                        self.kick(&name, "Banned by the server admin")
+                    ),
+                    Err(error) => format!("Couldn't ban {}: {}", name, error),
+                },
+                Ok(None) => format!("There is no user called {}", name),
+                Err(error) => format!("Couldn't find user: {}", error),
+            },
+            Command::Unban(name) => match self.users.find(&name) {
+                Ok(Some((id, _))) => match self.users.unban(id) {
+                    Ok(true) => format!("{} can log in again", name),
+                    Ok(false) => format!("{} wasn't banned", name),
+                    Err(error) => format!("Couldn't unban {}: {}", name, error),
+                },
+                Ok(None) => format!("There is no user called {}", name),
+                Err(error) => format!("Couldn't find user: {}", error),
+            },
+            Command::Announce(text) => {
+                let clients = self.clients.lock().unwrap();
+                for client in clients.values() {
+                    client
+                        .outbox
+                        .push(ServerMessage::Announcement { text: text.clone() });
+                }
+                format!("Announced to {} clients", clients.len())
+            }
+            Command::Save => match game.rooms.flush() {
+                Ok(()) => String::from("Saved campaigns"),
+                Err(error) => format!("Couldn't save campaigns: {}", error),
+            },
+            Command::Map { campaign, map } => self.activate_map(game, &campaign, &map),
+            Command::Close => match self.control.try_send(ServerControl::CloseServer) {
+                Ok(()) => String::from("Closing the server"),
+                Err(_) => String::from("The server is already closing"),
+            },
+            Command::Help => String::from(HELP),
+        }
+    }
+
+    fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return String::from("No one is connected");
+        }
+        let mut clients: Vec<_> = clients.values().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+            .into_iter()
+            .map(|client| {
+                let name = match self.users.get(client.user) {
+                    Ok(Some(user)) => user.name,
+                    _ => format!("user {}", client.user),
+                };
+                let latency = client
+                    .latency
+                    .map(|latency| format!(", {} ms", latency.as_millis()))
+                    .unwrap_or_default();
+                format!(
+                    "{:>4}  {} in {}{}, {} queued",
+                    client.id,
+                    name,
+                    client.campaign,
+                    latency,
+                    client.outbox.stats().depth
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn kick(&self, name: &str, reason: &str) -> String {
+        match self.users.find(name) {
+            Ok(Some((id, _))) => match kick(&self.clients.lock().unwrap(), id, reason) {
+                0 => format!("{} isn't connected", name),
+                count => format!("Disconnected {} ({} connections)", name, count),
+            },
+            Ok(None) => format!("There is no user called {}", name),
+            Err(error) => format!("Couldn't find user: {}", error),
+        }
+    }
+
+    fn activate_map(&self, game: &mut Game, name: &str, map: &str) -> String {
+        // Rooms would open a campaign under any name, so check it's real.
+        match self.campaigns.get(name) {
+            Ok(Some(_)) => {}
+            Ok(None) => return format!("There is no campaign called {}", name),
+            Err(error) => return format!("Couldn't find campaign: {}", error),
+        }
+        let campaign = match game.rooms.get(name) {
+            Ok(campaign) => campaign,
+            Err(error) => return format!("Couldn't load {}: {}", name, error),
+        };
+        let id = map.parse::<MapId>().ok().or_else(|| {
+            campaign
+                .state
+                .maps
+                .iter()
+                .find(|(_, found)| found.name == map)
+                .map(|(id, _)| *id)
+        });
+        let id = match id {
+            Some(id) => id,
+            None => return format!("{} has no map {:?}", name, map),
+        };
+        match campaign.activate_map(id) {
+            Ok(msg) => {
+                game.send(name, &[(Recipients::Everyone, msg)]);
+                format!("Everyone in {} is now shown map {}", name, id)
+            }
+            Err(reason) => format!("Couldn't switch map: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!("list".parse(), Ok(Command::List));
+        assert_eq!(
+            " kick  Gollum ".parse(),
+            Ok(Command::Kick(String::from("Gollum")))
+        );
+        assert_eq!(
+            "announce Break for ten minutes".parse(),
+            Ok(Command::Announce(String::from("Break for ten minutes")))
+        );
+        assert_eq!(
+            "map shire Bag End".parse(),
+            Ok(Command::Map {
+                campaign: String::from("shire"),
+                map: String::from("Bag End"),
+            })
+        );
+        assert!("ban".parse::<Command>().is_err());
+        assert!("map shire".parse::<Command>().is_err());
+        assert!("teleport".parse::<Command>().is_err());
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use server_lib::{AuthMode, MessageLimits, OutboxPolicy};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Prefix of the environment variables that override the config file, e.g.
+/// `VTT_LISTEN` or `VTT_ROOMS_MAX_PLAYERS`.
+const ENV_PREFIX: &str = "VTT_";
+
+/// Everything the server can be configured with. Read from a TOML file, then
+/// overridden by environment variables and finally command line flags.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Where the server listens for both HTTP and websocket connections.
+    pub listen: SocketAddr,
+    /// Where the database and uploaded assets are kept.
+    pub data_dir: PathBuf,
+    /// The built web client, if the server should serve it.
+    pub web_dir: Option<PathBuf>,
+    /// Files anyone can download under `/assets/`. Campaign uploads are kept
+    /// apart in the data directory and only handed out to their campaign.
+    pub public_assets_dir: Option<PathBuf>,
+    /// Where Prometheus metrics are served, if anywhere. Kept apart from
+    /// `listen` so they needn't be public.
+    pub metrics_listen: Option<SocketAddr>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub auth: AuthMode,
+    /// How many clients can be connected at once across every campaign.
+    pub max_clients: Option<usize>,
+    pub connections: ConnectionConfig,
+    pub rooms: RoomConfig,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RoomConfig {
+    /// The campaign clients join if they don't ask for one.
+    pub default: String,
+    /// How many clients can be connected to one campaign at once.
+    pub max_players: Option<usize>,
+    /// Queued messages at which a slow client is sent a snapshot instead.
+    pub lag_threshold: usize,
+    /// Snapshots in a row a slow client can need before it's disconnected.
+    pub max_snapshots: u32,
+}
+
+/// What each client can send before it's warned, and then disconnected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConnectionConfig {
+    /// The largest message a client can send, in bytes, other than uploads.
+    pub max_message_size: usize,
+    /// The largest upload a client can send, in bytes.
+    pub max_upload_size: usize,
+    /// Messages a second a client can keep sending.
+    pub messages_per_second: u32,
+    /// Messages a client can send at once after being quiet.
+    pub burst: u32,
+    /// Messages refused before the client is disconnected.
+    pub warnings: u32,
+}
+
+/// A PEM certificate chain and private key to serve `https://` and `wss://`
+/// with. Both have to be given.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            other => Err(format!(
+                "unknown log level {:?} - expected error, warn, info, debug or trace",
+                other
+            )),
+        }
+    }
+}
+
+impl LogLevel {
+    pub fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// How log lines are written - readable text, or one JSON object per line
+/// for collectors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format {:?} - expected text or json",
+                other
+            )),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3030)),
+            data_dir: dirs::data_dir()
+                .map(|dir| dir.join("vtt"))
+                .unwrap_or_else(|| PathBuf::from("vtt-data")),
+            web_dir: None,
+            public_assets_dir: None,
+            metrics_listen: None,
+            log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            auth: AuthMode::default(),
+            max_clients: None,
+            connections: ConnectionConfig::default(),
+            rooms: RoomConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        let outbox = OutboxPolicy::default();
+        RoomConfig {
+            default: String::from("default"),
+            max_players: None,
+            lag_threshold: outbox.lag_threshold,
+            max_snapshots: outbox.max_snapshots,
+        }
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        let limits = MessageLimits::default();
+        ConnectionConfig {
+            max_message_size: limits.max_size,
+            max_upload_size: limits.max_upload_size,
+            messages_per_second: limits.per_second,
+            burst: limits.burst,
+            warnings: limits.warnings,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /// A setting has a value that can't be used. `setting` is named the way
+    /// it was given - a config key, environment variable or flag.
+    Invalid {
+        setting: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Invalid { setting, reason } => write!(f, "{}: {}", setting, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        toml::from_str(&text).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    /// Sets one setting by its config key, e.g. `rooms.max-players`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => self.listen = parse(value)?,
+            "data-dir" => self.data_dir = PathBuf::from(value),
+            "web-dir" => self.web_dir = Some(PathBuf::from(value)),
+            "public-assets-dir" => self.public_assets_dir = Some(PathBuf::from(value)),
+            "metrics-listen" => self.metrics_listen = Some(parse(value)?),
+            "log-level" => self.log_level = value.parse()?,
+            "log-format" => self.log_format = value.parse()?,
+            "auth" => self.auth = value.parse()?,
+            "max-clients" => self.max_clients = Some(parse(value)?),
+            "connections.max-message-size" => self.connections.max_message_size = parse(value)?,
+            "connections.max-upload-size" => self.connections.max_upload_size = parse(value)?,
+            "connections.messages-per-second" => {
+                self.connections.messages_per_second = parse(value)?
+            }
+            "connections.burst" => self.connections.burst = parse(value)?,
+            "connections.warnings" => self.connections.warnings = parse(value)?,
+            "rooms.default" => self.rooms.default = value.to_string(),
+            "rooms.max-players" => self.rooms.max_players = Some(parse(value)?),
+            "rooms.lag-threshold" => self.rooms.lag_threshold = parse(value)?,
+            "rooms.max-snapshots" => self.rooms.max_snapshots = parse(value)?,
+            "tls.cert" => self.tls.get_or_insert_with(Default::default).cert = Some(value.into()),
+            "tls.key" => self.tls.get_or_insert_with(Default::default).key = Some(value.into()),
+            _ => return Err(String::from("not a setting")),
+        }
+        Ok(())
+    }
+
+    /// Applies every `VTT_` variable that names a setting. Anything else with
+    /// the prefix is left alone, since it could belong to something else.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX).and_then(env_key) {
+                Some(key) => key,
+                None => continue,
+            };
+            self.set(key, &value)
+                .map_err(|reason| ConfigError::Invalid {
+                    setting: name.clone(),
+                    reason,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense together, so the server fails at start
+    /// up rather than part way through.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |setting: &str, reason: &str| {
+            Err(ConfigError::Invalid {
+                setting: setting.to_string(),
+                reason: reason.to_string(),
+            })
+        };
+        if self.max_clients == Some(0) {
+            return invalid("max-clients", "has to be at least 1");
+        }
+        if self.connections.max_message_size == 0 {
+            return invalid("connections.max-message-size", "has to be at least 1");
+        }
+        if self.connections.max_upload_size < self.connections.max_message_size {
+            return invalid(
+                "connections.max-upload-size",
+                "has to be at least max-message-size",
+            );
+        }
+        if self.connections.messages_per_second == 0 {
+            return invalid("connections.messages-per-second", "has to be at least 1");
+        }
+        if self.connections.burst == 0 {
+            return invalid("connections.burst", "has to be at least 1");
+        }
+        if self.rooms.max_players == Some(0) {
+            return invalid("rooms.max-players", "has to be at least 1");
+        }
+        if self.rooms.lag_threshold == 0 {
+            return invalid("rooms.lag-threshold", "has to be at least 1");
+        }
+        let default = self.rooms.default.trim();
+        if default.is_empty() || default.contains('/') {
+            return invalid("rooms.default", "has to be a campaign name");
+        }
+        if self
+            .metrics_listen
+            .is_some_and(|metrics| metrics == self.listen)
+        {
+            return invalid("metrics-listen", "has to be a different address to listen");
+        }
+        let data_dir = resolve(&self.data_dir);
+        for (setting, dir) in [
+            ("web-dir", &self.web_dir),
+            ("public-assets-dir", &self.public_assets_dir),
+        ] {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => continue,
+            };
+            if !dir.is_dir() {
+                return invalid(setting, &format!("{} isn't a directory", dir.display()));
+            }
+            // Everything under a served directory can be downloaded, so it
+            // mustn't overlap the database or campaign uploads.
+            let served = resolve(dir);
+            if served.starts_with(&data_dir) || data_dir.starts_with(&served) {
+                return invalid(setting, "can't overlap the data directory");
+            }
+        }
+        match &self.tls {
+            None
+            | Some(TlsConfig {
+                cert: Some(_),
+                key: Some(_),
+            }) => Ok(()),
+            Some(_) => invalid("tls", "needs both a cert and a key"),
+        }
+    }
+
+    pub fn outbox_policy(&self) -> OutboxPolicy {
+        OutboxPolicy {
+            lag_threshold: self.rooms.lag_threshold,
+            max_snapshots: self.rooms.max_snapshots,
+        }
+    }
+
+    pub fn message_limits(&self) -> MessageLimits {
+        MessageLimits {
+            max_size: self.connections.max_message_size,
+            max_upload_size: self.connections.max_upload_size,
+            per_second: self.connections.messages_per_second,
+            burst: self.connections.burst,
+            warnings: self.connections.warnings,
+        }
+    }
+
+    /// Where campaign uploads are kept.
+    pub fn upload_dir(&self) -> PathBuf {
+        self.data_dir.join("assets")
+    }
+
+    /// The certificate and key files, once validated.
+    pub fn tls_files(&self) -> Option<(&Path, &Path)> {
+        match &self.tls {
+            Some(TlsConfig {
+                cert: Some(cert),
+                key: Some(key),
+            }) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+}
+
+/// The setting an environment variable (without its prefix) overrides.
+fn env_key(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "LISTEN" => "listen",
+        "DATA_DIR" => "data-dir",
+        "WEB_DIR" => "web-dir",
+        "PUBLIC_ASSETS_DIR" => "public-assets-dir",
+        "METRICS_LISTEN" => "metrics-listen",
+        "LOG_LEVEL" => "log-level",
+        "LOG_FORMAT" => "log-format",
+        "AUTH" => "auth",
+        "MAX_CLIENTS" => "max-clients",
+        "CONNECTIONS_MAX_MESSAGE_SIZE" => "connections.max-message-size",
+        "CONNECTIONS_MAX_UPLOAD_SIZE" => "connections.max-upload-size",
+        "CONNECTIONS_MESSAGES_PER_SECOND" => "connections.messages-per-second",
+        "CONNECTIONS_BURST" => "connections.burst",
+        "CONNECTIONS_WARNINGS" => "connections.warnings",
+        "ROOMS_DEFAULT" => "rooms.default",
+        "ROOMS_MAX_PLAYERS" => "rooms.max-players",
+        "ROOMS_LAG_THRESHOLD" => "rooms.lag-threshold",
+        "ROOMS_MAX_SNAPSHOTS" => "rooms.max-snapshots",
+        "TLS_CERT" => "tls.cert",
+        "TLS_KEY" => "tls.key",
+        _ => return None,
+    })
+}
+
+/// The path with symlinks and `..` resolved, as far as it exists, so paths
+/// can be compared. Whatever doesn't exist yet is added back on the end.
+fn resolve(path: &Path) -> PathBuf {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            return missing
+                .iter()
+                .rev()
+                .fold(resolved, |path, part| path.join(part));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|error| format!("{:?} isn't valid: {}", value, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn files_only_need_what_they_change() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "127.0.0.1:8080"
+            auth = "password"
+
+            [rooms]
+            max-players = 6
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(config.auth, AuthMode::Password);
+        assert_eq!(config.rooms.max_players, Some(6));
+        assert_eq!(config.rooms.default, "default");
+        assert!(toml::from_str::<Config>("port = 80").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                ("VTT_LISTEN", "[::1]:4000"),
+                ("VTT_ROOMS_MAX_PLAYERS", "4"),
+                ("VTT_CONNECTIONS_MESSAGES_PER_SECOND", "5"),
+                ("VTT_UNRELATED", "ignored"),
+                ("PATH", "/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.listen.port(), 4000);
+        assert_eq!(config.rooms.max_players, Some(4));
+        assert_eq!(config.message_limits().per_second, 5);
+
+        let error = config
+            .apply_env(env(&[("VTT_MAX_CLIENTS", "lots")]))
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("VTT_MAX_CLIENTS: \"lots\" isn't valid"));
+    }
+
+    #[test]
+    fn invalid_settings_are_reported() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        config.set("tls.cert", "cert.pem").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "tls: needs both a cert and a key"
+        );
+        config.set("tls.key", "key.pem").unwrap();
+        config.set("max-clients", "0").unwrap();
+        assert!(config.validate().is_err());
+        config.set("max-clients", "8").unwrap();
+        config.set("connections.burst", "0").unwrap();
+        assert!(config.validate().is_err());
+        config.set("connections.burst", "10").unwrap();
+        config
+            .set("metrics-listen", &config.listen.to_string())
+            .unwrap();
+        assert!(config.validate().is_err());
+        assert!(config.set("listen", "nowhere").is_err());
+        assert!(config.set("log-level", "loud").is_err());
+        assert!(config.set("log-format", "xml").is_err());
+    }
+
+    #[test]
+    fn uploads_arent_public() {
+        let dir = std::env::temp_dir().join(format!("vtt-config-{}", std::process::id()));
+        let data_dir = dir.join("data");
+        let public = dir.join("public");
+        fs::create_dir_all(data_dir.join("assets")).unwrap();
+        fs::create_dir_all(&public).unwrap();
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            ..Config::default()
+        };
+        for overlapping in [&dir, &data_dir, &config.upload_dir(), &data_dir.join("..")] {
+            config
+                .set("public-assets-dir", &overlapping.display().to_string())
+                .unwrap();
+            assert!(config.validate().is_err());
+        }
+        config
+            .set("public-assets-dir", &public.display().to_string())
+            .unwrap();
+        assert!(config.validate().is_ok());
+        config
+            .set("web-dir", &data_dir.display().to_string())
+            .unwrap();
+        assert!(config.validate().is_err());
+
+        // The data directory needn't exist yet.
+        config.data_dir = public.join("vtt");
+        config.web_dir = None;
+        assert!(config.validate().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn printed_config_reads_back_the_same() {
+        let mut config = Config::default();
+        config.set("web-dir", "client-bevy").unwrap();
+        config.set("tls.cert", "cert.pem").unwrap();
+        config.set("tls.key", "key.pem").unwrap();
+        let printed: Config = toml::from_str(&config.to_toml()).unwrap();
+        assert_eq!(printed, config);
+    }
+}
@@ -1,24 +1,78 @@
-use clap::{App, Arg};
-use dirs::document_dir;
-use server_lib::Server;
+mod admin;
+mod config;
+
+use admin::Admin;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use config::{Config, ConfigError, LogFormat};
+use crossbeam_channel::select;
+use protocol::Role;
+use server_lib::{
+    load_tls, serve_metrics, AssetStore, CampaignRegistry, Files, Game, Limits, Rooms, Server,
+    ServerControl, UserStore,
+};
 use sled::{self, Db};
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    env,
+    io::{self, BufRead, Write},
+    net::IpAddr,
     path::PathBuf,
-    str::FromStr,
 };
+use tracing::{error, info, warn};
+use tracing_subscriber::{filter::Targets, prelude::*};
+
+/// The crates `log-level` applies to. Everything else only logs warnings.
+const LOGGED_CRATES: [&str; 4] = ["server", "server_lib", "client_lib", "protocol"];
 
-fn parse_arguments() -> (SocketAddr, PathBuf) {
+/// Read if it exists and no other config file is given.
+const DEFAULT_CONFIG: &str = "vtt.toml";
+
+/// Flags that override a config setting directly, and the setting each one
+/// sets.
+const SETTING_FLAGS: [(&str, &str); 8] = [
+    ("dir", "data-dir"),
+    ("campaign", "rooms.default"),
+    ("lag-threshold", "rooms.lag-threshold"),
+    ("max-snapshots", "rooms.max-snapshots"),
+    ("cert", "tls.cert"),
+    ("key", "tls.key"),
+    ("web", "web-dir"),
+    ("metrics", "metrics-listen"),
+];
+
+/// What the server was asked to do - run, or manage its users and stop.
+enum Command {
+    Serve,
+    PrintConfig,
+    AddUser { name: String },
+    Invite { role: Role },
+    SetRole { name: String, role: Role },
+    ListCampaigns,
+    CreateCampaign { name: String },
+    ArchiveCampaign { name: String },
+}
+
+fn parse_arguments() -> (Config, Command) {
     let matches = App::new("VTT Server")
         .version("0.1")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads settings from this TOML file (default: $VTT_CONFIG or ./vtt.toml)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Prints the settings the server would run with, then exits"),
+        )
         .arg(
             Arg::with_name("host")
                 .short("h")
                 .long("host")
                 .value_name("ADDRESS")
                 .help("Sets the bound host")
-                .takes_value(true)
-                .default_value("0.0.0.0"),
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("port")
@@ -26,38 +80,229 @@ fn parse_arguments() -> (SocketAddr, PathBuf) {
                 .long("port")
                 .value_name("PORT")
                 .help("Sets the bound port")
-                .takes_value(true)
-                .default_value("3030"),
+                .takes_value(true),
         )
         .arg(
-            Arg::with_name("directory")
+            Arg::with_name("dir")
                 .short("d")
                 .long("dir")
                 .value_name("DIRECTORY")
-                .help("Sets the source directory")
+                .help("Sets where the database and assets are kept")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("campaign")
+                .short("c")
+                .long("campaign")
+                .value_name("NAME")
+                .help("Sets the campaign clients join if they don't pick one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lag-threshold")
+                .long("lag-threshold")
+                .value_name("MESSAGES")
+                .help("Queued messages at which a slow client is sent a snapshot instead")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-snapshots")
+                .long("max-snapshots")
+                .value_name("COUNT")
+                .help("Snapshots in a row a slow client can need before it's disconnected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .value_name("FILE")
+                .help("Serves wss:// using this PEM certificate chain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("FILE")
+                .help("The PEM private key for --cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("web")
+                .long("web")
+                .value_name("DIRECTORY")
+                .help("Serves the web client from this directory (index.html and pkg/)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .value_name("ADDRESS")
+                .help("Serves Prometheus metrics at /metrics on this address, e.g. 127.0.0.1:9100")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("add-user")
+                .about("Registers a user as a player in the campaign, asking for their password")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("invite")
+                .about("Makes an invite token that lets new players into the campaign")
+                .arg(
+                    Arg::with_name("role")
+                        .long("role")
+                        .value_name("ROLE")
+                        .help("What invited players can do - gm, player or spectator")
+                        .takes_value(true)
+                        .default_value("player"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-role")
+                .about("Sets what a user can do in the campaign")
+                .arg(Arg::with_name("name").required(true))
+                .arg(
+                    Arg::with_name("role")
+                        .required(true)
+                        .help("gm, player or spectator"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rooms")
+                .about("Manages the campaigns this server hosts")
+                .subcommand(SubCommand::with_name("list").about("Lists the campaigns"))
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Adds a campaign players can join")
+                        .arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("archive")
+                        .about("Stops players joining a campaign, keeping its state")
+                        .arg(Arg::with_name("name").required(true)),
+                ),
+        )
         .get_matches();
-    let host = matches.value_of("host").unwrap_or("0.0.0.0");
-    let port = matches.value_of("port").unwrap_or("3030");
-    let current_directory = String::from(
-        document_dir()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default(),
-    );
-    let directory = matches
-        .value_of("directory")
-        .unwrap_or_else(|| current_directory.as_str());
+    let config = match build_config(&matches) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Invalid configuration - {}", error);
+            std::process::exit(2);
+        }
+    };
 
-    println!("Host: {}", host);
-    println!("Port: {}", port);
-    println!("Directory: {}", directory);
+    let command = match matches.subcommand() {
+        ("add-user", Some(args)) => Command::AddUser {
+            name: args.value_of("name").unwrap_or_default().to_string(),
+        },
+        ("invite", Some(args)) => Command::Invite {
+            role: parse_role(args.value_of("role")),
+        },
+        ("set-role", Some(args)) => Command::SetRole {
+            name: args.value_of("name").unwrap_or_default().to_string(),
+            role: parse_role(args.value_of("role")),
+        },
+        ("rooms", Some(args)) => match args.subcommand() {
+            ("create", Some(args)) => Command::CreateCampaign {
+                name: args.value_of("name").unwrap_or_default().to_string(),
+            },
+            ("archive", Some(args)) => Command::ArchiveCampaign {
+                name: args.value_of("name").unwrap_or_default().to_string(),
+            },
+            _ => Command::ListCampaigns,
+        },
+        _ if matches.is_present("print-config") => Command::PrintConfig,
+        _ => Command::Serve,
+    };
 
-    let host_addr = SocketAddr::from_str(format!("{}:{}", host, port).as_str())
-        .unwrap_or_else(|_| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3030)));
+    (config, command)
+}
 
-    (host_addr, PathBuf::from_str(directory).unwrap_or_default())
+/// Builds the settings from the config file, then the environment, then the
+/// command line, each overriding the last.
+fn build_config(matches: &ArgMatches) -> Result<Config, ConfigError> {
+    let file = matches
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("VTT_CONFIG").map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG)).filter(|file| file.exists()));
+    let mut config = match &file {
+        Some(file) => Config::load(file)?,
+        None => Config::default(),
+    };
+    config.apply_env(env::vars())?;
+
+    let invalid = |flag: &str, reason: String| ConfigError::Invalid {
+        setting: format!("--{}", flag),
+        reason,
+    };
+    if let Some(host) = matches.value_of("host") {
+        let ip: IpAddr = host
+            .parse()
+            .map_err(|_| invalid("host", format!("{:?} isn't an IP address", host)))?;
+        config.listen.set_ip(ip);
+    }
+    if let Some(port) = matches.value_of("port") {
+        let port: u16 = port
+            .parse()
+            .map_err(|_| invalid("port", format!("{:?} isn't a port", port)))?;
+        config.listen.set_port(port);
+    }
+    for (flag, key) in SETTING_FLAGS {
+        if let Some(value) = matches.value_of(flag) {
+            config
+                .set(key, value)
+                .map_err(|reason| invalid(flag, reason))?;
+        }
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn parse_role(role: Option<&str>) -> Role {
+    match role.unwrap_or("player").parse() {
+        Ok(role) => role,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn add_user(users: &UserStore, name: &str, campaign: &str) {
+    print!("Password for {} (leave empty for invite-only): ", name);
+    let _ = io::stdout().flush();
+    let mut password = String::new();
+    if io::stdin().lock().read_line(&mut password).is_err() {
+        eprintln!("Couldn't read password");
+        return;
+    }
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    let password = if password.is_empty() {
+        None
+    } else {
+        Some(password)
+    };
+    let added = users
+        .add(name, password)
+        .and_then(|id| users.set_role(campaign, id, Role::Player).map(|()| id));
+    match added {
+        Ok(id) => println!("Added {} to {} with id {}", name, campaign, id),
+        Err(error) => eprintln!("Couldn't add user: {}", error),
+    }
+}
+
+fn init_logging(config: &Config) {
+    let filter = LOGGED_CRATES.iter().fold(
+        Targets::new().with_default(tracing::Level::WARN),
+        |filter, name| filter.with_target(*name, config.log_level.filter()),
+    );
+    let layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.log_format {
+        LogFormat::Text => registry.with(layer).init(),
+        LogFormat::Json => registry.with(layer.json()).init(),
+    }
 }
 
 fn setup_database(mut file: PathBuf) -> Result<Db, sled::Error> {
@@ -66,34 +311,176 @@ fn setup_database(mut file: PathBuf) -> Result<Db, sled::Error> {
 }
 #[tokio::main]
 async fn main() {
-    println!("Running VTT Server");
-    let (host_addr, directory) = parse_arguments();
+    let (config, command) = parse_arguments();
+    if let Command::PrintConfig = command {
+        print!("{}", config.to_toml());
+        return;
+    }
+    init_logging(&config);
+    info!(data_dir = %config.data_dir.display(), "Running VTT Server");
+    let campaign = config.rooms.default.clone();
 
-    let db_result = setup_database(directory);
+    let db_result = setup_database(config.data_dir.clone());
 
-    if db_result.is_ok() {
-        let server = Server::<String>::new(host_addr.to_string());
+    if let Ok(db) = db_result {
+        let users = match UserStore::open(&db) {
+            Ok(users) => users,
+            Err(error) => {
+                error!(%error, "Couldn't load users");
+                return;
+            }
+        };
+        let campaigns = match CampaignRegistry::open(&db) {
+            Ok(campaigns) => campaigns,
+            Err(error) => {
+                error!(%error, "Couldn't load campaigns");
+                return;
+            }
+        };
+        match command {
+            Command::Serve | Command::PrintConfig => {}
+            Command::AddUser { name } => return add_user(&users, &name, &campaign),
+            Command::Invite { role } => {
+                match users.invite(&campaign, role) {
+                    Ok(token) => println!("Invite token for {}: {}", campaign, token),
+                    Err(error) => eprintln!("Couldn't make invite: {}", error),
+                }
+                return;
+            }
+            Command::SetRole { name, role } => {
+                match users.find(&name) {
+                    Ok(Some((id, _))) => match users.set_role(&campaign, id, role) {
+                        Ok(()) => println!("{} is now {:?} in {}", name, role, campaign),
+                        Err(error) => eprintln!("Couldn't set role: {}", error),
+                    },
+                    Ok(None) => eprintln!("There is no user called {}", name),
+                    Err(error) => eprintln!("Couldn't find user: {}", error),
+                }
+                return;
+            }
+            Command::ListCampaigns => {
+                match campaigns.list() {
+                    Ok(list) => {
+                        for (name, info) in list {
+                            let archived = if info.archived { " (archived)" } else { "" };
+                            println!("{}{}", name, archived);
+                        }
+                    }
+                    Err(error) => eprintln!("Couldn't list campaigns: {}", error),
+                }
+                return;
+            }
+            Command::CreateCampaign { name } => {
+                match campaigns.create(&name) {
+                    Ok(()) => println!("Created {}", name),
+                    Err(error) => eprintln!("Couldn't create campaign: {}", error),
+                }
+                return;
+            }
+            Command::ArchiveCampaign { name } => {
+                match campaigns.archive(&name) {
+                    Ok(()) => println!("Archived {}", name),
+                    Err(error) => eprintln!("Couldn't archive campaign: {}", error),
+                }
+                return;
+            }
+        };
+        if let Err(error) = campaigns.ensure(&campaign) {
+            error!(%campaign, %error, "Couldn't register campaign");
+            return;
+        }
+        info!(listen = %config.listen, default_campaign = %campaign, "Starting server");
+        let server = Server::new(
+            config.listen.to_string(),
+            campaign,
+            users.clone(),
+            campaigns.clone(),
+        );
+        let mut rooms = Rooms::new(db);
+        rooms.users = Some(users.clone());
+        let assets = config.upload_dir();
+        match AssetStore::open(assets.clone()) {
+            Ok(store) => rooms.assets = Some(store),
+            Err(error) => warn!(
+                dir = %assets.display(),
+                %error,
+                "Uploads are disabled - couldn't open the asset directory"
+            ),
+        }
         if server.is_err() {
-            eprintln!("Couldn't set up server");
+            error!("Couldn't set up server");
             return;
         }
-        let server = server.unwrap();
+        let mut server = server.unwrap();
+        server.outbox_policy = config.outbox_policy();
+        server.limits = Limits {
+            max_clients: config.max_clients,
+            max_players: config.rooms.max_players,
+        };
+        server.message_limits = config.message_limits();
+        server.auth = config.auth;
+        if let Some((cert, key)) = config.tls_files() {
+            match load_tls(cert, key) {
+                Ok(config) => server.tls = Some(config),
+                Err(error) => {
+                    error!(cert = %cert.display(), %error, "Couldn't load TLS certificate");
+                    return;
+                }
+            }
+        }
+        server.files = Files {
+            web: config.web_dir.clone(),
+            assets: config.public_assets_dir.clone(),
+        };
+        rooms.metrics = Some(server.metrics.clone());
+        if let Some(address) = config.metrics_listen {
+            let metrics = server.metrics.clone();
+            let clients = server.clients.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve_metrics(address, metrics, clients).await {
+                    error!(%address, %error, "Stopped serving metrics");
+                }
+            });
+        }
         let clients = server.clients.clone();
         let receiver = server.reciever.clone();
+        let control = server.control_sender.clone();
+        let admin = Admin {
+            users,
+            campaigns,
+            clients: clients.clone(),
+            control: control.clone(),
+        };
+        let (command_sender, mut commands) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || admin::read_commands(command_sender));
 
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Shutting down");
+                let _ = control.send(ServerControl::CloseServer).await;
+            }
+        });
         tokio::spawn(server.start());
-        while let Ok(msg) = receiver.recv() {
-            let clients = clients.lock().unwrap();
-            for (id, client) in clients.iter() {
-                if msg.0 == *id {
-                    continue;
-                }
-                if client.sender.try_send(msg.to_owned()).is_err() {
-                    eprint!("Failed to send a message");
-                }
+        let mut game = Game::new(rooms, clients);
+        // Runs until the server has shut down and every connection has ended.
+        loop {
+            select! {
+                recv(receiver) -> event => match event {
+                    Ok((from, event)) => game.handle(from, event),
+                    Err(_) => break,
+                },
+                recv(commands) -> command => match command {
+                    Ok(command) => println!("{}", admin.run(command, &mut game)),
+                    // Stdin has closed, so there won't be any more.
+                    Err(_) => commands = crossbeam_channel::never(),
+                },
             }
         }
+        match game.rooms.flush() {
+            Ok(()) => info!("Saved campaigns"),
+            Err(error) => error!(%error, "Couldn't save campaigns"),
+        }
     } else {
-        println!("Failed to set up database");
+        error!("Failed to set up database");
     }
 }
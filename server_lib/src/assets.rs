@@ -0,0 +1,70 @@
+use std::{fs, io, path::PathBuf};
+
+use protocol::{asset_hash, check_asset, AssetError, AssetInfo};
+use tracing::error;
+
+/// Uploaded files, kept in a directory and named after their hash. Shared by
+/// every campaign on the server, so each file is only stored once however
+/// many campaigns use it.
+#[derive(Debug, Clone)]
+pub struct AssetStore {
+    dir: PathBuf,
+}
+
+impl AssetStore {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(AssetStore { dir })
+    }
+
+    /// Checks and stores a file, returning how the campaign should list it.
+    pub fn put(&self, name: &str, mime: &str, data: &[u8]) -> Result<AssetInfo, AssetError> {
+        check_asset(mime, data)?;
+        let info = AssetInfo {
+            hash: asset_hash(data),
+            name: name.to_string(),
+            mime: mime.to_string(),
+            size: data.len() as u64,
+        };
+        let file = self.dir.join(info.file_name());
+        if !file.exists() {
+            // Written aside and moved into place so a half-written file is
+            // never served under the hash.
+            let partial = file.with_extension("partial");
+            fs::write(&partial, data)
+                .and_then(|()| fs::rename(&partial, &file))
+                .map_err(|error| {
+                    error!(hash = %info.hash, %error, "Couldn't store asset");
+                    AssetError::Unavailable
+                })?;
+        }
+        Ok(info)
+    }
+
+    pub fn read(&self, info: &AssetInfo) -> Result<Vec<u8>, AssetError> {
+        fs::read(self.dir.join(info.file_name())).map_err(|error| {
+            error!(hash = %info.hash, %error, "Couldn't read asset");
+            AssetError::Unavailable
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_files_are_stored_once() {
+        let dir = std::env::temp_dir().join(format!("vtt-assets-{}", std::process::id()));
+        let store = AssetStore::open(dir.clone()).unwrap();
+        let png = b"\x89PNG\r\n\x1a\nrest of the image";
+        let first = store.put("token.png", "image/png", png).unwrap();
+        let second = store.put("copy.png", "image/png", png).unwrap();
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(dir.join(format!("{}.png", first.hash)).exists());
+        assert_eq!(store.read(&second).unwrap(), png);
+        assert!(store.put("page.html", "text/html", b"<html>").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
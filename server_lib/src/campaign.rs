@@ -0,0 +1,1016 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use protocol::{
+    AssetData, AssetError, AssetHash, CampaignState, ChatChannel, ChatEntry, ClientId,
+    ClientMessage, InvalidChat, InvalidEdit, MapId, Role, Seq, ServerMessage, UserId,
+    MAX_CHAT_LENGTH,
+};
+use sled::Db;
+use tracing::{debug_span, error, info};
+
+use crate::{AssetStore, CampaignStore, Metrics, StorageError, UserStore};
+
+/// Who a message from the campaign should be delivered to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipients {
+    Everyone,
+    Client(ClientId),
+    /// Every connection these users have.
+    Users(Vec<UserId>),
+    GameMasters,
+}
+
+/// Who a message came from - the connection any reply goes back on, the user
+/// behind it and their name, what they're allowed to do and which campaign
+/// they're playing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Participant {
+    pub client: ClientId,
+    pub user: UserId,
+    pub name: String,
+    pub role: Role,
+    pub campaign: String,
+}
+
+/// How many recent changes are kept around to catch up a client that missed
+/// some. Anyone further behind than this is sent a snapshot instead.
+const HISTORY: usize = 256;
+
+/// How many chat messages a snapshot carries. The full log stays on the server.
+const CHAT_BACKLOG: usize = 100;
+
+/// How many chat messages are sent for each page of history asked for.
+const CHAT_PAGE: usize = 50;
+
+/// The game being played on a server - its current state, and where that
+/// state gets saved if it's backed by a database.
+#[derive(Debug, Default)]
+pub struct Campaign {
+    pub state: CampaignState,
+    /// Where uploaded files are kept. Uploads are refused without one.
+    pub assets: Option<AssetStore>,
+    /// Where the time taken to save is recorded, if anywhere.
+    pub metrics: Option<Arc<Metrics>>,
+    /// Who's a member of the campaign, so whispers only go to them. Whispers
+    /// are refused without one.
+    pub users: Option<UserStore>,
+    store: Option<CampaignStore>,
+    last_id: u64,
+    seq: Seq,
+    history: VecDeque<ServerMessage>,
+}
+
+impl Campaign {
+    /// Opens the campaign called `name`, loading whatever was saved last time.
+    pub fn open(db: &Db, name: &str) -> Result<Self, StorageError> {
+        let store = CampaignStore::open(db, name)?;
+        let state = store.load()?;
+        Ok(Campaign {
+            last_id: state.max_id(),
+            state,
+            store: Some(store),
+            ..Default::default()
+        })
+    }
+
+    /// Validates and applies a message from a client. Changes that go through
+    /// are sent to everyone, while anything invalid or not allowed is only
+    /// reported back to the client that sent it.
+    pub fn handle(
+        &mut self,
+        from: &Participant,
+        msg: ClientMessage,
+    ) -> Vec<(Recipients, ServerMessage)> {
+        let _span = debug_span!(
+            "message",
+            client = from.client,
+            user = from.user,
+            kind = msg.kind()
+        )
+        .entered();
+        if let Err(reason) = self.state.permits(from.user, from.role, &msg) {
+            info!(%reason, "Denied message");
+            return vec![(
+                Recipients::Client(from.client),
+                ServerMessage::PermissionDenied { reason },
+            )];
+        }
+        let msg = match msg {
+            ClientMessage::Resync { since } => {
+                return self
+                    .resync(since)
+                    .into_iter()
+                    .map(|msg| (Recipients::Client(from.client), msg))
+                    .collect();
+            }
+            ClientMessage::FetchAsset { hash } => {
+                let reply = match self.fetch_asset(hash) {
+                    Ok(reply) => reply,
+                    Err(reason) => ServerMessage::AssetRejected { reason },
+                };
+                return vec![(Recipients::Client(from.client), reply)];
+            }
+            ClientMessage::Chat {
+                text,
+                channel,
+                in_character,
+            } => {
+                if let Err(reason) = self.check_chat(from, &text, channel) {
+                    info!(%reason, "Rejected chat");
+                    return vec![(
+                        Recipients::Client(from.client),
+                        ServerMessage::ChatRejected { reason },
+                    )];
+                }
+                let entry = self.chat(from, text, channel, in_character);
+                return match channel {
+                    ChatChannel::Everyone => vec![(
+                        Recipients::Everyone,
+                        self.sequence(ServerMessage::Chat { seq: 0, entry }),
+                    )],
+                    ChatChannel::GameMasters => {
+                        vec![(
+                            Recipients::GameMasters,
+                            ServerMessage::PrivateChat { entry },
+                        )]
+                    }
+                    ChatChannel::Whisper { to } => vec![(
+                        Recipients::Users(vec![from.user, to]),
+                        ServerMessage::PrivateChat { entry },
+                    )],
+                };
+            }
+            ClientMessage::ChatHistory { before } => {
+                return vec![(
+                    Recipients::Client(from.client),
+                    self.chat_history(from, before),
+                )];
+            }
+            ClientMessage::UploadAsset { name, mime, data } => {
+                return match self.upload_asset(from.user, &name, &mime, &data) {
+                    Ok(added) => vec![(Recipients::Everyone, self.sequence(added))],
+                    Err(reason) => {
+                        info!(?name, %reason, "Rejected asset");
+                        vec![(
+                            Recipients::Client(from.client),
+                            ServerMessage::AssetRejected { reason },
+                        )]
+                    }
+                };
+            }
+            msg => msg,
+        };
+        match self.apply(from, msg) {
+            Ok(Some(msg)) => vec![(Recipients::Everyone, self.sequence(msg))],
+            Ok(None) => vec![],
+            Err(reason) => {
+                info!(%reason, "Rejected edit");
+                vec![(
+                    Recipients::Client(from.client),
+                    ServerMessage::EditRejected { reason },
+                )]
+            }
+        }
+    }
+
+    /// Applies a message to the state, returning the change to broadcast. Its
+    /// sequence number is filled in afterwards by `sequence`.
+    fn apply(
+        &mut self,
+        participant: &Participant,
+        msg: ClientMessage,
+    ) -> Result<Option<ServerMessage>, InvalidEdit> {
+        let from = participant.user;
+        match msg {
+            ClientMessage::Hello { .. }
+            | ClientMessage::Resync { .. }
+            | ClientMessage::Chat { .. }
+            | ClientMessage::ChatHistory { .. }
+            | ClientMessage::UploadAsset { .. }
+            | ClientMessage::FetchAsset { .. } => Ok(None),
+            ClientMessage::MapEdit(edit) => {
+                self.state.validate(&edit)?;
+                let change = edit.resolve(|| self.allocate());
+                let changes = self.state.apply(change.clone());
+                for change in changes.iter() {
+                    self.save(|store| store.record(change));
+                }
+                Ok(Some(ServerMessage::MapChanged {
+                    seq: 0,
+                    from,
+                    change,
+                }))
+            }
+            ClientMessage::PlaceToken { mut token } => {
+                if participant.role != Role::GameMaster {
+                    token.owner = Some(from);
+                }
+                let id = self.allocate();
+                self.save(|store| store.put_token(id, &token));
+                self.state.tokens.insert(id, token.clone());
+                Ok(Some(ServerMessage::TokenPlaced {
+                    seq: 0,
+                    from,
+                    id,
+                    token,
+                }))
+            }
+            ClientMessage::MoveToken { id, position } => {
+                let token = self
+                    .state
+                    .tokens
+                    .get_mut(&id)
+                    .ok_or(InvalidEdit::UnknownToken(id))?;
+                token.position = position;
+                let token = token.clone();
+                self.save(|store| store.put_token(id, &token));
+                Ok(Some(ServerMessage::TokenMoved {
+                    seq: 0,
+                    from,
+                    id,
+                    position,
+                }))
+            }
+        }
+    }
+
+    /// Checks a chat message says something, isn't too long and, if it's a
+    /// whisper, is to a member of the campaign.
+    fn check_chat(
+        &self,
+        from: &Participant,
+        text: &str,
+        channel: ChatChannel,
+    ) -> Result<(), InvalidChat> {
+        if text.trim().is_empty() {
+            return Err(InvalidChat::Empty);
+        }
+        let length = text.chars().count();
+        if length > MAX_CHAT_LENGTH {
+            return Err(InvalidChat::TooLong {
+                length,
+                limit: MAX_CHAT_LENGTH,
+            });
+        }
+        if let ChatChannel::Whisper { to } = channel {
+            let member = match &self.users {
+                Some(users) => match users.role(&from.campaign, to) {
+                    Ok(role) => role.is_some(),
+                    Err(error) => {
+                        error!(%error, "Couldn't look up who a whisper is for");
+                        false
+                    }
+                },
+                None => false,
+            };
+            if !member {
+                return Err(InvalidChat::UnknownRecipient(to));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a message to the chat log, stamped with who sent it and when.
+    fn chat(
+        &mut self,
+        from: &Participant,
+        text: String,
+        channel: ChatChannel,
+        in_character: bool,
+    ) -> ChatEntry {
+        let sent = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or_default();
+        let entry = ChatEntry {
+            id: self.state.chat.last().map_or(0, |last| last.id + 1),
+            from: from.user,
+            name: from.name.clone(),
+            sent,
+            channel,
+            in_character,
+            text,
+        };
+        self.save(|store| store.push_chat(&entry));
+        self.state.chat.push(entry.clone());
+        entry
+    }
+
+    /// A page of the chat `from` is allowed to read, ending just before
+    /// message `before` or with the latest.
+    fn chat_history(&self, from: &Participant, before: Option<u64>) -> ServerMessage {
+        let mut visible = self
+            .state
+            .chat
+            .iter()
+            .rev()
+            .filter(|entry| before.is_none_or(|before| entry.id < before))
+            .filter(|entry| entry.visible_to(from.user, from.role));
+        let mut entries: Vec<_> = visible.by_ref().take(CHAT_PAGE).cloned().collect();
+        let more = visible.next().is_some();
+        entries.reverse();
+        ServerMessage::ChatHistory { entries, more }
+    }
+
+    /// Stores an uploaded file and adds it to the campaign's assets.
+    fn upload_asset(
+        &mut self,
+        from: UserId,
+        name: &str,
+        mime: &str,
+        data: &[u8],
+    ) -> Result<ServerMessage, AssetError> {
+        let info = self
+            .assets
+            .as_ref()
+            .ok_or(AssetError::Unavailable)?
+            .put(name, mime, data)?;
+        self.save(|store| store.put_asset(&info));
+        self.state.assets.insert(info.hash.clone(), info.clone());
+        Ok(ServerMessage::AssetAdded { seq: 0, from, info })
+    }
+
+    /// Reads one of this campaign's assets. Files other campaigns uploaded
+    /// can't be fetched through it.
+    fn fetch_asset(&self, hash: AssetHash) -> Result<ServerMessage, AssetError> {
+        let info = match self.state.assets.get(&hash) {
+            Some(info) => info,
+            None => return Err(AssetError::Unknown(hash)),
+        };
+        let data = self
+            .assets
+            .as_ref()
+            .ok_or(AssetError::Unavailable)?
+            .read(info)?;
+        Ok(ServerMessage::Asset {
+            hash,
+            data: AssetData(data),
+        })
+    }
+
+    /// Shows everyone in the campaign one of its maps, returning the change to
+    /// send them.
+    pub fn activate_map(&mut self, map: MapId) -> Result<ServerMessage, InvalidEdit> {
+        if !self.state.maps.contains_key(&map) {
+            return Err(InvalidEdit::UnknownMap(map));
+        }
+        self.state.active_map = Some(map);
+        self.save(|store| store.set_active_map(Some(map)));
+        Ok(self.sequence(ServerMessage::MapActivated { seq: 0, map }))
+    }
+
+    /// Gives a change the next sequence number and remembers it for clients
+    /// that need to catch up.
+    fn sequence(&mut self, msg: ServerMessage) -> ServerMessage {
+        self.seq += 1;
+        let msg = msg.with_seq(self.seq);
+        self.history.push_back(msg.clone());
+        if self.history.len() > HISTORY {
+            self.history.pop_front();
+        }
+        msg
+    }
+
+    /// Everything a client needs after change `since` - just the missing
+    /// changes if they're still in the history, otherwise a fresh snapshot.
+    pub fn resync(&self, since: Seq) -> Vec<ServerMessage> {
+        let oldest = self.seq - self.history.len() as Seq;
+        if since < oldest || since > self.seq {
+            return vec![self.snapshot()];
+        }
+        self.history
+            .iter()
+            .skip((since - oldest) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Everything a newly connected client needs to catch up, with only the
+    /// most recent part of the chat everyone can read. Whispers and messages
+    /// to the game masters are left for the client to ask for.
+    pub fn snapshot(&self) -> ServerMessage {
+        let mut state = self.state.clone();
+        state
+            .chat
+            .retain(|entry| entry.channel == ChatChannel::Everyone);
+        let skip = state.chat.len().saturating_sub(CHAT_BACKLOG);
+        state.chat.drain(..skip);
+        ServerMessage::Snapshot {
+            seq: self.seq,
+            state,
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        match &self.store {
+            Some(store) => self.timed(|| store.flush()),
+            None => Ok(()),
+        }
+    }
+
+    fn allocate(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn save(&self, write: impl FnOnce(&CampaignStore) -> Result<(), StorageError>) {
+        if let Some(store) = &self.store {
+            if let Err(error) = self.timed(|| write(store)) {
+                error!(%error, "Failed to save campaign");
+            }
+        }
+    }
+
+    fn timed<T>(&self, write: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = write();
+        if let Some(metrics) = &self.metrics {
+            metrics.wrote(started.elapsed());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{Forbidden, Map, MapChange, MapEdit, Token};
+
+    fn player(id: usize) -> Participant {
+        Participant {
+            client: id,
+            user: id as UserId,
+            name: format!("Player {}", id),
+            role: Role::Player,
+            campaign: String::from("test"),
+        }
+    }
+
+    fn game_master(id: usize) -> Participant {
+        Participant {
+            role: Role::GameMaster,
+            ..player(id)
+        }
+    }
+
+    fn temporary_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    /// A campaign whose members are players 1 to 3.
+    fn with_members() -> Campaign {
+        let users = UserStore::temporary().unwrap();
+        for id in 1..=3 {
+            users.set_role("test", id, Role::Player).unwrap();
+        }
+        Campaign {
+            users: Some(users),
+            ..Campaign::default()
+        }
+    }
+
+    #[test]
+    fn edits_are_given_fresh_ids() {
+        let mut campaign = Campaign::default();
+        let created = campaign.handle(
+            &game_master(1),
+            ClientMessage::MapEdit(MapEdit::CreateMap {
+                map: Map {
+                    name: String::from("Cave"),
+                },
+            }),
+        );
+        assert_eq!(
+            created,
+            vec![(
+                Recipients::Everyone,
+                ServerMessage::MapChanged {
+                    seq: 1,
+                    from: 1,
+                    change: MapChange::MapSet {
+                        id: 1,
+                        map: Map {
+                            name: String::from("Cave"),
+                        },
+                    },
+                }
+            )]
+        );
+        let placed = campaign.handle(
+            &player(1),
+            ClientMessage::PlaceToken {
+                token: Token {
+                    name: String::from("Hero"),
+                    position: [0., 0., 0.],
+                    owner: None,
+                },
+            },
+        );
+        assert!(matches!(
+            placed.as_slice(),
+            [(
+                Recipients::Everyone,
+                ServerMessage::TokenPlaced { seq: 2, id: 2, .. }
+            )]
+        ));
+    }
+
+    #[test]
+    fn moving_a_missing_token_is_rejected_to_the_sender() {
+        let mut campaign = Campaign::default();
+        let moved = campaign.handle(
+            &player(1),
+            ClientMessage::MoveToken {
+                id: 5,
+                position: [1., 0., 1.],
+            },
+        );
+        assert_eq!(
+            moved,
+            vec![(
+                Recipients::Client(1),
+                ServerMessage::EditRejected {
+                    reason: InvalidEdit::UnknownToken(5)
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn invalid_edits_leave_the_state_alone() {
+        let mut campaign = Campaign::default();
+        let rejected = campaign.handle(
+            &game_master(3),
+            ClientMessage::MapEdit(MapEdit::RemoveZone { id: 1 }),
+        );
+        assert_eq!(
+            rejected,
+            vec![(
+                Recipients::Client(3),
+                ServerMessage::EditRejected {
+                    reason: InvalidEdit::UnknownZone(1)
+                }
+            )]
+        );
+        assert_eq!(campaign.state, CampaignState::default());
+        assert_eq!(campaign.last_id, 0);
+    }
+
+    #[test]
+    fn concurrent_moves_are_applied_in_order() {
+        let mut campaign = Campaign::default();
+        campaign.handle(
+            &player(1),
+            ClientMessage::PlaceToken {
+                token: Token {
+                    name: String::from("Hero"),
+                    position: [0., 0., 0.],
+                    owner: None,
+                },
+            },
+        );
+        let first = campaign.handle(
+            &player(1),
+            ClientMessage::MoveToken {
+                id: 1,
+                position: [1., 0., 0.],
+            },
+        );
+        let second = campaign.handle(
+            &game_master(2),
+            ClientMessage::MoveToken {
+                id: 1,
+                position: [2., 0., 0.],
+            },
+        );
+        assert_eq!(first[0].0, Recipients::Everyone);
+        assert_eq!(second[0].0, Recipients::Everyone);
+        assert_eq!(campaign.state.tokens[&1].position, [2., 0., 0.]);
+    }
+
+    #[test]
+    fn state_survives_reopening() {
+        let db = temporary_db();
+        let mut campaign = Campaign::open(&db, "test").unwrap();
+        campaign.handle(
+            &player(1),
+            ClientMessage::Chat {
+                text: String::from("hello"),
+                channel: ChatChannel::Everyone,
+                in_character: true,
+            },
+        );
+        campaign.handle(
+            &player(2),
+            ClientMessage::PlaceToken {
+                token: Token {
+                    name: String::from("Hero"),
+                    position: [0., 0., 0.],
+                    owner: None,
+                },
+            },
+        );
+        campaign.handle(
+            &player(2),
+            ClientMessage::MoveToken {
+                id: 1,
+                position: [3., 0., 4.],
+            },
+        );
+        let expected = campaign.state.clone();
+
+        let reopened = Campaign::open(&db, "test").unwrap();
+        assert_eq!(reopened.state, expected);
+        assert_eq!(reopened.state.tokens[&1].position, [3., 0., 4.]);
+        assert_eq!(reopened.last_id, 1);
+    }
+
+    #[test]
+    fn active_map_is_kept_until_the_map_goes() {
+        let db = temporary_db();
+        let mut campaign = Campaign::open(&db, "test").unwrap();
+        assert_eq!(campaign.activate_map(1), Err(InvalidEdit::UnknownMap(1)));
+        campaign.handle(
+            &game_master(1),
+            ClientMessage::MapEdit(MapEdit::CreateMap {
+                map: Map {
+                    name: String::from("Cave"),
+                },
+            }),
+        );
+        assert_eq!(
+            campaign.activate_map(1),
+            Ok(ServerMessage::MapActivated { seq: 2, map: 1 })
+        );
+        assert_eq!(
+            Campaign::open(&db, "test").unwrap().state.active_map,
+            Some(1)
+        );
+
+        campaign.handle(
+            &game_master(1),
+            ClientMessage::MapEdit(MapEdit::RemoveMap { id: 1 }),
+        );
+        assert_eq!(campaign.state.active_map, None);
+        assert_eq!(Campaign::open(&db, "test").unwrap().state.active_map, None);
+    }
+
+    #[test]
+    fn uploads_are_listed_and_fetched_by_hash() {
+        let db = temporary_db();
+        let dir = std::env::temp_dir().join(format!("vtt-uploads-{}", std::process::id()));
+        let mut campaign = Campaign::open(&db, "test").unwrap();
+        campaign.assets = Some(AssetStore::open(dir.clone()).unwrap());
+        let png = b"\x89PNG\r\n\x1a\nimage".to_vec();
+        let added = campaign.handle(
+            &game_master(1),
+            ClientMessage::UploadAsset {
+                name: String::from("hero.png"),
+                mime: String::from("image/png"),
+                data: AssetData(png.clone()),
+            },
+        );
+        let hash = match added.as_slice() {
+            [(Recipients::Everyone, ServerMessage::AssetAdded { seq: 1, info, .. })] => {
+                info.hash.clone()
+            }
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let reopened = Campaign::open(&db, "test").unwrap();
+        assert_eq!(reopened.state.assets, campaign.state.assets);
+
+        let fetch = |hash: &str| ClientMessage::FetchAsset {
+            hash: hash.to_string(),
+        };
+        assert_eq!(
+            campaign.handle(&player(2), fetch(&hash)),
+            vec![(
+                Recipients::Client(2),
+                ServerMessage::Asset {
+                    hash: hash.clone(),
+                    data: AssetData(png)
+                }
+            )]
+        );
+        // Another campaign can't read it, even with the hash.
+        let mut other = Campaign::open(&db, "other").unwrap();
+        other.assets = campaign.assets.clone();
+        assert_eq!(
+            other.handle(&player(2), fetch(&hash)),
+            vec![(
+                Recipients::Client(2),
+                ServerMessage::AssetRejected {
+                    reason: AssetError::Unknown(hash)
+                }
+            )]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn campaigns_are_stored_separately() {
+        let db = temporary_db();
+        let mut first = Campaign::open(&db, "first").unwrap();
+        first.handle(
+            &player(1),
+            ClientMessage::Chat {
+                text: String::from("hello"),
+                channel: ChatChannel::Everyone,
+                in_character: true,
+            },
+        );
+        let second = Campaign::open(&db, "second").unwrap();
+        assert!(second.state.chat.is_empty());
+    }
+
+    #[test]
+    fn players_can_only_move_their_own_tokens() {
+        let mut campaign = Campaign::default();
+        campaign.handle(
+            &player(1),
+            ClientMessage::PlaceToken {
+                token: Token {
+                    name: String::from("Hero"),
+                    position: [0., 0., 0.],
+                    owner: Some(2),
+                },
+            },
+        );
+        assert_eq!(campaign.state.tokens[&1].owner, Some(1));
+        let moved = campaign.handle(
+            &player(2),
+            ClientMessage::MoveToken {
+                id: 1,
+                position: [1., 0., 0.],
+            },
+        );
+        assert_eq!(
+            moved,
+            vec![(
+                Recipients::Client(2),
+                ServerMessage::PermissionDenied {
+                    reason: Forbidden::NotYourToken(1)
+                }
+            )]
+        );
+        assert_eq!(campaign.state.tokens[&1].position, [0., 0., 0.]);
+    }
+
+    #[test]
+    fn spectators_and_players_cant_edit_maps() {
+        let mut campaign = Campaign::default();
+        let edit = ClientMessage::MapEdit(MapEdit::CreateMap {
+            map: Map {
+                name: String::from("Cave"),
+            },
+        });
+        let spectator = Participant {
+            role: Role::Spectator,
+            ..player(3)
+        };
+        assert!(matches!(
+            campaign.handle(&player(1), edit.clone()).as_slice(),
+            [(
+                Recipients::Client(1),
+                ServerMessage::PermissionDenied {
+                    reason: Forbidden::GameMasterOnly
+                }
+            )]
+        ));
+        assert!(matches!(
+            campaign.handle(&spectator, edit).as_slice(),
+            [(
+                Recipients::Client(3),
+                ServerMessage::PermissionDenied {
+                    reason: Forbidden::ReadOnly
+                }
+            )]
+        ));
+        assert_eq!(campaign.state, CampaignState::default());
+    }
+
+    fn talk(
+        campaign: &mut Campaign,
+        from: &Participant,
+        channel: ChatChannel,
+        text: &str,
+    ) -> Vec<(Recipients, ServerMessage)> {
+        campaign.handle(
+            from,
+            ClientMessage::Chat {
+                text: String::from(text),
+                channel,
+                in_character: false,
+            },
+        )
+    }
+
+    fn chat(campaign: &mut Campaign, from: usize, text: &str) {
+        talk(campaign, &player(from), ChatChannel::Everyone, text);
+    }
+
+    #[test]
+    fn resync_sends_only_missed_changes() {
+        let mut campaign = Campaign::default();
+        for text in ["one", "two", "three"] {
+            chat(&mut campaign, 1, text);
+        }
+        let resent = campaign.handle(&player(2), ClientMessage::Resync { since: 1 });
+        let seqs: Vec<_> = resent
+            .iter()
+            .map(|(to, msg)| (to.clone(), msg.seq()))
+            .collect();
+        assert_eq!(
+            seqs,
+            vec![
+                (Recipients::Client(2), Some(2)),
+                (Recipients::Client(2), Some(3))
+            ]
+        );
+        assert!(campaign.resync(3).is_empty());
+    }
+
+    #[test]
+    fn resync_from_too_far_back_sends_a_snapshot() {
+        let mut campaign = Campaign::default();
+        for _ in 0..HISTORY + 2 {
+            chat(&mut campaign, 1, "spam");
+        }
+        let resent = campaign.resync(1);
+        assert!(matches!(
+            resent.as_slice(),
+            [ServerMessage::Snapshot { seq, .. }] if *seq == (HISTORY + 2) as Seq
+        ));
+        assert_eq!(campaign.resync(2).len(), HISTORY);
+        assert!(matches!(
+            campaign.resync(9999).as_slice(),
+            [ServerMessage::Snapshot { .. }]
+        ));
+    }
+
+    #[test]
+    fn snapshots_only_carry_recent_chat() {
+        let mut campaign = Campaign::default();
+        for i in 0..CHAT_BACKLOG + 5 {
+            chat(&mut campaign, 1, &i.to_string());
+        }
+        match campaign.snapshot() {
+            ServerMessage::Snapshot { state, .. } => {
+                assert_eq!(state.chat.len(), CHAT_BACKLOG);
+                assert_eq!(state.chat[0].text, "5");
+            }
+            other => panic!("expected a snapshot, got {:?}", other),
+        }
+        assert_eq!(campaign.state.chat.len(), CHAT_BACKLOG + 5);
+    }
+
+    #[test]
+    fn chat_is_stamped_with_the_senders_name() {
+        let mut campaign = Campaign::default();
+        chat(&mut campaign, 1, "first");
+        let said = talk(&mut campaign, &player(2), ChatChannel::Everyone, "second");
+        match said.as_slice() {
+            [(Recipients::Everyone, ServerMessage::Chat { seq: 2, entry })] => {
+                assert_eq!(entry.id, 1);
+                assert_eq!(entry.from, 2);
+                assert_eq!(entry.name, "Player 2");
+                assert!(entry.sent > 0);
+            }
+            other => panic!("expected a chat, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chat_has_to_be_sendable() {
+        let mut campaign = with_members();
+        let rejected = |said: Vec<(Recipients, ServerMessage)>| match said.as_slice() {
+            [(Recipients::Client(1), ServerMessage::ChatRejected { reason })] => reason.clone(),
+            other => panic!("expected a rejection, got {:?}", other),
+        };
+        let long = "a".repeat(MAX_CHAT_LENGTH + 1);
+        assert_eq!(
+            rejected(talk(
+                &mut campaign,
+                &player(1),
+                ChatChannel::Everyone,
+                " \n"
+            )),
+            InvalidChat::Empty
+        );
+        assert_eq!(
+            rejected(talk(
+                &mut campaign,
+                &player(1),
+                ChatChannel::Everyone,
+                &long
+            )),
+            InvalidChat::TooLong {
+                length: MAX_CHAT_LENGTH + 1,
+                limit: MAX_CHAT_LENGTH
+            }
+        );
+        assert_eq!(
+            rejected(talk(
+                &mut campaign,
+                &player(1),
+                ChatChannel::Whisper { to: 4 },
+                "anyone there?"
+            )),
+            InvalidChat::UnknownRecipient(4)
+        );
+        // Without knowing who's a member, no one can be whispered to.
+        assert_eq!(
+            rejected(talk(
+                &mut Campaign::default(),
+                &player(1),
+                ChatChannel::Whisper { to: 2 },
+                "psst"
+            )),
+            InvalidChat::UnknownRecipient(2)
+        );
+        assert!(campaign.state.chat.is_empty());
+        assert_eq!(campaign.seq, 0);
+    }
+
+    #[test]
+    fn private_chat_only_reaches_who_its_for() {
+        let mut campaign = with_members();
+        let whisper = talk(
+            &mut campaign,
+            &player(1),
+            ChatChannel::Whisper { to: 2 },
+            "psst",
+        );
+        assert!(matches!(
+            whisper.as_slice(),
+            [(Recipients::Users(users), ServerMessage::PrivateChat { .. })] if users == &[1, 2]
+        ));
+        let aside = talk(
+            &mut campaign,
+            &game_master(3),
+            ChatChannel::GameMasters,
+            "they'll never guess",
+        );
+        assert!(matches!(
+            aside.as_slice(),
+            [(Recipients::GameMasters, ServerMessage::PrivateChat { .. })]
+        ));
+        // Neither is a change everyone sees, so neither can be resynced.
+        assert!(campaign.resync(0).is_empty());
+        match campaign.snapshot() {
+            ServerMessage::Snapshot { state, .. } => assert!(state.chat.is_empty()),
+            other => panic!("expected a snapshot, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chat_history_pages_through_what_each_reader_can_see() {
+        let mut campaign = with_members();
+        for i in 0..CHAT_PAGE + 5 {
+            chat(&mut campaign, 1, &i.to_string());
+        }
+        talk(
+            &mut campaign,
+            &player(1),
+            ChatChannel::Whisper { to: 2 },
+            "psst",
+        );
+        talk(
+            &mut campaign,
+            &game_master(3),
+            ChatChannel::GameMasters,
+            "secret",
+        );
+        let page = |campaign: &mut Campaign, reader: &Participant, before| match campaign
+            .handle(reader, ClientMessage::ChatHistory { before })
+            .pop()
+        {
+            Some((_, ServerMessage::ChatHistory { entries, more })) => (
+                entries
+                    .into_iter()
+                    .map(|entry| entry.text)
+                    .collect::<Vec<_>>(),
+                more,
+            ),
+            other => panic!("expected chat history, got {:?}", other),
+        };
+
+        let (latest, more) = page(&mut campaign, &player(2), None);
+        assert!(more);
+        assert_eq!(latest.len(), CHAT_PAGE);
+        assert_eq!(latest.last().unwrap(), "psst");
+        assert_eq!(latest[0], "6");
+        let (older, more) = page(&mut campaign, &player(2), Some(6));
+        assert!(!more);
+        assert_eq!(older, ["0", "1", "2", "3", "4", "5"]);
+
+        let (latest, _) = page(&mut campaign, &player(4), None);
+        assert_eq!(latest.last().unwrap(), &(CHAT_PAGE + 4).to_string());
+        let (latest, _) = page(&mut campaign, &game_master(5), None);
+        assert_eq!(latest.last().unwrap(), "secret");
+    }
+}
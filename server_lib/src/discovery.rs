@@ -0,0 +1,50 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use protocol::{SessionAdvert, ADVERT_INTERVAL, DISCOVERY_PORT};
+use tokio::{net::UdpSocket, sync::watch, time::interval};
+use tracing::{debug, info, warn};
+
+use crate::Clients;
+
+/// Broadcasts a campaign to the local network, along with how many are
+/// playing it, until the server shuts down.
+pub(crate) async fn advertise(
+    campaign: String,
+    port: u16,
+    clients: Clients,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!(%error, "Couldn't advertise on the local network");
+            return;
+        }
+    };
+    if let Err(error) = socket.set_broadcast(true) {
+        warn!(%error, "Couldn't advertise on the local network");
+        return;
+    }
+    let target = SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+    let mut ticks = interval(ADVERT_INTERVAL);
+    info!(%campaign, "Advertising on the local network");
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let players = clients
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|client| client.campaign == campaign)
+                    .count();
+                let advert = SessionAdvert::new(campaign.clone(), port, players);
+                // Machines without a network to broadcast to are still fine
+                // to play on, so this is only worth a debug message.
+                if let Err(error) = socket.send_to(&advert.encode(), target).await {
+                    debug!(%error, "Couldn't send advert");
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crossbeam_channel::Receiver;
+use protocol::ServerMessage;
+use tracing::{error, info};
+
+use crate::{deliver, ClientEvent, Clients, Participant, Recipients, Rooms};
+
+/// The authoritative game - the campaigns being played and the clients they
+/// tell about it. The standalone server and a game master hosting from the
+/// client both run this, so a game plays the same either way.
+#[derive(Debug)]
+pub struct Game {
+    pub rooms: Rooms,
+    pub clients: Clients,
+}
+
+impl Game {
+    pub fn new(rooms: Rooms, clients: Clients) -> Self {
+        Game { rooms, clients }
+    }
+
+    /// Handles events until every connection has ended and the server has
+    /// shut down, then saves the campaigns.
+    pub fn run(mut self, events: Receiver<(Participant, ClientEvent)>) {
+        while let Ok((from, event)) = events.recv() {
+            self.handle(from, event);
+        }
+        match self.rooms.flush() {
+            Ok(()) => info!("Saved campaigns"),
+            Err(error) => error!(%error, "Couldn't save campaigns"),
+        }
+    }
+
+    /// Plays out one event from a connection.
+    pub fn handle(&mut self, from: Participant, event: ClientEvent) {
+        let campaign = match self.rooms.get(&from.campaign) {
+            Ok(campaign) => campaign,
+            Err(error) => {
+                error!(campaign = %from.campaign, %error, "Couldn't load campaign");
+                return;
+            }
+        };
+        let outgoing = match event {
+            ClientEvent::Connected => {
+                vec![(Recipients::Client(from.client), campaign.snapshot())]
+            }
+            ClientEvent::Message(msg) => campaign.handle(&from, msg),
+            ClientEvent::Disconnected => return,
+        };
+        self.send(&from.campaign, &outgoing);
+    }
+
+    /// Delivers messages to a campaign's clients, sending a snapshot instead
+    /// to any that have fallen too far behind.
+    pub fn send(&mut self, campaign: &str, outgoing: &[(Recipients, ServerMessage)]) {
+        let clients = self.clients.lock().unwrap();
+        let mut lagging = Vec::new();
+        for (recipients, msg) in outgoing.iter() {
+            lagging.extend(deliver(&clients, campaign, recipients, msg));
+        }
+        if lagging.is_empty() {
+            return;
+        }
+        let snapshot = match self.rooms.get(campaign) {
+            Ok(room) => room.snapshot(),
+            Err(error) => {
+                error!(%campaign, %error, "Couldn't load campaign");
+                return;
+            }
+        };
+        for id in lagging {
+            deliver(&clients, campaign, &Recipients::Client(id), &snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, Outbox, OutboxPolicy, UserStore};
+    use protocol::{ChatChannel, ClientId, ClientMessage, Role};
+    use std::sync::Arc;
+
+    fn participant(client: ClientId, campaign: &str) -> Participant {
+        Participant {
+            client,
+            user: client as u64,
+            name: format!("Player {}", client),
+            role: Role::Player,
+            campaign: campaign.to_string(),
+        }
+    }
+
+    fn client(from: &Participant) -> Client {
+        Client {
+            id: from.client,
+            user: from.user,
+            name: from.name.clone(),
+            role: from.role,
+            campaign: from.campaign.clone(),
+            outbox: Outbox::new(OutboxPolicy::default()),
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn events_are_played_in_their_own_campaign() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let frodo = participant(1, "shire");
+        let sauron = participant(2, "mordor");
+        let clients = Clients::default();
+        for from in [&frodo, &sauron] {
+            clients.lock().unwrap().insert(from.client, client(from));
+        }
+        let mut game = Game::new(Rooms::new(db), Arc::clone(&clients));
+
+        game.handle(frodo.clone(), ClientEvent::Connected);
+        game.handle(
+            frodo,
+            ClientEvent::Message(ClientMessage::Chat {
+                text: String::from("second breakfast"),
+                channel: ChatChannel::Everyone,
+                in_character: false,
+            }),
+        );
+        let clients = clients.lock().unwrap();
+        assert_eq!(clients[&1].outbox.stats().depth, 2);
+        assert_eq!(clients[&2].outbox.stats().depth, 0);
+        assert_eq!(game.rooms.get("shire").unwrap().state.chat.len(), 1);
+    }
+
+    #[test]
+    fn private_chat_only_reaches_its_readers() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let frodo = participant(1, "shire");
+        let sam = participant(2, "shire");
+        let gandalf = Participant {
+            role: Role::GameMaster,
+            ..participant(3, "shire")
+        };
+        let clients = Clients::default();
+        for from in [&frodo, &sam, &gandalf] {
+            clients.lock().unwrap().insert(from.client, client(from));
+        }
+        let mut rooms = Rooms::new(db);
+        let users = UserStore::temporary().unwrap();
+        users.set_role("shire", sam.user, Role::Player).unwrap();
+        rooms.users = Some(users);
+        let mut game = Game::new(rooms, Arc::clone(&clients));
+        let say = |channel| {
+            ClientEvent::Message(ClientMessage::Chat {
+                text: String::from("psst"),
+                channel,
+                in_character: false,
+            })
+        };
+
+        game.handle(frodo, say(ChatChannel::Whisper { to: 2 }));
+        game.handle(gandalf, say(ChatChannel::GameMasters));
+        let clients = clients.lock().unwrap();
+        let depths: Vec<_> = (1..=3)
+            .map(|id| clients[&id].outbox.stats().depth)
+            .collect();
+        assert_eq!(depths, [1, 1, 1]);
+        assert_eq!(game.rooms.get("shire").unwrap().state.chat.len(), 2);
+    }
+}
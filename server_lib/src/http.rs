@@ -0,0 +1,225 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use hyper::{
+    header::{
+        HeaderValue, CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+    },
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    server::{serve_websocket, Context, Shutdown},
+    Clients, Metrics,
+};
+
+/// The directories the server hands out files from. Either can be left out,
+/// in which case requests for it are answered with a 404.
+#[derive(Debug, Clone, Default)]
+pub struct Files {
+    /// The web client - `index.html` and the `pkg` directory `wasm-pack`
+    /// builds.
+    pub web: Option<PathBuf>,
+    /// Public files, served under `/assets/` to anyone who asks. Never the
+    /// `AssetStore` - uploads only go to players of their own campaign.
+    pub assets: Option<PathBuf>,
+}
+
+/// Paths that upgrade to the game's websocket. `/server` is what the
+/// released client connects to, and `/` keeps older addresses working.
+const SOCKET_PATHS: [&str; 2] = ["/server", "/"];
+
+/// Speaks HTTP on a connection, handing it over to the game if it asks to
+/// upgrade to a websocket.
+pub(crate) async fn serve<S>(stream: S, context: Context, shutdown: Shutdown)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut signal = shutdown.signal.clone();
+    // Clients get as long to send a request as they do to say hello once
+    // it's a websocket, so a half-sent request can't hold on to a connection.
+    let header_timeout = context.lobby.heartbeat.timeout;
+    let service = service_fn(move |request| {
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        async move { Ok::<_, Infallible>(route(request, context, shutdown).await) }
+    });
+    let connection = Http::new()
+        .http1_only(true)
+        .http1_header_read_timeout(header_timeout)
+        .serve_connection(stream, service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = &mut connection => result,
+        _ = signal.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(error) = result {
+        debug!(%error, "HTTP error");
+    }
+}
+
+async fn route(request: Request<Body>, context: Context, shutdown: Shutdown) -> Response<Body> {
+    let path = request.uri().path().to_string();
+    if request.headers().contains_key(SEC_WEBSOCKET_KEY) {
+        return if SOCKET_PATHS.contains(&path.as_str()) {
+            upgrade(request, context, shutdown)
+        } else {
+            status(StatusCode::NOT_FOUND)
+        };
+    }
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let files = &context.lobby.files;
+    match path.strip_prefix("/assets/") {
+        Some(asset) => send_file(files.assets.as_deref(), asset).await,
+        None if path == "/" => send_file(files.web.as_deref(), "index.html").await,
+        None => send_file(files.web.as_deref(), &path).await,
+    }
+}
+
+/// Accepts a websocket upgrade and runs the game's side of the connection
+/// once hyper lets go of it.
+fn upgrade(request: Request<Body>, context: Context, shutdown: Shutdown) -> Response<Body> {
+    let accept = match request.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+    tokio::spawn(
+        async move {
+            let upgraded = match hyper::upgrade::on(request).await {
+                Ok(upgraded) => upgraded,
+                Err(error) => {
+                    warn!(%error, "Couldn't upgrade to a websocket");
+                    return;
+                }
+            };
+            serve_websocket(upgraded, context, shutdown).await;
+        }
+        .in_current_span(),
+    );
+    let mut response = status(StatusCode::SWITCHING_PROTOCOLS);
+    let headers = response.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    }
+    response
+}
+
+async fn send_file(root: Option<&Path>, path: &str) -> Response<Body> {
+    let file = match root.and_then(|root| resolve(root, path)) {
+        Some(file) => file,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    match tokio::fs::read(&file).await {
+        Ok(contents) => {
+            let mime = mime_guess::from_path(&file).first_or_octet_stream();
+            let mut response = Response::new(Body::from(contents));
+            if let Ok(mime) = HeaderValue::from_str(mime.as_ref()) {
+                response.headers_mut().insert(CONTENT_TYPE, mime);
+            }
+            response
+        }
+        Err(_) => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Finds a requested file under `root`, refusing anything that would climb
+/// out of it.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => file.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+/// Answers Prometheus scrapes of `/metrics` on an address of its own, so
+/// metrics needn't be exposed wherever players can connect. Runs until the
+/// listener fails.
+pub async fn serve_metrics(
+    address: SocketAddr,
+    metrics: Arc<Metrics>,
+    clients: Clients,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!(%address, "Serving metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let clients = clients.clone();
+        let service = service_fn(move |request: Request<Body>| {
+            let response = if request.uri().path() != "/metrics" {
+                status(StatusCode::NOT_FOUND)
+            } else if request.method() != Method::GET {
+                status(StatusCode::METHOD_NOT_ALLOWED)
+            } else {
+                let text = metrics.render(&clients.lock().unwrap());
+                let mut response = Response::new(Body::from(text));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                response
+            };
+            async move { Ok::<_, Infallible>(response) }
+        });
+        tokio::spawn(async move {
+            if let Err(error) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                debug!(%error, "HTTP error serving metrics");
+            }
+        });
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_inside_the_root() {
+        let root = Path::new("/srv/web");
+        assert_eq!(
+            resolve(root, "/pkg/client_bevy.js"),
+            Some(PathBuf::from("/srv/web/pkg/client_bevy.js"))
+        );
+        assert_eq!(resolve(root, "/../vtt_db/conf"), None);
+        assert_eq!(resolve(root, "pkg/../../secret"), None);
+    }
+}
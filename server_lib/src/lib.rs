@@ -1,4 +1,58 @@
 #[cfg(feature = "native")]
+mod assets;
+#[cfg(feature = "native")]
+mod campaign;
+#[cfg(feature = "native")]
+mod discovery;
+#[cfg(feature = "native")]
+mod game;
+#[cfg(feature = "native")]
+mod http;
+#[cfg(feature = "native")]
+mod local;
+#[cfg(feature = "native")]
+mod metrics;
+#[cfg(feature = "native")]
+mod outbox;
+#[cfg(feature = "native")]
+mod rooms;
+#[cfg(feature = "native")]
 mod server;
 #[cfg(feature = "native")]
+mod session;
+#[cfg(feature = "native")]
+mod storage;
+#[cfg(feature = "native")]
+mod throttle;
+#[cfg(feature = "native")]
+mod tls;
+#[cfg(feature = "native")]
+mod users;
+#[cfg(feature = "native")]
+pub use assets::*;
+#[cfg(feature = "native")]
+pub use campaign::*;
+#[cfg(feature = "native")]
+pub use game::*;
+#[cfg(feature = "native")]
+pub use http::{serve_metrics, Files};
+#[cfg(feature = "native")]
+pub use local::*;
+#[cfg(feature = "native")]
+pub use metrics::*;
+#[cfg(feature = "native")]
+pub use outbox::*;
+#[cfg(feature = "native")]
+pub use rooms::*;
+#[cfg(feature = "native")]
 pub use server::*;
+#[cfg(feature = "native")]
+pub use session::*;
+#[cfg(feature = "native")]
+pub use storage::*;
+#[cfg(feature = "native")]
+pub use throttle::*;
+#[cfg(feature = "native")]
+pub use tls::*;
+#[cfg(feature = "native")]
+pub use users::*;
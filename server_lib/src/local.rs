@@ -0,0 +1,29 @@
+use std::io;
+
+use tokio::{
+    io::{duplex, DuplexStream},
+    sync::mpsc,
+};
+
+/// Bytes buffered each way in an in-process connection.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Opens connections to a server in the same process through in-memory pipes
+/// instead of sockets, for tests and bots. Get one from
+/// `Server::local_connector`.
+#[derive(Debug, Clone)]
+pub struct LocalConnector {
+    pub(crate) sender: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl LocalConnector {
+    /// Returns the client's end of a new connection. The server treats the
+    /// other end like any websocket that has just been upgraded.
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = duplex(PIPE_SIZE);
+        self.sender.send(server).map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "the server has stopped")
+        })?;
+        Ok(client)
+    }
+}
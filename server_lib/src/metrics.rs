@@ -0,0 +1,285 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use protocol::ClientId;
+
+use crate::{queue_stats, Client, OutboxStats};
+
+/// Upper bounds, in seconds, of the buckets storage writes are counted in.
+const WRITE_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.];
+
+/// Counters for everything the server has done since it started, shared by
+/// the connections and the game and read in Prometheus' text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    received: Mutex<BTreeMap<&'static str, u64>>,
+    sent: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// Messages a connection couldn't hand on to the game.
+    undelivered: AtomicU64,
+    /// Messages refused for breaking a connection's limits.
+    refused: AtomicU64,
+    /// Outbox counters of clients that have since disconnected, so totals
+    /// don't go backwards when someone leaves.
+    finished: Mutex<OutboxStats>,
+    writes: Mutex<Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; WRITE_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Counts a message from a client, `bytes` long once encoded.
+    pub fn received(&self, kind: &'static str, bytes: usize) {
+        *self.received.lock().unwrap().entry(kind).or_default() += 1;
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message sent to a client, `bytes` long once encoded.
+    pub fn sent(&self, kind: &'static str, bytes: usize) {
+        *self.sent.lock().unwrap().entry(kind).or_default() += 1;
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn undelivered(&self) {
+        self.undelivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Keeps a disconnected client's outbox counters in the totals.
+    pub fn finished(&self, stats: OutboxStats) {
+        let mut finished = self.finished.lock().unwrap();
+        finished.coalesced += stats.coalesced;
+        finished.dropped += stats.dropped;
+        finished.snapshots += stats.snapshots;
+    }
+
+    /// Records how long a write to the database took.
+    pub fn wrote(&self, took: Duration) {
+        let seconds = took.as_secs_f64();
+        let mut writes = self.writes.lock().unwrap();
+        if let Some(bucket) = WRITE_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            writes.buckets[bucket] += 1;
+        }
+        writes.count += 1;
+        writes.sum += seconds;
+    }
+
+    /// Everything counted so far, along with the connected clients, in
+    /// Prometheus' text exposition format.
+    pub fn render(&self, clients: &HashMap<ClientId, Client>) -> String {
+        let mut out = String::new();
+        let mut rooms = BTreeMap::<&str, usize>::new();
+        for client in clients.values() {
+            *rooms.entry(&client.campaign).or_default() += 1;
+        }
+        header(
+            &mut out,
+            "vtt_connected_clients",
+            "gauge",
+            "Clients connected to each campaign.",
+        );
+        for (campaign, count) in rooms {
+            let _ = writeln!(
+                out,
+                "vtt_connected_clients{{campaign=\"{}\"}} {}",
+                escape(campaign),
+                count
+            );
+        }
+
+        for (name, help, counts) in [
+            (
+                "vtt_messages_received_total",
+                "Messages received from clients.",
+                &self.received,
+            ),
+            (
+                "vtt_messages_sent_total",
+                "Messages sent to clients.",
+                &self.sent,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (kind, count) in counts.lock().unwrap().iter() {
+                let _ = writeln!(out, "{}{{type=\"{}\"}} {}", name, kind, count);
+            }
+        }
+
+        let queues = queue_stats(clients);
+        let finished = *self.finished.lock().unwrap();
+        for (name, kind, help, value) in [
+            (
+                "vtt_received_bytes_total",
+                "counter",
+                "Bytes of messages received from clients.",
+                self.bytes_received.load(Ordering::Relaxed),
+            ),
+            (
+                "vtt_sent_bytes_total",
+                "counter",
+                "Bytes of messages sent to clients.",
+                self.bytes_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "vtt_undelivered_messages_total",
+                "counter",
+                "Messages from clients the game couldn't be handed.",
+                self.undelivered.load(Ordering::Relaxed),
+            ),
+            (
+                "vtt_refused_messages_total",
+                "counter",
+                "Messages from clients refused for being too large or too frequent.",
+                self.refused.load(Ordering::Relaxed),
+            ),
+            (
+                "vtt_dropped_messages_total",
+                "counter",
+                "Changes not queued for clients that were waiting for a snapshot.",
+                finished.dropped + queues.dropped,
+            ),
+            (
+                "vtt_coalesced_messages_total",
+                "counter",
+                "Queued token moves replaced by a later move.",
+                finished.coalesced + queues.coalesced,
+            ),
+            (
+                "vtt_lag_snapshots_total",
+                "counter",
+                "Snapshots sent to clients that fell behind.",
+                finished.snapshots + queues.snapshots,
+            ),
+            (
+                "vtt_queued_messages",
+                "gauge",
+                "Messages waiting to be sent across every client.",
+                queues.queued as u64,
+            ),
+            (
+                "vtt_lagging_clients",
+                "gauge",
+                "Clients waiting for a snapshot.",
+                queues.lagging as u64,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let writes = self.writes.lock().unwrap();
+        let name = "vtt_storage_write_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "How long writes to the database took.",
+        );
+        let mut total = 0;
+        for (bound, count) in WRITE_BUCKETS.iter().zip(writes.buckets) {
+            total += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, total);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, writes.count);
+        let _ = writeln!(out, "{}_sum {}", name, writes.sum);
+        let _ = writeln!(out, "{}_count {}", name, writes.count);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Campaign names are chosen by game masters, so they're escaped before going
+/// in a label.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outbox, OutboxPolicy};
+    use protocol::Role;
+
+    fn client(id: ClientId, campaign: &str) -> (ClientId, Client) {
+        let client = Client {
+            id,
+            user: id as u64,
+            name: format!("Player {}", id),
+            role: Role::Player,
+            campaign: campaign.to_string(),
+            outbox: Outbox::new(OutboxPolicy::default()),
+            latency: None,
+        };
+        (id, client)
+    }
+
+    #[test]
+    fn metrics_are_rendered_for_prometheus() {
+        let metrics = Metrics::default();
+        metrics.received("Chat", 20);
+        metrics.received("Chat", 30);
+        metrics.sent("Snapshot", 100);
+        metrics.refused();
+        metrics.wrote(Duration::from_millis(2));
+        metrics.wrote(Duration::from_secs(2));
+        metrics.finished(OutboxStats {
+            dropped: 3,
+            ..Default::default()
+        });
+        let clients: HashMap<_, _> = [
+            client(1, "shire"),
+            client(2, "shire"),
+            client(3, "say \"hi\""),
+        ]
+        .into_iter()
+        .collect();
+
+        let text = metrics.render(&clients);
+        for line in [
+            "vtt_connected_clients{campaign=\"shire\"} 2",
+            "vtt_connected_clients{campaign=\"say \\\"hi\\\"\"} 1",
+            "vtt_messages_received_total{type=\"Chat\"} 2",
+            "vtt_messages_sent_total{type=\"Snapshot\"} 1",
+            "vtt_received_bytes_total 50",
+            "vtt_refused_messages_total 1",
+            "vtt_dropped_messages_total 3",
+            "vtt_storage_write_seconds_bucket{le=\"0.001\"} 0",
+            "vtt_storage_write_seconds_bucket{le=\"0.005\"} 1",
+            "vtt_storage_write_seconds_bucket{le=\"1\"} 1",
+            "vtt_storage_write_seconds_bucket{le=\"+Inf\"} 2",
+            "vtt_storage_write_seconds_count 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        assert!(text.contains("# TYPE vtt_storage_write_seconds histogram"));
+    }
+}
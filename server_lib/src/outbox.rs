@@ -0,0 +1,466 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use protocol::{Presence, ServerMessage};
+use tokio::sync::Notify;
+
+/// How far a client may fall behind before the server stops queueing
+/// changes for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxPolicy {
+    /// Queued messages at which a client counts as lagging. Its queued
+    /// changes are dropped and it's sent a snapshot instead. Latency updates
+    /// don't count, since only the latest for each player is kept.
+    pub lag_threshold: usize,
+    /// How many snapshots in a row a client can need before it's
+    /// disconnected. The count starts again whenever it empties its queue.
+    pub max_snapshots: u32,
+}
+
+impl Default for OutboxPolicy {
+    fn default() -> Self {
+        OutboxPolicy {
+            lag_threshold: 100,
+            max_snapshots: 3,
+        }
+    }
+}
+
+/// What happened to a message handed to an outbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// Queued, and an older move of the same token or latency of the same
+    /// player was dropped for it.
+    Coalesced,
+    /// The client is waiting for a snapshot, which will include this change.
+    Dropped,
+    /// The client just fell too far behind - the game should send it a
+    /// snapshot.
+    Lagging,
+    /// The client is being disconnected.
+    Closed,
+}
+
+/// What a connection should do next with its client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    Send(ServerMessage),
+    Close(String),
+}
+
+/// Counters for a single client's queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxStats {
+    pub depth: usize,
+    pub peak: usize,
+    pub coalesced: u64,
+    pub dropped: u64,
+    pub snapshots: u64,
+    pub lagging: bool,
+}
+
+/// Queue depth across every connected client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub clients: usize,
+    pub queued: usize,
+    pub deepest: usize,
+    pub lagging: usize,
+    pub coalesced: u64,
+    pub dropped: u64,
+    pub snapshots: u64,
+}
+
+impl FromIterator<OutboxStats> for QueueStats {
+    fn from_iter<I: IntoIterator<Item = OutboxStats>>(iter: I) -> Self {
+        iter.into_iter()
+            .fold(QueueStats::default(), |total, stats| QueueStats {
+                clients: total.clients + 1,
+                queued: total.queued + stats.depth,
+                deepest: total.deepest.max(stats.depth),
+                lagging: total.lagging + stats.lagging as usize,
+                coalesced: total.coalesced + stats.coalesced,
+                dropped: total.dropped + stats.dropped,
+                snapshots: total.snapshots + stats.snapshots,
+            })
+    }
+}
+
+/// The messages waiting to go out to one client. The game pushes to it
+/// without blocking, and the client's connection sends them as fast as the
+/// socket allows.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    state: Arc<Mutex<OutboxState>>,
+    notify: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    policy: OutboxPolicy,
+    queue: VecDeque<ServerMessage>,
+    /// Waiting for a snapshot, so changes aren't worth queueing.
+    stale: bool,
+    /// Snapshots needed since the queue was last empty.
+    resets: u32,
+    closing: Option<String>,
+    stats: OutboxStats,
+}
+
+impl Outbox {
+    pub fn new(policy: OutboxPolicy) -> Self {
+        Outbox {
+            state: Arc::new(Mutex::new(OutboxState {
+                policy,
+                ..Default::default()
+            })),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn push(&self, msg: ServerMessage) -> Pushed {
+        let pushed = self.state.lock().unwrap().push(msg);
+        if pushed != Pushed::Dropped {
+            self.notify.notify_one();
+        }
+        pushed
+    }
+
+    /// Waits for the next message to send, or for the client to be closed.
+    pub async fn next(&self) -> Outgoing {
+        loop {
+            if let Some(outgoing) = self.state.lock().unwrap().pop() {
+                return outgoing;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Disconnects the client once everything already queued has been sent.
+    /// Nothing more is queued in the meantime.
+    pub fn close(&self, reason: String) {
+        self.state.lock().unwrap().closing.get_or_insert(reason);
+        self.notify.notify_one();
+    }
+
+    /// Everything still queued, for sending before the connection closes.
+    pub fn drain(&self) -> Vec<ServerMessage> {
+        let mut state = self.state.lock().unwrap();
+        state.stats.depth = 0;
+        state.queue.drain(..).collect()
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+impl OutboxState {
+    fn push(&mut self, msg: ServerMessage) -> Pushed {
+        if self.closing.is_some() {
+            return Pushed::Closed;
+        }
+        let pushed = match msg {
+            ServerMessage::Snapshot { .. } => {
+                // The snapshot covers every change queued before it.
+                self.queue.retain(|queued| !is_change(queued));
+                self.stale = false;
+                self.stats.lagging = false;
+                self.queue.push_back(msg);
+                Pushed::Queued
+            }
+            msg if self.stale && is_change(&msg) => {
+                self.stats.dropped += 1;
+                Pushed::Dropped
+            }
+            ServerMessage::Presence(Presence::Latency { user, .. }) => {
+                let queued = self.queue.iter_mut().find(|queued| {
+                    matches!(queued, ServerMessage::Presence(Presence::Latency { user: other, .. }) if *other == user)
+                });
+                match queued {
+                    Some(queued) => {
+                        *queued = msg;
+                        self.stats.coalesced += 1;
+                        Pushed::Coalesced
+                    }
+                    None => {
+                        self.queue.push_back(msg);
+                        Pushed::Queued
+                    }
+                }
+            }
+            // Already waiting on a snapshot, so there's nothing more to do
+            // until it's queued.
+            msg if !self.stale && self.backlog() >= self.policy.lag_threshold => {
+                let pushed = self.fall_behind();
+                if pushed == Pushed::Lagging && !is_change(&msg) {
+                    self.queue.push_back(msg);
+                } else {
+                    self.stats.dropped += 1;
+                }
+                pushed
+            }
+            msg => {
+                let coalesced = self.coalesce(&msg);
+                self.queue.push_back(msg);
+                if coalesced {
+                    self.stats.coalesced += 1;
+                    Pushed::Coalesced
+                } else {
+                    Pushed::Queued
+                }
+            }
+        };
+        self.stats.depth = self.queue.len();
+        self.stats.peak = self.stats.peak.max(self.queue.len());
+        pushed
+    }
+
+    /// How many messages count towards the lag threshold.
+    fn backlog(&self) -> usize {
+        self.queue
+            .iter()
+            .filter(|queued| !matches!(queued, ServerMessage::Presence(Presence::Latency { .. })))
+            .count()
+    }
+
+    /// Drops the queued changes and waits for a snapshot, unless the client
+    /// has needed too many already.
+    fn fall_behind(&mut self) -> Pushed {
+        self.resets += 1;
+        if self.resets > self.policy.max_snapshots {
+            self.queue.clear();
+            self.closing = Some(format!(
+                "Too far behind - needed {} snapshots in a row",
+                self.resets
+            ));
+            return Pushed::Closed;
+        }
+        let before = self.queue.len();
+        self.queue.retain(|queued| !is_change(queued));
+        self.stats.dropped += (before - self.queue.len()) as u64;
+        self.stats.snapshots += 1;
+        self.stats.lagging = true;
+        self.stale = true;
+        Pushed::Lagging
+    }
+
+    /// Replaces a queued move of the same token with a placeholder, so the
+    /// client only sees where it ended up. Returns whether one was found.
+    fn coalesce(&mut self, msg: &ServerMessage) -> bool {
+        let moved = match msg {
+            ServerMessage::TokenMoved { id, .. } => *id,
+            _ => return false,
+        };
+        let index = self.queue.iter().position(
+            |queued| matches!(queued, ServerMessage::TokenMoved { id, .. } if *id == moved),
+        );
+        let index = match index {
+            Some(index) => index,
+            None => return false,
+        };
+        let seq = self.queue[index].seq().unwrap_or_default();
+        self.queue[index] = ServerMessage::Superseded { from: seq, to: seq };
+        // Runs of placeholders collapse into one.
+        if index + 1 < self.queue.len() && merge(&mut self.queue, index) {
+            self.queue.remove(index + 1);
+        }
+        if index > 0 && merge(&mut self.queue, index - 1) {
+            self.queue.remove(index);
+        }
+        true
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        let msg = match self.queue.pop_front() {
+            Some(msg) => msg,
+            None => return self.closing.clone().map(Outgoing::Close),
+        };
+        if self.queue.is_empty() && !self.stale {
+            self.resets = 0;
+        }
+        self.stats.depth = self.queue.len();
+        Some(Outgoing::Send(msg))
+    }
+}
+
+/// Whether a message is made redundant by a snapshot.
+fn is_change(msg: &ServerMessage) -> bool {
+    msg.seq().is_some() || matches!(msg, ServerMessage::Snapshot { .. })
+}
+
+/// Folds the placeholder after `index` into the one at `index` if they're
+/// next to each other in the history.
+fn merge(queue: &mut VecDeque<ServerMessage>, index: usize) -> bool {
+    let next = match queue.get(index + 1) {
+        Some(ServerMessage::Superseded { from, to }) => (*from, *to),
+        _ => return false,
+    };
+    match &mut queue[index] {
+        ServerMessage::Superseded { to, .. } if *to + 1 == next.0 => {
+            *to = next.1;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{CampaignState, ChatEntry, Presence, Seq, SyncAction, SyncTracker};
+
+    fn moved(seq: Seq, id: u64) -> ServerMessage {
+        ServerMessage::TokenMoved {
+            seq,
+            from: 1,
+            id,
+            position: [seq as f32, 0., 0.],
+        }
+    }
+
+    fn chat(seq: Seq) -> ServerMessage {
+        ServerMessage::Chat {
+            seq,
+            entry: ChatEntry {
+                from: 1,
+                text: String::from("hi"),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn snapshot(seq: Seq) -> ServerMessage {
+        ServerMessage::Snapshot {
+            seq,
+            state: CampaignState::default(),
+        }
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<ServerMessage> {
+        outbox.state.lock().unwrap().queue.iter().cloned().collect()
+    }
+
+    #[test]
+    fn repeated_moves_keep_only_the_latest() {
+        let outbox = Outbox::new(OutboxPolicy::default());
+        assert_eq!(outbox.push(moved(1, 7)), Pushed::Queued);
+        assert_eq!(outbox.push(chat(2)), Pushed::Queued);
+        assert_eq!(outbox.push(moved(3, 7)), Pushed::Coalesced);
+        assert_eq!(outbox.push(moved(4, 7)), Pushed::Coalesced);
+        assert_eq!(outbox.push(moved(5, 8)), Pushed::Queued);
+        assert_eq!(
+            queued(&outbox),
+            vec![
+                ServerMessage::Superseded { from: 1, to: 1 },
+                chat(2),
+                ServerMessage::Superseded { from: 3, to: 3 },
+                moved(4, 7),
+                moved(5, 8),
+            ]
+        );
+        assert_eq!(outbox.push(moved(6, 7)), Pushed::Coalesced);
+        assert_eq!(
+            queued(&outbox)[2],
+            ServerMessage::Superseded { from: 3, to: 4 }
+        );
+        assert_eq!(outbox.stats().coalesced, 3);
+
+        // A client following along never sees a gap.
+        let mut sync = SyncTracker::default();
+        sync.observe(&snapshot(0));
+        for msg in queued(&outbox) {
+            assert_eq!(sync.observe(&msg), SyncAction::Apply);
+        }
+        assert_eq!(sync.last(), Some(6));
+    }
+
+    #[test]
+    fn lagging_clients_wait_for_a_snapshot() {
+        let outbox = Outbox::new(OutboxPolicy {
+            lag_threshold: 2,
+            max_snapshots: 3,
+        });
+        let left = ServerMessage::Presence(Presence::Left { user: 2 });
+        outbox.push(left.clone());
+        outbox.push(chat(1));
+        assert_eq!(outbox.push(chat(2)), Pushed::Lagging);
+        assert_eq!(outbox.push(chat(3)), Pushed::Dropped);
+        assert_eq!(queued(&outbox), vec![left.clone()]);
+        assert!(outbox.stats().lagging);
+
+        assert_eq!(outbox.push(snapshot(3)), Pushed::Queued);
+        assert_eq!(outbox.push(chat(4)), Pushed::Lagging);
+        assert_eq!(queued(&outbox), vec![left.clone()]);
+        assert_eq!(outbox.stats().snapshots, 2);
+
+        // Messages a snapshot doesn't cover are still queued while waiting
+        // for it, without asking for another.
+        let joined = ServerMessage::Presence(Presence::Joined {
+            user: 3,
+            name: String::from("Pippin"),
+        });
+        assert_eq!(outbox.push(joined.clone()), Pushed::Queued);
+        assert_eq!(outbox.push(joined.clone()), Pushed::Queued);
+        assert_eq!(queued(&outbox), vec![left, joined.clone(), joined]);
+        assert_eq!(outbox.stats().snapshots, 2);
+    }
+
+    #[tokio::test]
+    async fn clients_that_never_catch_up_are_closed() {
+        let outbox = Outbox::new(OutboxPolicy {
+            lag_threshold: 1,
+            max_snapshots: 1,
+        });
+        outbox.push(chat(1));
+        assert_eq!(outbox.push(chat(2)), Pushed::Lagging);
+        outbox.push(snapshot(2));
+        assert_eq!(outbox.push(chat(3)), Pushed::Closed);
+        assert_eq!(outbox.push(chat(4)), Pushed::Closed);
+        assert!(matches!(outbox.next().await, Outgoing::Close(_)));
+    }
+
+    #[tokio::test]
+    async fn closing_sends_whats_queued_first() {
+        let outbox = Outbox::new(OutboxPolicy::default());
+        outbox.push(chat(1));
+        outbox.close(String::from("Kicked"));
+        assert_eq!(outbox.push(chat(2)), Pushed::Closed);
+        assert_eq!(outbox.next().await, Outgoing::Send(chat(1)));
+        assert_eq!(outbox.next().await, Outgoing::Close(String::from("Kicked")));
+    }
+
+    #[test]
+    fn latency_updates_dont_make_clients_lag() {
+        let outbox = Outbox::new(OutboxPolicy {
+            lag_threshold: 2,
+            max_snapshots: 3,
+        });
+        let latency = |user, millis| ServerMessage::Presence(Presence::Latency { user, millis });
+        for millis in 0..10 {
+            outbox.push(latency(1, millis));
+            outbox.push(latency(2, millis));
+        }
+        assert_eq!(outbox.push(chat(1)), Pushed::Queued);
+        assert_eq!(queued(&outbox), vec![latency(1, 9), latency(2, 9), chat(1)]);
+        assert_eq!(outbox.push(chat(2)), Pushed::Queued);
+        assert_eq!(outbox.push(chat(3)), Pushed::Lagging);
+    }
+
+    #[tokio::test]
+    async fn catching_up_forgives_earlier_lag() {
+        let outbox = Outbox::new(OutboxPolicy {
+            lag_threshold: 1,
+            max_snapshots: 1,
+        });
+        outbox.push(chat(1));
+        assert_eq!(outbox.push(chat(2)), Pushed::Lagging);
+        outbox.push(snapshot(2));
+        assert_eq!(outbox.next().await, Outgoing::Send(snapshot(2)));
+        outbox.push(chat(3));
+        assert_eq!(outbox.push(chat(4)), Pushed::Lagging);
+    }
+}
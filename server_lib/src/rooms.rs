@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+
+use crate::{AssetStore, Campaign, Metrics, StorageError, UserStore};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CampaignInfo {
+    /// Seconds since the unix epoch.
+    pub created: u64,
+    /// Archived campaigns keep their state but can't be joined.
+    pub archived: bool,
+}
+
+/// The campaigns a server knows about, keyed by name. Cheap to clone, so the
+/// connection handlers can check a campaign exists while the game plays it.
+#[derive(Debug, Clone)]
+pub struct CampaignRegistry {
+    campaigns: Tree,
+}
+
+impl CampaignRegistry {
+    pub fn open(db: &Db) -> Result<Self, StorageError> {
+        Ok(CampaignRegistry {
+            campaigns: db.open_tree("campaigns")?,
+        })
+    }
+
+    /// A registry that's thrown away when it's dropped, for hosts that don't
+    /// keep anything between runs.
+    pub fn temporary() -> Result<Self, StorageError> {
+        CampaignRegistry::open(&sled::Config::new().temporary(true).open()?)
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, CampaignInfo)>, StorageError> {
+        self.campaigns
+            .iter()
+            .map(|record| {
+                let (name, info) = record?;
+                Ok((
+                    String::from_utf8_lossy(&name).into_owned(),
+                    serde_json::from_slice(&info)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<CampaignInfo>, StorageError> {
+        match self.campaigns.get(name)? {
+            Some(info) => Ok(Some(serde_json::from_slice(&info)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether clients can join the campaign right now.
+    pub fn is_open(&self, name: &str) -> bool {
+        matches!(
+            self.get(name),
+            Ok(Some(CampaignInfo {
+                archived: false,
+                ..
+            }))
+        )
+    }
+
+    /// Registers a new campaign, failing if the name is already in use.
+    pub fn create(&self, name: &str) -> Result<(), StorageError> {
+        let name = name.trim();
+        if name.is_empty() || name.contains('/') || self.campaigns.contains_key(name)? {
+            return Err(StorageError::BadName(name.to_string()));
+        }
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        self.put(
+            name,
+            &CampaignInfo {
+                created,
+                archived: false,
+            },
+        )
+    }
+
+    /// Registers the campaign if it isn't already, leaving it alone if it is.
+    pub fn ensure(&self, name: &str) -> Result<(), StorageError> {
+        match self.get(name)? {
+            Some(_) => Ok(()),
+            None => self.create(name),
+        }
+    }
+
+    /// Stops new clients joining the campaign. Its state is kept in case it's
+    /// needed again.
+    pub fn archive(&self, name: &str) -> Result<(), StorageError> {
+        match self.get(name)? {
+            Some(info) => self.put(
+                name,
+                &CampaignInfo {
+                    archived: true,
+                    ..info
+                },
+            ),
+            None => Err(StorageError::BadName(name.to_string())),
+        }
+    }
+
+    fn put(&self, name: &str, info: &CampaignInfo) -> Result<(), StorageError> {
+        self.campaigns.insert(name, serde_json::to_vec(info)?)?;
+        Ok(())
+    }
+}
+
+/// The campaigns being played, each opened the first time someone joins it.
+#[derive(Debug)]
+pub struct Rooms {
+    db: Db,
+    campaigns: HashMap<String, Campaign>,
+    /// Where every campaign's uploads go. Uploads are refused without one.
+    pub assets: Option<AssetStore>,
+    pub metrics: Option<Arc<Metrics>>,
+    /// Who's a member of each campaign. Whispers are refused without it.
+    pub users: Option<UserStore>,
+}
+
+impl Rooms {
+    pub fn new(db: Db) -> Self {
+        Rooms {
+            db,
+            campaigns: HashMap::new(),
+            assets: None,
+            metrics: None,
+            users: None,
+        }
+    }
+
+    /// Rooms that are thrown away when they're dropped, for hosts that don't
+    /// keep anything between runs.
+    pub fn temporary() -> Result<Self, StorageError> {
+        Ok(Rooms::new(sled::Config::new().temporary(true).open()?))
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<&mut Campaign, StorageError> {
+        if !self.campaigns.contains_key(name) {
+            let mut campaign = Campaign::open(&self.db, name)?;
+            campaign.assets = self.assets.clone();
+            campaign.metrics = self.metrics.clone();
+            campaign.users = self.users.clone();
+            self.campaigns.insert(name.to_string(), campaign);
+        }
+        Ok(self.campaigns.get_mut(name).unwrap())
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        for campaign in self.campaigns.values() {
+            campaign.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Participant;
+    use protocol::{ChatChannel, ClientMessage, Role};
+
+    #[test]
+    fn campaigns_can_be_created_and_archived() {
+        let registry = CampaignRegistry::temporary().unwrap();
+        registry.create("shire").unwrap();
+        registry.create("mordor").unwrap();
+        assert!(registry.create("shire").is_err());
+        assert!(registry.create("").is_err());
+        assert!(registry.is_open("shire"));
+        assert!(!registry.is_open("rivendell"));
+
+        registry.archive("mordor").unwrap();
+        assert!(!registry.is_open("mordor"));
+        let names: Vec<_> = registry
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|(name, info)| (name, info.archived))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (String::from("mordor"), true),
+                (String::from("shire"), false)
+            ]
+        );
+        assert!(registry.archive("rivendell").is_err());
+    }
+
+    #[test]
+    fn rooms_keep_their_own_state() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut rooms = Rooms::new(db);
+        let from = Participant {
+            client: 1,
+            user: 1,
+            name: String::from("Frodo"),
+            role: Role::Player,
+            campaign: String::from("shire"),
+        };
+        rooms.get("shire").unwrap().handle(
+            &from,
+            ClientMessage::Chat {
+                text: String::from("second breakfast"),
+                channel: ChatChannel::Everyone,
+                in_character: false,
+            },
+        );
+        assert_eq!(rooms.get("shire").unwrap().state.chat.len(), 1);
+        assert!(rooms.get("mordor").unwrap().state.chat.is_empty());
+    }
+}
@@ -1,26 +1,84 @@
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use futures_util::{SinkExt, StreamExt};
-use serde::{de::DeserializeOwned, Serialize};
+use protocol::{
+    check_hello, ClientId, ClientMessage, Codec, Credentials, Frame, Presence, Rejection,
+    ResumeToken, Role, ServerMessage, UserId, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT,
+    PROTOCOL_VERSION,
+};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{AddrParseError, SocketAddr},
-    sync::{atomic::AtomicUsize, Arc, Mutex},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::{interval, timeout, timeout_at},
+};
+
+use crate::{
+    discovery, http, AuthMode, CampaignRegistry, Files, LocalConnector, MessageLimits, Metrics,
+    Outbox, OutboxPolicy, Outgoing, Participant, Pushed, QueueStats, Recipients, Sessions,
+    Throttle, UserStore, Verdict,
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{Error, Message},
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, Role as WebSocketRole},
+        Error, Message,
+    },
+    WebSocketStream,
 };
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 
-pub type Clients<T> = Arc<Mutex<HashMap<usize, Client<T>>>>;
+pub type Clients = Arc<Mutex<HashMap<ClientId, Client>>>;
+type SharedSessions = Arc<Mutex<Sessions>>;
 
 #[derive(Debug, Clone)]
-pub struct Client<T>
-where
-    T: Clone + Send + Serialize + DeserializeOwned,
-{
-    pub id: usize,
-    pub sender: tokio::sync::mpsc::Sender<(usize, T)>,
+pub struct Client {
+    pub id: ClientId,
+    pub user: UserId,
+    pub name: String,
+    pub role: Role,
+    pub campaign: String,
+    pub outbox: Outbox,
+    /// The last round trip measured by a heartbeat.
+    pub latency: Option<Duration>,
+}
+
+/// What the game hears about a connected client.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Connected,
+    Message(ClientMessage),
+    Disconnected,
+}
+
+/// How often connections are pinged, and how long one can go without
+/// answering before it's dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: HEARTBEAT_INTERVAL,
+            timeout: HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+/// How many clients can be connected at once, in total and to each campaign.
+/// `None` means there's no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_clients: Option<usize>,
+    pub max_players: Option<usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -29,16 +87,36 @@ pub enum ServerControl {
 }
 
 #[derive(Debug)]
-pub struct Server<T>
-where
-    T: Clone + Send + Serialize + DeserializeOwned,
-{
-    pub clients: Clients<T>,
+pub struct Server {
+    pub clients: Clients,
+    sessions: SharedSessions,
+    users: UserStore,
+    campaigns: CampaignRegistry,
+    /// The campaign clients join if they don't ask for one.
+    default_campaign: String,
     pub address: SocketAddr,
-    pub reciever: Receiver<(usize, T)>,
-    sender: Sender<(usize, T)>,
+    /// How far behind a client can fall before it's sent a snapshot or
+    /// disconnected.
+    pub outbox_policy: OutboxPolicy,
+    pub heartbeat: Heartbeat,
+    pub limits: Limits,
+    pub message_limits: MessageLimits,
+    pub auth: AuthMode,
+    /// Serves `wss://` and `https://` instead of `ws://` and `http://` when
+    /// set.
+    pub tls: Option<Arc<ServerConfig>>,
+    /// The web client and campaign assets, served over HTTP on the same port.
+    pub files: Files,
+    /// Broadcasts the default campaign on the local network so players can
+    /// find it without typing an address.
+    pub advertise: bool,
+    pub metrics: Arc<Metrics>,
+    pub reciever: Receiver<(Participant, ClientEvent)>,
+    sender: Sender<(Participant, ClientEvent)>,
     pub control_sender: tokio::sync::mpsc::Sender<ServerControl>,
     control_reciever: tokio::sync::mpsc::Receiver<ServerControl>,
+    local_sender: mpsc::UnboundedSender<DuplexStream>,
+    local_receiver: mpsc::UnboundedReceiver<DuplexStream>,
 }
 
 #[derive(Debug)]
@@ -48,182 +126,1332 @@ pub enum ServerError {
     BindingError,
 }
 
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
-
-impl<T: Clone + Send + Serialize + DeserializeOwned + 'static> Server<T> {
-    pub fn new(address: String) -> Result<Self, ServerError> {
+impl Server {
+    pub fn new(
+        address: String,
+        default_campaign: String,
+        users: UserStore,
+        campaigns: CampaignRegistry,
+    ) -> Result<Self, ServerError> {
         let address: Result<SocketAddr, AddrParseError> = address.parse();
         match address {
             Ok(address) => {
-                let clients: Clients<T> = Arc::new(Mutex::new(HashMap::new()));
-                let (client_to_game_sender, client_to_game_receiver) = unbounded::<(usize, T)>();
+                let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+                let (client_to_game_sender, client_to_game_receiver) =
+                    unbounded::<(Participant, ClientEvent)>();
                 let (control_sender, control_reciever) =
                     tokio::sync::mpsc::channel::<ServerControl>(1);
-                Ok(Server::<T> {
+                let (local_sender, local_receiver) = mpsc::unbounded_channel();
+                Ok(Server {
                     clients,
+                    sessions: Arc::new(Mutex::new(Sessions::default())),
+                    users,
+                    campaigns,
+                    default_campaign,
                     address,
+                    outbox_policy: OutboxPolicy::default(),
+                    heartbeat: Heartbeat::default(),
+                    limits: Limits::default(),
+                    message_limits: MessageLimits::default(),
+                    auth: AuthMode::default(),
+                    tls: None,
+                    files: Files::default(),
+                    advertise: false,
+                    metrics: Arc::default(),
                     sender: client_to_game_sender,
                     reciever: client_to_game_receiver,
                     control_reciever,
                     control_sender,
+                    local_sender,
+                    local_receiver,
                 })
             }
             Err(_) => Err(ServerError::InvalidAddress),
         }
     }
 
-    pub async fn start(mut self) -> Result<(), ServerError> {
-        let listener = TcpListener::bind(&self.address).await;
-        let control_reciever = &mut self.control_reciever;
-        match listener {
-            Ok(listener) => {
-                println!("Listening on socket {}", &self.address);
-                loop {
-                    tokio::select! {
-                        stream = listener.accept() => {
-                            match stream {
-                                Ok((stream, _)) => {
-                                    let peer = stream.peer_addr();
-                                    if let Ok(peer) = peer {
-                                        tokio::spawn(accept_connection(
-                                            peer,
-                                            stream,
-                                            self.clients.clone(),
-                                            self.sender.clone(),
-                                        ));
-                                    } else {
-                                        eprintln!("Error with server - couldn't connect to peer");
-                                        break;
-                                    }
-                                },
-                                Err(_) => {
-                                    eprintln!("Server closed unexpectedly");
-                                    break;
-                                }
-                            }
+    /// Opens connections to this server from the same process, without
+    /// going through the network.
+    pub fn local_connector(&self) -> LocalConnector {
+        LocalConnector {
+            sender: self.local_sender.clone(),
+        }
+    }
+
+    /// Accepts connections until the server is told to close, then asks every
+    /// client to leave and only returns once all their connections have ended.
+    pub async fn start(self) -> Result<(), ServerError> {
+        match TcpListener::bind(&self.address).await {
+            Ok(listener) => self.run(Some(listener)).await,
+            Err(_) => {
+                error!(address = %self.address, "Couldn't listen");
+                Err(ServerError::BindingError)
+            }
+        }
+    }
+
+    /// Like `start`, but only accepts connections made through
+    /// `local_connector`, without listening on the network.
+    pub async fn start_local(self) -> Result<(), ServerError> {
+        self.run(None).await
+    }
+
+    async fn run(mut self, listener: Option<TcpListener>) -> Result<(), ServerError> {
+        let context = self.context();
+        let tls = self.tls.clone().map(TlsAcceptor::from);
+        let (shutdown_sender, signal) = watch::channel(false);
+        // Each connection holds a clone of this sender, so once ours is
+        // dropped the receiver only closes after the last connection ends.
+        let (running_sender, mut running) = mpsc::channel::<()>(1);
+        let shutdown = || Shutdown {
+            signal: signal.clone(),
+            _running: running_sender.clone(),
+        };
+        if let Some(listener) = &listener {
+            info!(address = %self.address, "Listening");
+            if self.advertise {
+                if let Ok(address) = listener.local_addr() {
+                    tokio::spawn(discovery::advertise(
+                        self.default_campaign.clone(),
+                        address.port(),
+                        self.clients.clone(),
+                        signal.clone(),
+                    ));
+                }
+            }
+        }
+        loop {
+            tokio::select! {
+                stream = accept(listener.as_ref()) => {
+                    match stream {
+                        Ok((stream, peer)) => {
+                            let span = info_span!(
+                                "connection",
+                                %peer,
+                                client = field::Empty,
+                                user = field::Empty,
+                            );
+                            tokio::spawn(
+                                accept_connection(stream, context.clone(), tls.clone(), shutdown())
+                                    .instrument(span),
+                            );
                         },
-                        _ = control_reciever.recv() => {
-                            println!("Closing server on socket {}", &self.address);
+                        Err(_) => {
+                            error!("Server closed unexpectedly");
                             break;
-                        },
+                        }
                     }
-                }
-                println!("Socket should be closed");
-            }
-            Err(_) => {
-                eprintln!("Couldn't listen on socket {}", &self.address);
-                return Err(ServerError::BindingError);
+                },
+                Some(stream) = self.local_receiver.recv() => {
+                    let span = info_span!(
+                        "connection",
+                        peer = "local",
+                        client = field::Empty,
+                        user = field::Empty,
+                    );
+                    tokio::spawn(
+                        serve_websocket(stream, context.clone(), shutdown()).instrument(span),
+                    );
+                },
+                _ = self.control_reciever.recv() => {
+                    info!(address = %self.address, "Closing server");
+                    break;
+                },
             }
         }
-        println!("Should really be closed now");
+        let _ = shutdown_sender.send(true);
+        drop(running_sender);
+        let _ = running.recv().await;
+        info!(address = %self.address, "All connections closed");
         Ok(())
     }
+
+    fn context(&self) -> Context {
+        Context {
+            clients: self.clients.clone(),
+            sessions: self.sessions.clone(),
+            lobby: Lobby {
+                users: self.users.clone(),
+                campaigns: self.campaigns.clone(),
+                default_campaign: self.default_campaign.clone(),
+                outbox_policy: self.outbox_policy,
+                heartbeat: self.heartbeat,
+                limits: self.limits,
+                message_limits: self.message_limits,
+                auth: self.auth,
+                files: self.files.clone(),
+            },
+            game: self.sender.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Waits for the next connection from the network, or forever if the server
+/// isn't listening on it.
+async fn accept(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
 }
 
-async fn accept_connection<T>(
-    peer: SocketAddr,
+async fn accept_connection(
     stream: TcpStream,
-    clients: Clients<T>,
-    client_to_game_sender: Sender<(usize, T)>,
-) where
-    T: Clone + Send + Serialize + DeserializeOwned,
-{
-    if let Err(e) = handle_connection(peer, stream, clients, client_to_game_sender).await {
-        match e {
-            Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8 => (),
-            err => println!("Error processing connection: {}", err),
+    context: Context,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) {
+    debug!("Received connection request");
+    match tls {
+        Some(acceptor) => {
+            let heartbeat = context.lobby.heartbeat;
+            match timeout(heartbeat.timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => http::serve(stream, context, shutdown).await,
+                Ok(Err(error)) => warn!(%error, "TLS handshake failed"),
+                Err(_) => warn!("TLS handshake timed out"),
+            }
         }
+        None => http::serve(stream, context, shutdown).await,
     }
 }
 
-async fn handle_connection<T>(
-    peer: SocketAddr,
-    stream: TcpStream,
-    clients: Clients<T>,
-    client_to_game_sender: Sender<(usize, T)>,
-) -> Result<(), Error>
-where
-    T: Clone + Send + Serialize + DeserializeOwned,
-{
-    println!("Recieved connection request from {}", peer);
-    let ws_stream = accept_async(stream).await;
-    if let Err(error) = ws_stream {
-        eprintln!("Failed to connect {}: {}", peer, error);
-        return Err(error);
-    }
-    println!("Unwrapping {}", peer);
-    let ws_stream = ws_stream.unwrap();
-    println!("Accepted Connection {}", peer);
+/// Runs a connection that has just become a websocket until it closes.
+pub(crate) async fn serve_websocket<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    context: Context,
+    mut shutdown: Shutdown,
+) {
+    let config = context.lobby.message_limits.websocket_config();
+    let ws_stream =
+        WebSocketStream::from_raw_socket(stream, WebSocketRole::Server, Some(config)).await;
+    match handle_connection(ws_stream, context, &mut shutdown.signal).await {
+        Ok(()) | Err(Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8) => (),
+        Err(error) => warn!(%error, "Error processing connection"),
+    }
+}
+
+/// Runs a client's websocket from the hello until it disconnects.
+pub(crate) async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws_stream: WebSocketStream<S>,
+    context: Context,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let Context {
+        clients,
+        sessions,
+        lobby,
+        game: client_to_game_sender,
+        metrics,
+    } = context;
+    let Accepted {
+        codec,
+        resume,
+        user,
+        name,
+        role,
+        campaign,
+        password,
+    } = tokio::select! {
+        accepted = handshake(&mut ws_stream, &lobby, &clients) => {
+            match accepted? {
+                Some(accepted) => accepted,
+                None => return Ok(()),
+            }
+        },
+        _ = shutdown.changed() => {
+            return ws_stream.close(Some(shutting_down())).await;
+        },
+    };
+
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let (game_to_client_sender, mut game_to_client_receiver) =
-        tokio::sync::mpsc::channel::<(usize, T)>(100);
-    let id = NEXT_USER_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let outbox = Outbox::new(lobby.outbox_policy);
+    let (id, resume, resumed) =
+        sessions
+            .lock()
+            .unwrap()
+            .start(resume.as_deref(), user, Instant::now());
+    let span = Span::current();
+    span.record("client", &id);
+    span.record("user", &name.as_str());
+    info!(?role, %campaign, ?codec, resumed, "Accepted connection");
+    let participant = Participant {
+        client: id,
+        user,
+        name: name.clone(),
+        role,
+        campaign: campaign.clone(),
+    };
+    let welcome = Codec::Json.encode(&ServerMessage::Welcome {
+        version: PROTOCOL_VERSION,
+        client: id,
+        codec,
+        user,
+        name: name.clone(),
+        role,
+        campaign: campaign.clone(),
+        resume,
+        resumed,
+        password,
+    });
+    if let Ok(welcome) = welcome {
+        metrics.sent("Welcome", welcome.len());
+        ws_sender.send(to_message(welcome)).await?;
+    }
     {
         let lock = clients.lock();
-        lock.unwrap().insert(
+        let mut clients = lock.unwrap();
+        broadcast(
+            &clients,
+            &campaign,
+            ServerMessage::Presence(Presence::Joined {
+                user,
+                name: name.clone(),
+            }),
+        );
+        for msg in roster(&clients, &campaign) {
+            outbox.push(msg);
+        }
+        clients.insert(
             id,
             Client {
                 id,
-                sender: game_to_client_sender,
+                user,
+                name,
+                role,
+                campaign: campaign.clone(),
+                outbox: outbox.clone(),
+                latency: None,
             },
         );
     }
+    if client_to_game_sender
+        .send((participant.clone(), ClientEvent::Connected))
+        .is_err()
+    {
+        error!("Failed to send message to game");
+    }
 
+    let mut heartbeat = interval(lobby.heartbeat.interval);
+    let mut throttle = Throttle::new(lobby.message_limits, Instant::now());
+    let mut last_heard = Instant::now();
+    // The number of the last ping sent, and when it went out.
+    let mut ping: (u64, Instant) = (0, Instant::now());
     loop {
         tokio::select! {
             msg = ws_receiver.next() => {
-                println!("Maybe got something from {}", id);
                 match msg {
                     Some (msg) => {
-                        if msg.is_err() {
+                        // The socket can't be read past an error, so there's
+                        // nothing more to hear from this client.
+                        let msg = match msg {
+                            Ok(msg) => msg,
+                            Err(Error::Capacity(error)) => {
+                                warn!(%error, "Disconnecting - message too big");
+                                metrics.refused();
+                                let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                                    code: CloseCode::Size,
+                                    reason: "Message too big".into(),
+                                }))).await;
+                                break;
+                            }
+                            Err(error) => {
+                                debug!(%error, "Couldn't read from connection");
+                                break;
+                            }
+                        };
+                        last_heard = Instant::now();
+                        if let Message::Pong(payload) = &msg {
+                            if payload[..] == ping.0.to_be_bytes() {
+                                record_latency(&clients, id, ping.1.elapsed());
+                            }
                             continue;
                         }
-                        let msg = msg.unwrap();
-                        match msg {
-                            Message::Text(msg) => {
-                                let str = msg.clone();
-                                println!("MSG FROM {}: {}", id, &str);
-                                if let Ok(value) = serde_json::from_str(&str) {
-                                    if client_to_game_sender.send((id, value)).is_err() {
-                                        eprintln!("Failed to send message to game");
+                        match to_frame(msg) {
+                            Some(frame) => {
+                                // Decoded first, since uploads can be bigger
+                                // than anything else.
+                                let decoded = frame.decode::<ClientMessage>();
+                                let upload = matches!(decoded, Ok(ClientMessage::UploadAsset { .. }));
+                                match throttle.check(frame.len(), upload, Instant::now()) {
+                                    Verdict::Accept => {}
+                                    Verdict::Refuse(reason) => {
+                                        warn!(%reason, "Refused a message");
+                                        metrics.refused();
+                                        let reply = codec.encode(&ServerMessage::Error {
+                                            message: format!("Message refused: {} - keep it up and you'll be disconnected", reason),
+                                        });
+                                        if let Ok(reply) = reply {
+                                            if ws_sender.send(to_message(reply)).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                    Verdict::Disconnect(reason) => {
+                                        warn!(%reason, "Disconnecting - ignored warnings");
+                                        metrics.refused();
+                                        let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                                            code: CloseCode::Policy,
+                                            reason: "Sent too much after being warned".into(),
+                                        }))).await;
+                                        break;
+                                    }
+                                }
+                                match decoded {
+                                    Ok(value) => {
+                                        debug!(kind = value.kind(), "Received message");
+                                        trace!(?value, "Message body");
+                                        metrics.received(value.kind(), frame.len());
+                                        if client_to_game_sender.send((participant.clone(), ClientEvent::Message(value))).is_err() {
+                                            metrics.undelivered();
+                                            error!("Failed to send message to game");
+                                        }
+                                    }
+                                    Err(error) => {
+                                        warn!(%error, "Couldn't decode message");
+                                        let reply = codec.encode(&ServerMessage::Error {
+                                            message: format!("Couldn't decode message: {}", error),
+                                        });
+                                        if let Ok(reply) = reply {
+                                            if ws_sender.send(to_message(reply)).await.is_err() {
+                                                break;
+                                            }
+                                        }
                                     }
                                 }
                             },
-                            _ => {
-                                println!("Got unidentified message from {}", id);
+                            None => {
+                                debug!("Ignored a frame that isn't a message");
                             }
                         }
                     },
                     None => {
-                        println!("Stream ended for {}", id);
+                        debug!("Stream ended");
                         break;
                     },
                 }
             },
-            game_msg = game_to_client_receiver.recv() => {
-                if game_msg.is_none() {
-                    continue;
-                }
-                let game_msg = game_msg.unwrap();
-                let game_msg = serde_json::to_string(&game_msg);
+            outgoing = outbox.next() => {
+                let game_msg = match outgoing {
+                    Outgoing::Send(game_msg) => game_msg,
+                    Outgoing::Close(reason) => {
+                        warn!(%reason, "Disconnecting");
+                        let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: reason.into(),
+                        }))).await;
+                        break;
+                    }
+                };
+                let kind = game_msg.kind();
+                debug!(kind, "Sending message");
+                trace!(?game_msg, "Message body");
+                let game_msg = codec.encode(&game_msg);
                 if game_msg.is_err() {
                     continue;
                 }
                 let game_msg = game_msg.unwrap();
-                println!("Sending message {} to {}", game_msg, id);
-                let result = ws_sender.send(Message::Text(game_msg)).await;
+                metrics.sent(kind, game_msg.len());
+                let result = ws_sender.send(to_message(game_msg)).await;
                 if result.is_err() {
                     break;
                 }
+            },
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > lobby.heartbeat.timeout {
+                    warn!("Dropping connection - missed heartbeats");
+                    let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Missed heartbeats".into(),
+                    }))).await;
+                    break;
+                }
+                ping = (ping.0 + 1, Instant::now());
+                if ws_sender.send(Message::Ping(ping.0.to_be_bytes().to_vec())).await.is_err() {
+                    break;
+                }
+            },
+            _ = shutdown.changed() => {
+                // Send whatever the game already queued before saying goodbye.
+                for game_msg in outbox.drain() {
+                    if let Ok(game_msg) = codec.encode(&game_msg) {
+                        let _ = ws_sender.feed(to_message(game_msg)).await;
+                    }
+                }
+                let _ = ws_sender.send(Message::Close(Some(shutting_down()))).await;
+                debug!("Closed connection for shutdown");
+                break;
             }
         }
     }
 
     {
         let lock = clients.lock();
-        lock.unwrap().remove(&id);
+        let mut clients = lock.unwrap();
+        clients.remove(&id);
+        metrics.finished(outbox.stats());
+        broadcast(
+            &clients,
+            &campaign,
+            ServerMessage::Presence(Presence::Left { user }),
+        );
+    }
+    sessions.lock().unwrap().end(id, Instant::now());
+    if client_to_game_sender
+        .send((participant, ClientEvent::Disconnected))
+        .is_err()
+    {
+        error!("Failed to send message to game");
+    }
+    info!("Disconnected");
+
+    Ok(())
+}
+
+/// Lets a connection know when the server is closing, and lets the server
+/// know when the connection has ended by being dropped.
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    pub(crate) signal: watch::Receiver<bool>,
+    _running: mpsc::Sender<()>,
+}
+
+/// Everything a connection needs from the server.
+#[derive(Clone)]
+pub(crate) struct Context {
+    clients: Clients,
+    sessions: SharedSessions,
+    pub(crate) lobby: Lobby,
+    game: Sender<(Participant, ClientEvent)>,
+    metrics: Arc<Metrics>,
+}
+
+/// What a connection needs to decide who a client is and where they're going,
+/// and how to treat them once they're in.
+#[derive(Clone)]
+pub(crate) struct Lobby {
+    users: UserStore,
+    campaigns: CampaignRegistry,
+    default_campaign: String,
+    outbox_policy: OutboxPolicy,
+    pub(crate) heartbeat: Heartbeat,
+    limits: Limits,
+    pub(crate) message_limits: MessageLimits,
+    auth: AuthMode,
+    pub(crate) files: Files,
+}
+
+/// What the server learns about a client from its hello.
+struct Accepted {
+    /// The codec the connection should use from here on.
+    codec: Codec,
+    resume: Option<ResumeToken>,
+    user: UserId,
+    name: String,
+    role: Role,
+    campaign: String,
+    /// The password made up for a client that just signed up.
+    password: Option<String>,
+}
+
+/// Waits for the client's hello and checks its credentials, replying with a
+/// rejection and closing the socket if it can't be accepted. Returns `None`
+/// if the client was turned away. Only the wait is timed - checking
+/// credentials can be slow when lots of people log in at once.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_stream: &mut WebSocketStream<S>,
+    lobby: &Lobby,
+    clients: &Clients,
+) -> Result<Option<Accepted>, Error> {
+    let deadline = tokio::time::Instant::now() + lobby.heartbeat.timeout;
+    loop {
+        let msg = match timeout_at(deadline, ws_stream.next()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(None),
+            Err(_) => {
+                warn!("Never said hello");
+                ws_stream
+                    .close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "Took too long to say hello".into(),
+                    }))
+                    .await?;
+                return Ok(None);
+            }
+        };
+        let frame = match msg? {
+            Message::Close(_) => return Ok(None),
+            msg => match to_frame(msg) {
+                Some(frame) => frame,
+                None => continue,
+            },
+        };
+        let result = match frame.decode::<ClientMessage>() {
+            Ok(msg) => match check_hello(&msg) {
+                Ok(()) => admit(msg, lobby, clients).await,
+                Err(rejection) => Err(rejection),
+            },
+            Err(_) => Err(Rejection::HandshakeExpected),
+        };
+        return match result {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(rejection) => {
+                info!(%rejection, "Rejecting connection");
+                if let Ok(reply) = Codec::Json.encode(&ServerMessage::Rejected(rejection.clone())) {
+                    ws_stream.send(to_message(reply)).await?;
+                }
+                ws_stream
+                    .close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: rejection.to_string().into(),
+                    }))
+                    .await?;
+                Ok(None)
+            }
+        };
+    }
+}
+
+/// Decides whether a client's hello lets it in.
+async fn admit(
+    hello: ClientMessage,
+    lobby: &Lobby,
+    clients: &Clients,
+) -> Result<Accepted, Rejection> {
+    let (codecs, resume, login, campaign) = match hello {
+        ClientMessage::Hello {
+            codecs,
+            resume,
+            login: Some(login),
+            campaign,
+            ..
+        } => (codecs, resume, login, campaign),
+        _ => return Err(Rejection::LoginRequired),
+    };
+    let campaign = campaign.unwrap_or_else(|| lobby.default_campaign.clone());
+    if !lobby.campaigns.is_open(&campaign) {
+        return Err(Rejection::UnknownCampaign(campaign));
     }
-    println!("Client Disconnected {}", peer);
+    if lobby.auth == AuthMode::Password && matches!(login, Credentials::Invite { .. }) {
+        return Err(Rejection::InvitesDisabled);
+    }
+    check_capacity(clients, lobby.limits, &campaign)?;
+    // Checking a password is slow on purpose, so it's kept off the threads
+    // running everyone else's connections.
+    let users = lobby.users.clone();
+    let checking = campaign.clone();
+    let found = tokio::task::spawn_blocking(move || {
+        users.authenticate(&login, &checking).and_then(|found| {
+            found
+                .map(|login| {
+                    let role = users.role(&checking, login.id)?;
+                    let banned = users.is_banned(login.id)?;
+                    Ok((login, role, banned))
+                })
+                .transpose()
+        })
+    })
+    .await;
+    match found {
+        Ok(Ok(Some((_, _, true)))) => Err(Rejection::Banned),
+        // Only members can join a campaign, and anyone else isn't told it's
+        // there.
+        Ok(Ok(Some((_, None, false)))) => Err(Rejection::UnknownCampaign(campaign)),
+        Ok(Ok(Some((login, Some(role), false)))) => Ok(Accepted {
+            codec: Codec::negotiate(&codecs),
+            resume,
+            user: login.id,
+            name: login.user.name,
+            role,
+            campaign,
+            password: login.password,
+        }),
+        Ok(Ok(None)) => Err(Rejection::BadCredentials),
+        Ok(Err(error)) => {
+            error!(%error, "Couldn't check credentials");
+            Err(Rejection::BadCredentials)
+        }
+        Err(error) => {
+            error!(%error, "Credential check failed");
+            Err(Rejection::BadCredentials)
+        }
+    }
+}
 
+/// Turns a client away if the server or its campaign is full.
+fn check_capacity(clients: &Clients, limits: Limits, campaign: &str) -> Result<(), Rejection> {
+    let clients = clients.lock().unwrap();
+    if limits.max_clients.is_some_and(|max| clients.len() >= max) {
+        return Err(Rejection::ServerFull);
+    }
+    let players = clients
+        .values()
+        .filter(|client| client.campaign == campaign)
+        .count();
+    if limits.max_players.is_some_and(|max| players >= max) {
+        return Err(Rejection::CampaignFull(campaign.to_string()));
+    }
     Ok(())
 }
+
+/// Who's already playing a campaign, and their latest round trips, for a
+/// client that has just joined it.
+fn roster(clients: &HashMap<ClientId, Client>, campaign: &str) -> Vec<ServerMessage> {
+    let mut joined = Vec::new();
+    let mut latencies = Vec::new();
+    let mut seen = HashSet::new();
+    for client in clients
+        .values()
+        .filter(|client| client.campaign == campaign)
+    {
+        if seen.insert(client.user) {
+            joined.push(ServerMessage::Presence(Presence::Joined {
+                user: client.user,
+                name: client.name.clone(),
+            }));
+        }
+        if let Some(latency) = client.latency {
+            latencies.push(latency_update(client.user, latency));
+        }
+    }
+    joined.extend(latencies);
+    joined
+}
+
+/// Remembers a client's latest round trip and lets the rest of its campaign
+/// know about it.
+fn record_latency(clients: &Clients, id: ClientId, latency: Duration) {
+    let mut clients = clients.lock().unwrap();
+    let (user, campaign) = match clients.get_mut(&id) {
+        Some(client) => {
+            client.latency = Some(latency);
+            (client.user, client.campaign.clone())
+        }
+        None => return,
+    };
+    broadcast(&clients, &campaign, latency_update(user, latency));
+}
+
+fn latency_update(user: UserId, latency: Duration) -> ServerMessage {
+    let millis = latency.as_millis().min(u32::MAX as u128) as u32;
+    ServerMessage::Presence(Presence::Latency { user, millis })
+}
+
+fn shutting_down() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: "Server is shutting down".into(),
+    }
+}
+
+fn to_message(frame: Frame) -> Message {
+    match frame {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
+    }
+}
+
+fn to_frame(msg: Message) -> Option<Frame> {
+    match msg {
+        Message::Text(text) => Some(Frame::Text(text)),
+        Message::Binary(bytes) => Some(Frame::Binary(bytes)),
+        _ => None,
+    }
+}
+
+fn broadcast(clients: &HashMap<ClientId, Client>, campaign: &str, msg: ServerMessage) {
+    deliver(clients, campaign, &Recipients::Everyone, &msg);
+}
+
+/// Queues a message for each of the recipients in a campaign, returning the
+/// ids of any clients that fell too far behind and need a snapshot.
+pub fn deliver(
+    clients: &HashMap<ClientId, Client>,
+    campaign: &str,
+    recipients: &Recipients,
+    msg: &ServerMessage,
+) -> Vec<ClientId> {
+    let mut lagging = Vec::new();
+    let mut send = |client: &Client| match client.outbox.push(msg.clone()) {
+        Pushed::Lagging => {
+            warn!(
+                client = client.id,
+                "Client fell behind - sending a snapshot"
+            );
+            lagging.push(client.id);
+        }
+        Pushed::Closed => warn!(client = client.id, "Client is too far behind to keep up"),
+        _ => {}
+    };
+    match recipients {
+        Recipients::Everyone => clients
+            .values()
+            .filter(|client| client.campaign == campaign)
+            .for_each(&mut send),
+        Recipients::Client(id) => clients.get(id).into_iter().for_each(&mut send),
+        Recipients::Users(users) => clients
+            .values()
+            .filter(|client| client.campaign == campaign && users.contains(&client.user))
+            .for_each(&mut send),
+        Recipients::GameMasters => clients
+            .values()
+            .filter(|client| client.campaign == campaign && client.role == Role::GameMaster)
+            .for_each(&mut send),
+    }
+    lagging
+}
+
+/// Disconnects every connection a user has, returning how many there were.
+pub fn kick(clients: &HashMap<ClientId, Client>, user: UserId, reason: &str) -> usize {
+    let mut kicked = 0;
+    for client in clients.values().filter(|client| client.user == user) {
+        client.outbox.close(reason.to_string());
+        kicked += 1;
+    }
+    kicked
+}
+
+/// How backed up the clients' outgoing queues are.
+pub fn queue_stats(clients: &HashMap<ClientId, Client>) -> QueueStats {
+    clients
+        .values()
+        .map(|client| client.outbox.stats())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_tls;
+    use protocol::{ChatChannel, ChatEntry, Credentials};
+    use std::convert::TryFrom;
+    use tokio_rustls::{
+        rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+    use tokio_tungstenite::{client_async, connect_async, MaybeTlsStream};
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// A server for the "shire" campaign with one player, Frodo.
+    fn server(address: &str) -> Server {
+        let users = UserStore::temporary().unwrap();
+        let frodo = users.add("Frodo", Some("ring")).unwrap();
+        users.set_role("shire", frodo, Role::Player).unwrap();
+        let campaigns = CampaignRegistry::temporary().unwrap();
+        campaigns.ensure("shire").unwrap();
+        Server::new(address.to_string(), String::from("shire"), users, campaigns).unwrap()
+    }
+
+    async fn connect(address: &str) -> Socket {
+        for _ in 0..50 {
+            if let Ok((socket, _)) = connect_async(format!("ws://{}/server", address)).await {
+                return socket;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("server never started listening on {}", address);
+    }
+
+    /// Connects and logs in as Frodo.
+    async fn log_in(address: &str) -> Socket {
+        let mut socket = connect(address).await;
+        say_hello(&mut socket).await;
+        socket
+    }
+
+    async fn say_hello<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut WebSocketStream<S>) {
+        let hello = Codec::Json
+            .encode(&ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                codecs: vec![Codec::Json],
+                resume: None,
+                login: Some(Credentials::Password {
+                    username: String::from("Frodo"),
+                    password: String::from("ring"),
+                }),
+                campaign: None,
+            })
+            .unwrap();
+        socket.send(to_message(hello)).await.unwrap();
+        assert!(matches!(
+            next_server_message(socket).await,
+            ServerMessage::Welcome { .. }
+        ));
+    }
+
+    async fn next_message<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: &mut WebSocketStream<S>,
+    ) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("timed out waiting for the server")
+            .expect("socket ended without a close frame")
+            .unwrap()
+    }
+
+    /// The next game message, skipping heartbeats.
+    async fn next_server_message<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: &mut WebSocketStream<S>,
+    ) -> ServerMessage {
+        loop {
+            if let Some(frame) = to_frame(next_message(socket).await) {
+                return frame.decode().unwrap();
+            }
+        }
+    }
+
+    async fn next_close(socket: &mut Socket) -> CloseFrame<'static> {
+        loop {
+            match next_message(socket).await {
+                Message::Close(Some(frame)) => return frame,
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_closes_every_connection() {
+        let address = free_address();
+        let server = server(&address);
+        let clients = server.clients.clone();
+        let events = server.reciever.clone();
+        let control = server.control_sender.clone();
+        let running = tokio::spawn(server.start());
+
+        let mut player = log_in(&address).await;
+        // Someone still in the middle of the handshake is turned away too.
+        let mut lurker = connect(&address).await;
+
+        let chat = ServerMessage::Chat {
+            seq: 1,
+            entry: ChatEntry {
+                from: 1,
+                text: String::from("goodbye"),
+                ..Default::default()
+            },
+        };
+        deliver(
+            &clients.lock().unwrap(),
+            "shire",
+            &Recipients::Everyone,
+            &chat,
+        );
+        control.send(ServerControl::CloseServer).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("server didn't finish shutting down")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(next_server_message(&mut player).await, chat);
+        for socket in [&mut player, &mut lurker] {
+            assert_eq!(next_close(socket).await.code, CloseCode::Away);
+        }
+        assert!(clients.lock().unwrap().is_empty());
+        // Every connection task has dropped its way of talking to the game.
+        while events.try_recv().is_ok() {}
+        assert!(events.recv().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn heartbeats_measure_latency() {
+        let address = free_address();
+        let mut server = server(&address);
+        server.heartbeat.interval = Duration::from_millis(20);
+        let clients = server.clients.clone();
+        tokio::spawn(server.start());
+
+        // Reading the socket is what answers the server's pings.
+        let mut player = log_in(&address).await;
+        loop {
+            if let ServerMessage::Presence(Presence::Latency { user, .. }) =
+                next_server_message(&mut player).await
+            {
+                assert_eq!(user, 1);
+                break;
+            }
+        }
+        let clients = clients.lock().unwrap();
+        assert!(clients.values().all(|client| client.latency.is_some()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quiet_connections_are_dropped() {
+        let address = free_address();
+        let mut server = server(&address);
+        server.heartbeat = Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+        let clients = server.clients.clone();
+        tokio::spawn(server.start());
+
+        let mut player = log_in(&address).await;
+        // Not reading means never answering a ping.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(clients.lock().unwrap().is_empty());
+        // Catching up now fails, as the pongs go nowhere.
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = player.next().await {}
+        });
+        assert!(ended.await.is_ok());
+
+        let mut lurker = connect(&address).await;
+        assert_eq!(next_close(&mut lurker).await.code, CloseCode::Policy);
+
+        // Nor can a request that never finishes keep a connection open.
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stalled = TcpStream::connect(&address).await.unwrap();
+        stalled.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stalled.read_to_end(&mut rest));
+        assert!(closed.await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_wss_with_a_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = std::env::temp_dir().join(format!("vtt-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+
+        let address = free_address();
+        let mut server = server(&address);
+        server.tls = Some(load_tls(&cert_file, &key_file).unwrap());
+        tokio::spawn(server.start());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let mut tcp = None;
+        for _ in 0..50 {
+            match TcpStream::connect(&address).await {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        // Plain websockets aren't accepted any more.
+        assert!(connect_async(format!("ws://{}/server", address))
+            .await
+            .is_err());
+
+        let localhost = ServerName::try_from("localhost").unwrap();
+        let tls = connector.connect(localhost, tcp.unwrap()).await.unwrap();
+        let (mut socket, _) = client_async("wss://localhost/server", tls).await.unwrap();
+        say_hello(&mut socket).await;
+    }
+
+    /// Sends a bare HTTP/1.0 request and returns the whole response.
+    async fn get(address: &str, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.0\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_files_beside_the_websocket() {
+        let dir = std::env::temp_dir().join(format!("vtt-web-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("web")).unwrap();
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::write(dir.join("web/index.html"), "<html>vtt</html>").unwrap();
+        std::fs::write(dir.join("assets/map.png"), "not really a png").unwrap();
+
+        let address = free_address();
+        let mut server = server(&address);
+        server.files = Files {
+            web: Some(dir.join("web")),
+            assets: Some(dir.join("assets")),
+        };
+        tokio::spawn(server.start());
+        log_in(&address).await;
+
+        let index = get(&address, "/").await;
+        assert!(index.starts_with("HTTP/1.0 200"));
+        assert!(index.contains("content-type: text/html"));
+        assert!(index.ends_with("<html>vtt</html>"));
+        let asset = get(&address, "/assets/map.png").await;
+        assert!(asset.contains("content-type: image/png"));
+        assert!(get(&address, "/assets/../web/index.html")
+            .await
+            .starts_with("HTTP/1.0 404"));
+        assert!(get(&address, "/missing.js")
+            .await
+            .starts_with("HTTP/1.0 404"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Sends a hello with `login` and returns the server's answer.
+    async fn hello_as(socket: &mut Socket, login: Credentials) -> ServerMessage {
+        let hello = Codec::Json
+            .encode(&ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                codecs: vec![Codec::Json],
+                resume: None,
+                login: Some(login),
+                campaign: None,
+            })
+            .unwrap();
+        socket.send(to_message(hello)).await.unwrap();
+        next_server_message(socket).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invites_sign_up_with_a_password_to_come_back_with() {
+        let address = free_address();
+        let server = server(&address);
+        let token = server.users.invite("shire", Role::Player).unwrap();
+        tokio::spawn(server.start());
+        let invite = Credentials::Invite {
+            username: String::from("Merry"),
+            token,
+        };
+
+        let mut socket = connect(&address).await;
+        let password = match hello_as(&mut socket, invite.clone()).await {
+            ServerMessage::Welcome {
+                password: Some(password),
+                ..
+            } => password,
+            other => panic!("expected a welcome with a password, got {:?}", other),
+        };
+        let mut again = connect(&address).await;
+        assert_eq!(
+            hello_as(&mut again, invite).await,
+            ServerMessage::Rejected(Rejection::BadCredentials)
+        );
+        let mut back = connect(&address).await;
+        let login = Credentials::Password {
+            username: String::from("Merry"),
+            password,
+        };
+        assert!(matches!(
+            hello_as(&mut back, login).await,
+            ServerMessage::Welcome { password: None, .. }
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn late_joiners_are_told_whos_playing() {
+        let address = free_address();
+        let server = server(&address);
+        let token = server.users.invite("shire", Role::Player).unwrap();
+        let clients = server.clients.clone();
+        tokio::spawn(server.start());
+
+        let mut frodo = log_in(&address).await;
+        for client in clients.lock().unwrap().values_mut() {
+            client.latency = Some(Duration::from_millis(42));
+        }
+        let mut merry = connect(&address).await;
+        let login = Credentials::Invite {
+            username: String::from("Merry"),
+            token,
+        };
+        let merry_id = match hello_as(&mut merry, login).await {
+            ServerMessage::Welcome { user, .. } => user,
+            other => panic!("expected a welcome, got {:?}", other),
+        };
+        assert_eq!(
+            next_server_message(&mut merry).await,
+            ServerMessage::Presence(Presence::Joined {
+                user: 1,
+                name: String::from("Frodo")
+            })
+        );
+        assert_eq!(
+            next_server_message(&mut merry).await,
+            ServerMessage::Presence(Presence::Latency {
+                user: 1,
+                millis: 42
+            })
+        );
+        assert_eq!(
+            next_server_message(&mut frodo).await,
+            ServerMessage::Presence(Presence::Joined {
+                user: merry_id,
+                name: String::from("Merry")
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn banned_users_are_kicked_and_turned_away() {
+        let users = UserStore::temporary().unwrap();
+        let frodo = users.add("Frodo", Some("ring")).unwrap();
+        users.set_role("shire", frodo, Role::Player).unwrap();
+        let campaigns = CampaignRegistry::temporary().unwrap();
+        campaigns.ensure("shire").unwrap();
+        let address = free_address();
+        let server = Server::new(
+            address.clone(),
+            String::from("shire"),
+            users.clone(),
+            campaigns,
+        )
+        .unwrap();
+        let clients = server.clients.clone();
+        tokio::spawn(server.start());
+
+        let mut socket = log_in(&address).await;
+        users.ban(frodo).unwrap();
+        assert_eq!(kick(&clients.lock().unwrap(), frodo, "Banned"), 1);
+        let close = next_close(&mut socket).await;
+        assert_eq!(close.code, CloseCode::Policy);
+        assert_eq!(close.reason, "Banned");
+
+        let mut socket = connect(&address).await;
+        let login = Credentials::Password {
+            username: String::from("Frodo"),
+            password: String::from("ring"),
+        };
+        assert_eq!(
+            hello_as(&mut socket, login).await,
+            ServerMessage::Rejected(Rejection::Banned)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flooding_clients_are_warned_then_disconnected() {
+        let address = free_address();
+        let mut server = server(&address);
+        server.message_limits = MessageLimits {
+            max_size: 1000,
+            max_upload_size: 1000,
+            per_second: 1,
+            burst: 2,
+            warnings: 1,
+        };
+        let events = server.reciever.clone();
+        tokio::spawn(server.start());
+
+        let mut socket = log_in(&address).await;
+        for text in ["one", "two", "three", "four"] {
+            let chat = Codec::Json
+                .encode(&ClientMessage::Chat {
+                    text: text.to_string(),
+                    channel: ChatChannel::Everyone,
+                    in_character: false,
+                })
+                .unwrap();
+            socket.send(to_message(chat)).await.unwrap();
+        }
+        assert!(matches!(
+            next_server_message(&mut socket).await,
+            ServerMessage::Error { message } if message.starts_with("Message refused")
+        ));
+        let close = next_close(&mut socket).await;
+        assert_eq!(close.code, CloseCode::Policy);
+
+        let chats = events
+            .try_iter()
+            .filter(|(_, event)| matches!(event, ClientEvent::Message(_)))
+            .count();
+        assert_eq!(chats, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn oversized_messages_close_the_connection() {
+        let address = free_address();
+        let mut server = server(&address);
+        server.message_limits = MessageLimits {
+            max_size: 100,
+            max_upload_size: 100,
+            ..MessageLimits::default()
+        };
+        let clients = server.clients.clone();
+        let metrics = server.metrics.clone();
+        tokio::spawn(server.start());
+
+        let mut socket = log_in(&address).await;
+        socket.send(Message::Text("x".repeat(1000))).await.unwrap();
+        let close = next_close(&mut socket).await;
+        assert_eq!(close.code, CloseCode::Size);
+        for _ in 0..50 {
+            if clients.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let clients = clients.lock().unwrap();
+        assert!(clients.is_empty());
+        assert!(metrics
+            .render(&clients)
+            .contains("vtt_refused_messages_total 1"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn limits_and_auth_mode_turn_clients_away() {
+        let users = UserStore::temporary().unwrap();
+        for (name, password) in [("Frodo", "ring"), ("Sam", "potatoes")] {
+            let id = users.add(name, Some(password)).unwrap();
+            users.set_role("shire", id, Role::Player).unwrap();
+        }
+        users.add("Gollum", Some("precious")).unwrap();
+        let token = users.invite("shire", Role::Player).unwrap();
+        let campaigns = CampaignRegistry::temporary().unwrap();
+        campaigns.ensure("shire").unwrap();
+        let address = free_address();
+        let mut server =
+            Server::new(address.clone(), String::from("shire"), users, campaigns).unwrap();
+        server.auth = AuthMode::Password;
+        server.limits.max_players = Some(1);
+        tokio::spawn(server.start());
+
+        let mut invited = connect(&address).await;
+        let login = Credentials::Invite {
+            username: String::from("Merry"),
+            token,
+        };
+        assert_eq!(
+            hello_as(&mut invited, login).await,
+            ServerMessage::Rejected(Rejection::InvitesDisabled)
+        );
+
+        // Registered, but not a member of the campaign.
+        let mut gollum = connect(&address).await;
+        let login = Credentials::Password {
+            username: String::from("Gollum"),
+            password: String::from("precious"),
+        };
+        assert_eq!(
+            hello_as(&mut gollum, login).await,
+            ServerMessage::Rejected(Rejection::UnknownCampaign(String::from("shire")))
+        );
+
+        let _frodo = log_in(&address).await;
+        let mut sam = connect(&address).await;
+        let login = Credentials::Password {
+            username: String::from("Sam"),
+            password: String::from("potatoes"),
+        };
+        assert_eq!(
+            hello_as(&mut sam, login).await,
+            ServerMessage::Rejected(Rejection::CampaignFull(String::from("shire")))
+        );
+    }
+}
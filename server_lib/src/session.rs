@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use protocol::{ClientId, ResumeToken, UserId};
+use rand::{distributions::Alphanumeric, Rng};
+
+/// How long a dropped client has to reconnect before its id is forgotten.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct Session {
+    client: ClientId,
+    user: UserId,
+    /// When the client dropped, or `None` while it's still connected.
+    left: Option<Instant>,
+}
+
+/// Hands out client ids, remembering them against resume tokens so a client
+/// that reconnects soon enough gets its old id back.
+#[derive(Debug)]
+pub struct Sessions {
+    sessions: HashMap<ResumeToken, Session>,
+    next_id: ClientId,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            sessions: HashMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl Sessions {
+    /// Starts a session for a new connection. If `resume` names a session the
+    /// same user dropped within the window, its id is reused. Returns the id,
+    /// the token to resume with next time, and whether it was resumed.
+    pub fn start(
+        &mut self,
+        resume: Option<&str>,
+        user: UserId,
+        now: Instant,
+    ) -> (ClientId, ResumeToken, bool) {
+        self.expire(now);
+        let previous = resume
+            .filter(|token| {
+                matches!(
+                    self.sessions.get(*token),
+                    Some(Session { left: Some(_), user: owner, .. }) if *owner == user
+                )
+            })
+            .and_then(|token| self.sessions.remove(token));
+        let (client, resumed) = match previous {
+            Some(session) => (session.client, true),
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                (id, false)
+            }
+        };
+        let token = new_token();
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                client,
+                user,
+                left: None,
+            },
+        );
+        (client, token, resumed)
+    }
+
+    /// Marks a client as gone, starting the window it has to come back in.
+    pub fn end(&mut self, client: ClientId, now: Instant) {
+        for session in self.sessions.values_mut() {
+            if session.client == client {
+                session.left = Some(now);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| match session.left {
+            Some(left) => now.duration_since(left) < RESUME_WINDOW,
+            None => true,
+        });
+    }
+}
+
+fn new_token() -> ResumeToken {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_connections_get_fresh_ids() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (first, first_token, resumed) = sessions.start(None, 1, now);
+        let (second, second_token, _) = sessions.start(None, 1, now);
+        assert!(!resumed);
+        assert_ne!(first, second);
+        assert_ne!(first_token, second_token);
+    }
+
+    #[test]
+    fn dropped_clients_resume_their_id() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (client, token, _) = sessions.start(None, 1, now);
+        sessions.end(client, now);
+        let (resumed_as, new_token, resumed) = sessions.start(Some(&token), 1, now);
+        assert!(resumed);
+        assert_eq!(resumed_as, client);
+        assert_ne!(new_token, token);
+
+        // The old token has been used up.
+        sessions.end(client, now);
+        let (_, _, resumed) = sessions.start(Some(&token), 1, now);
+        assert!(!resumed);
+    }
+
+    #[test]
+    fn connected_clients_cant_be_taken_over() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (client, token, _) = sessions.start(None, 1, now);
+        let (other, _, resumed) = sessions.start(Some(&token), 1, now);
+        assert!(!resumed);
+        assert_ne!(other, client);
+    }
+
+    #[test]
+    fn only_the_same_user_can_resume() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (client, token, _) = sessions.start(None, 1, now);
+        sessions.end(client, now);
+        let (other, _, resumed) = sessions.start(Some(&token), 2, now);
+        assert!(!resumed);
+        assert_ne!(other, client);
+    }
+
+    #[test]
+    fn sessions_expire_after_the_window() {
+        let mut sessions = Sessions::default();
+        let now = Instant::now();
+        let (client, token, _) = sessions.start(None, 1, now);
+        sessions.end(client, now);
+        let later = now + RESUME_WINDOW;
+        let (other, _, resumed) = sessions.start(Some(&token), 1, later);
+        assert!(!resumed);
+        assert_ne!(other, client);
+    }
+}
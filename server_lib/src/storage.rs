@@ -0,0 +1,169 @@
+use protocol::{AssetInfo, CampaignState, ChatEntry, MapChange, MapId, Token, TokenId};
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{Db, Tree};
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Database(sled::Error),
+    Corrupt(serde_json::Error),
+    BadName(String),
+    Password(String),
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(error: sled::Error) -> Self {
+        StorageError::Database(error)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(error: serde_json::Error) -> Self {
+        StorageError::Corrupt(error)
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Database(e) => write!(f, "database error: {}", e),
+            StorageError::Corrupt(e) => write!(f, "couldn't read stored record: {}", e),
+            StorageError::BadName(name) => write!(f, "the name {:?} is empty or taken", name),
+            StorageError::Password(e) => write!(f, "couldn't hash password: {}", e),
+        }
+    }
+}
+
+const ACTIVE_MAP: &str = "active-map";
+
+/// A campaign's records in sled - one tree per kind of record, each prefixed
+/// with the campaign name and keyed by big-endian id so they load in order.
+#[derive(Debug, Clone)]
+pub struct CampaignStore {
+    maps: Tree,
+    zones: Tree,
+    brushes: Tree,
+    tokens: Tree,
+    chat: Tree,
+    /// Keyed by hash rather than id.
+    assets: Tree,
+    /// Single values keyed by name, like the active map.
+    settings: Tree,
+}
+
+impl CampaignStore {
+    pub fn open(db: &Db, campaign: &str) -> Result<Self, StorageError> {
+        let tree = |kind: &str| db.open_tree(format!("{}/{}", campaign, kind));
+        Ok(CampaignStore {
+            maps: tree("maps")?,
+            zones: tree("zones")?,
+            brushes: tree("brushes")?,
+            tokens: tree("tokens")?,
+            chat: tree("chat")?,
+            assets: tree("assets")?,
+            settings: tree("settings")?,
+        })
+    }
+
+    pub fn load(&self) -> Result<CampaignState, StorageError> {
+        let maps: BTreeMap<MapId, _> = load_tree(&self.maps)?.into_iter().collect();
+        let active_map = match self.settings.get(ACTIVE_MAP)? {
+            Some(map) => {
+                serde_json::from_slice::<Option<MapId>>(&map)?.filter(|map| maps.contains_key(map))
+            }
+            None => None,
+        };
+        Ok(CampaignState {
+            maps,
+            zones: load_tree(&self.zones)?.into_iter().collect(),
+            brushes: load_tree(&self.brushes)?.into_iter().collect(),
+            tokens: load_tree(&self.tokens)?.into_iter().collect(),
+            // Messages saved before they had ids are numbered by their key.
+            chat: load_tree(&self.chat)?
+                .into_iter()
+                .map(|(id, entry)| ChatEntry { id, ..entry })
+                .collect(),
+            assets: self
+                .assets
+                .iter()
+                .map(|record| {
+                    let (_, info) = record?;
+                    let info: AssetInfo = serde_json::from_slice(&info)?;
+                    Ok((info.hash.clone(), info))
+                })
+                .collect::<Result<_, StorageError>>()?,
+            active_map,
+        })
+    }
+
+    pub fn record(&self, change: &MapChange) -> Result<(), StorageError> {
+        match change {
+            MapChange::MapSet { id, map } => put(&self.maps, *id, map),
+            MapChange::MapRemoved { id } => remove(&self.maps, *id),
+            MapChange::ZoneSet { id, zone } => put(&self.zones, *id, zone),
+            MapChange::ZoneRemoved { id } => remove(&self.zones, *id),
+            MapChange::BrushSet { id, brush } => put(&self.brushes, *id, brush),
+            MapChange::BrushRemoved { id } => remove(&self.brushes, *id),
+        }
+    }
+
+    pub fn put_token(&self, id: TokenId, token: &Token) -> Result<(), StorageError> {
+        put(&self.tokens, id, token)
+    }
+
+    pub fn push_chat(&self, entry: &ChatEntry) -> Result<(), StorageError> {
+        put(&self.chat, entry.id, entry)
+    }
+
+    pub fn put_asset(&self, info: &AssetInfo) -> Result<(), StorageError> {
+        self.assets
+            .insert(info.hash.as_bytes(), serde_json::to_vec(info)?)?;
+        Ok(())
+    }
+
+    pub fn set_active_map(&self, map: Option<MapId>) -> Result<(), StorageError> {
+        self.settings
+            .insert(ACTIVE_MAP, serde_json::to_vec(&map)?)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        for tree in [
+            &self.maps,
+            &self.zones,
+            &self.brushes,
+            &self.tokens,
+            &self.chat,
+            &self.assets,
+            &self.settings,
+        ] {
+            tree.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn put<V: Serialize>(tree: &Tree, id: u64, value: &V) -> Result<(), StorageError> {
+    tree.insert(id.to_be_bytes(), serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+fn remove(tree: &Tree, id: u64) -> Result<(), StorageError> {
+    tree.remove(id.to_be_bytes())?;
+    Ok(())
+}
+
+fn load_tree<V: DeserializeOwned>(tree: &Tree) -> Result<Vec<(u64, V)>, StorageError> {
+    tree.iter()
+        .map(|record| {
+            let (key, value) = record?;
+            Ok((from_key(&key), serde_json::from_slice(&value)?))
+        })
+        .collect()
+}
+
+pub(crate) fn from_key(key: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(bytes)
+}
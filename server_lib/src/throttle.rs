@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use protocol::{encoded_asset_size, MAX_ASSET_SIZE};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// How much a single connection is allowed to send, so a buggy or hostile
+/// client can't swamp the server or the game.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageLimits {
+    /// The largest message accepted, in bytes as sent. Uploads have their own
+    /// limit.
+    pub max_size: usize,
+    /// The largest upload accepted, in bytes as sent.
+    pub max_upload_size: usize,
+    /// Messages a second a client can keep sending.
+    pub per_second: u32,
+    /// Messages a client can send at once after being quiet.
+    pub burst: u32,
+    /// Messages that can be refused before the client is disconnected.
+    pub warnings: u32,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_size: 64 * 1024,
+            // Room for the largest asset, which JSON sends as base64.
+            max_upload_size: encoded_asset_size(MAX_ASSET_SIZE) + 64 * 1024,
+            per_second: 20,
+            // Joining a campaign asks for every asset at once.
+            burst: 100,
+            warnings: 3,
+        }
+    }
+}
+
+impl MessageLimits {
+    /// Messages a little over the limit are still read so the client can be
+    /// warned. Anything far bigger isn't worth buffering.
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        let backstop = self.max_size.max(self.max_upload_size).saturating_mul(2);
+        WebSocketConfig {
+            max_message_size: Some(backstop),
+            max_frame_size: Some(backstop),
+            ..WebSocketConfig::default()
+        }
+    }
+
+    /// How long a client has to behave once warned before its warnings are
+    /// forgotten - as long as an empty bucket takes to fill.
+    fn recovery(&self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.burst) / f64::from(self.per_second.max(1)))
+    }
+}
+
+/// What to do with a message from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    /// Drop the message and tell the client why.
+    Refuse(String),
+    /// The client has been refused too often and has to go.
+    Disconnect(String),
+}
+
+/// A token bucket for one connection, along with how often it has been
+/// refused lately.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    limits: MessageLimits,
+    tokens: f64,
+    updated: Instant,
+    refused: u32,
+    last_refused: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(limits: MessageLimits, now: Instant) -> Self {
+        Throttle {
+            limits,
+            tokens: f64::from(limits.burst),
+            updated: now,
+            refused: 0,
+            last_refused: now,
+        }
+    }
+
+    /// Decides what to do with a message `size` bytes long arriving at `now`.
+    pub(crate) fn check(&mut self, size: usize, upload: bool, now: Instant) -> Verdict {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.limits.per_second))
+            .min(f64::from(self.limits.burst));
+        self.updated = now;
+        if self.refused > 0
+            && now.saturating_duration_since(self.last_refused) >= self.limits.recovery()
+        {
+            self.refused = 0;
+        }
+
+        let max_size = if upload {
+            self.limits.max_upload_size
+        } else {
+            self.limits.max_size
+        };
+        let reason = if size > max_size {
+            format!("message is {} bytes - the limit is {}", size, max_size)
+        } else if self.tokens < 1. {
+            format!(
+                "sending more than {} messages a second",
+                self.limits.per_second
+            )
+        } else {
+            self.tokens -= 1.;
+            return Verdict::Accept;
+        };
+        self.refused += 1;
+        self.last_refused = now;
+        if self.refused > self.limits.warnings {
+            Verdict::Disconnect(reason)
+        } else {
+            Verdict::Refuse(reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> MessageLimits {
+        MessageLimits {
+            max_size: 100,
+            max_upload_size: 1000,
+            per_second: 10,
+            burst: 5,
+            warnings: 2,
+        }
+    }
+
+    #[test]
+    fn bursts_are_allowed_then_the_rate_holds() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(limits(), start);
+        for _ in 0..5 {
+            assert_eq!(throttle.check(10, false, start), Verdict::Accept);
+        }
+        assert!(matches!(
+            throttle.check(10, false, start),
+            Verdict::Refuse(_)
+        ));
+        // A tenth of a second buys one more message.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(throttle.check(10, false, later), Verdict::Accept);
+        assert!(matches!(
+            throttle.check(10, false, later),
+            Verdict::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn clients_that_ignore_warnings_are_disconnected() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(limits(), start);
+        assert!(matches!(
+            throttle.check(101, false, start),
+            Verdict::Refuse(_)
+        ));
+        assert!(matches!(
+            throttle.check(101, false, start),
+            Verdict::Refuse(_)
+        ));
+        assert_eq!(
+            throttle.check(101, false, start),
+            Verdict::Disconnect(String::from("message is 101 bytes - the limit is 100"))
+        );
+    }
+
+    #[test]
+    fn only_uploads_can_be_large() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(limits(), start);
+        assert_eq!(throttle.check(1000, true, start), Verdict::Accept);
+        assert!(matches!(
+            throttle.check(1001, true, start),
+            Verdict::Refuse(_)
+        ));
+        assert!(matches!(
+            throttle.check(101, false, start),
+            Verdict::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn warnings_are_forgotten_after_behaving() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(limits(), start);
+        assert!(matches!(
+            throttle.check(101, false, start),
+            Verdict::Refuse(_)
+        ));
+        assert!(matches!(
+            throttle.check(101, false, start),
+            Verdict::Refuse(_)
+        ));
+        let later = start + Duration::from_secs(1);
+        assert!(matches!(
+            throttle.check(101, false, later),
+            Verdict::Refuse(_)
+        ));
+        assert!(matches!(
+            throttle.check(101, false, later),
+            Verdict::Refuse(_)
+        ));
+    }
+}
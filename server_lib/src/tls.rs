@@ -0,0 +1,61 @@
+use std::{fmt, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls_pemfile::Item;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(std::io::Error),
+    NoCertificates,
+    NoKey,
+    Rustls(tokio_rustls::rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(error) => write!(f, "couldn't read file: {}", error),
+            TlsError::NoCertificates => write!(f, "no certificates found in the certificate file"),
+            TlsError::NoKey => write!(f, "no private key found in the key file"),
+            TlsError::Rustls(error) => write!(f, "invalid certificate or key: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<std::io::Error> for TlsError {
+    fn from(error: std::io::Error) -> Self {
+        TlsError::Io(error)
+    }
+}
+
+impl From<tokio_rustls::rustls::Error> for TlsError {
+    fn from(error: tokio_rustls::rustls::Error) -> Self {
+        TlsError::Rustls(error)
+    }
+}
+
+/// Reads a PEM certificate chain and private key, so the server can accept
+/// `wss://` connections without a proxy in front of it.
+pub fn load_tls(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(TlsError::NoKey)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,415 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use protocol::{Credentials, Role, UserId};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+
+use crate::{storage::from_key, StorageError};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// An argon2 hash. Only users added without one have `None`, and they
+    /// can't log in.
+    password: Option<String>,
+}
+
+impl User {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+}
+
+/// How people can get into the server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Registered users log in with a password, and invites sign up new ones.
+    #[default]
+    Invite,
+    /// Only users with a password can log in - invites are turned away.
+    Password,
+}
+
+impl std::str::FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "invite" => Ok(AuthMode::Invite),
+            "password" => Ok(AuthMode::Password),
+            other => Err(format!(
+                "unknown auth mode {:?} - expected invite or password",
+                other
+            )),
+        }
+    }
+}
+
+/// Who a client's credentials belong to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Login {
+    pub id: UserId,
+    pub user: User,
+    /// Made up for a user who has just signed up with an invite. It's what
+    /// they log in with from then on, since the invite can't be used again
+    /// for the same name.
+    pub password: Option<String>,
+}
+
+/// Lets new players into a campaign with the given role.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub campaign: String,
+    pub role: Role,
+}
+
+/// The server's users, shared by every campaign. Users are keyed by id, with
+/// a second tree to look them up by name. Invites are keyed by token, roles
+/// by campaign and user id, and bans by user id.
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    db: Db,
+    users: Tree,
+    names: Tree,
+    invites: Tree,
+    roles: Tree,
+    bans: Tree,
+}
+
+impl UserStore {
+    pub fn open(db: &Db) -> Result<Self, StorageError> {
+        Ok(UserStore {
+            db: db.clone(),
+            users: db.open_tree("users")?,
+            names: db.open_tree("usernames")?,
+            invites: db.open_tree("invites")?,
+            roles: db.open_tree("roles")?,
+            bans: db.open_tree("bans")?,
+        })
+    }
+
+    /// A store that's thrown away when it's dropped, for hosts that don't
+    /// keep anything between runs.
+    pub fn temporary() -> Result<Self, StorageError> {
+        UserStore::open(&sled::Config::new().temporary(true).open()?)
+    }
+
+    /// Registers a new user, failing if the name is already taken.
+    pub fn add(&self, name: &str, password: Option<&str>) -> Result<UserId, StorageError> {
+        let name = name.trim();
+        if name.is_empty() || self.names.contains_key(name)? {
+            return Err(StorageError::BadName(name.to_string()));
+        }
+        let password = match password {
+            Some(password) => Some(hash(password)?),
+            None => None,
+        };
+        // Ids start at one, so zero never belongs to anyone.
+        let id = self.db.generate_id()? + 1;
+        let user = User {
+            name: name.to_string(),
+            password,
+        };
+        self.users
+            .insert(id.to_be_bytes(), serde_json::to_vec(&user)?)?;
+        self.names.insert(name, &id.to_be_bytes())?;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: UserId) -> Result<Option<User>, StorageError> {
+        match self.users.get(id.to_be_bytes())? {
+            Some(user) => Ok(Some(serde_json::from_slice(&user)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Result<Option<(UserId, User)>, StorageError> {
+        let id = match self.names.get(name.trim())? {
+            Some(id) => from_key(&id),
+            None => return Ok(None),
+        };
+        Ok(self.get(id)?.map(|user| (id, user)))
+    }
+
+    /// Registers a new user with a made-up password and gives them `role` in
+    /// a campaign. Returns their id and the password.
+    pub fn sign_up(
+        &self,
+        name: &str,
+        campaign: &str,
+        role: Role,
+    ) -> Result<(UserId, String), StorageError> {
+        let password = secret(20);
+        let id = self.add(name, Some(&password))?;
+        self.set_role(campaign, id, role)?;
+        Ok((id, password))
+    }
+
+    /// Makes a new invite token for a campaign.
+    pub fn invite(&self, campaign: &str, role: Role) -> Result<String, StorageError> {
+        let token = secret(12);
+        let invite = Invite {
+            campaign: campaign.to_string(),
+            role,
+        };
+        self.invites
+            .insert(token.as_str(), serde_json::to_vec(&invite)?)?;
+        Ok(token)
+    }
+
+    /// What an invite token is for, if it's valid.
+    pub fn invited_to(&self, token: &str) -> Result<Option<Invite>, StorageError> {
+        match self.invites.get(token)? {
+            Some(invite) => Ok(Some(serde_json::from_slice(&invite)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A user's role in a campaign, or `None` if they aren't one of its
+    /// members.
+    pub fn role(&self, campaign: &str, user: UserId) -> Result<Option<Role>, StorageError> {
+        match self.roles.get(role_key(campaign, user))? {
+            Some(role) => Ok(Some(serde_json::from_slice(&role)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_role(&self, campaign: &str, user: UserId, role: Role) -> Result<(), StorageError> {
+        self.roles
+            .insert(role_key(campaign, user), serde_json::to_vec(&role)?)?;
+        Ok(())
+    }
+
+    /// Stops a user logging in. Returns whether they weren't already banned.
+    pub fn ban(&self, user: UserId) -> Result<bool, StorageError> {
+        Ok(self.bans.insert(user.to_be_bytes(), &[])?.is_none())
+    }
+
+    /// Lets a banned user log in again. Returns whether they were banned.
+    pub fn unban(&self, user: UserId) -> Result<bool, StorageError> {
+        Ok(self.bans.remove(user.to_be_bytes())?.is_some())
+    }
+
+    pub fn is_banned(&self, user: UserId) -> Result<bool, StorageError> {
+        Ok(self.bans.contains_key(user.to_be_bytes())?)
+    }
+
+    /// Checks a client's credentials for a campaign, returning who they
+    /// belong to or `None` if they're wrong. An invite only signs up new
+    /// users, giving them the invite's role and a password to come back with -
+    /// it can't be used to log in as anyone who already exists.
+    pub fn authenticate(
+        &self,
+        credentials: &Credentials,
+        campaign: &str,
+    ) -> Result<Option<Login>, StorageError> {
+        match credentials {
+            Credentials::Password { username, password } => Ok(self
+                .find(username)?
+                .filter(|(_, user)| {
+                    user.password
+                        .as_deref()
+                        .is_some_and(|hash| verify(password, hash))
+                })
+                .map(|(id, user)| Login {
+                    id,
+                    user,
+                    password: None,
+                })),
+            Credentials::Invite { username, token } => {
+                let invite = match self.invited_to(token)? {
+                    Some(invite) if invite.campaign == campaign => invite,
+                    _ => return Ok(None),
+                };
+                if username.trim().is_empty() || self.find(username)?.is_some() {
+                    return Ok(None);
+                }
+                let (id, password) = match self.sign_up(username, campaign, invite.role) {
+                    Ok(signed_up) => signed_up,
+                    // Someone else signed up with the name first.
+                    Err(StorageError::BadName(_)) => return Ok(None),
+                    Err(error) => return Err(error),
+                };
+                Ok(self.get(id)?.map(|user| Login {
+                    id,
+                    user,
+                    password: Some(password),
+                }))
+            }
+        }
+    }
+}
+
+fn role_key(campaign: &str, user: UserId) -> String {
+    format!("{}/{}", campaign, user)
+}
+
+/// A random string of letters and digits.
+fn secret(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash(password: &str) -> Result<String, StorageError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| StorageError::Password(e.to_string()))
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials::Password {
+            username: String::from(username),
+            password: String::from(password),
+        }
+    }
+
+    fn invite(username: &str, token: &str) -> Credentials {
+        Credentials::Invite {
+            username: String::from(username),
+            token: String::from(token),
+        }
+    }
+
+    #[test]
+    fn passwords_are_checked() {
+        let users = UserStore::temporary().unwrap();
+        let id = users.add("Frodo", Some("ring")).unwrap();
+        let found = users
+            .authenticate(&password("Frodo", "ring"), "shire")
+            .unwrap();
+        assert_eq!(found.map(|login| login.id), Some(id));
+        assert!(users
+            .authenticate(&password("Frodo", "wrong"), "shire")
+            .unwrap()
+            .is_none());
+        assert!(users
+            .authenticate(&password("Sam", "ring"), "shire")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn bans_are_kept_until_lifted() {
+        let users = UserStore::temporary().unwrap();
+        let id = users.add("Gollum", None).unwrap();
+        assert!(!users.is_banned(id).unwrap());
+        assert!(users.ban(id).unwrap());
+        assert!(!users.ban(id).unwrap());
+        assert!(users.is_banned(id).unwrap());
+        assert!(users.unban(id).unwrap());
+        assert!(!users.is_banned(id).unwrap());
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let users = UserStore::temporary().unwrap();
+        users.add("Frodo", None).unwrap();
+        assert!(matches!(
+            users.add("Frodo", Some("ring")),
+            Err(StorageError::BadName(_))
+        ));
+    }
+
+    #[test]
+    fn invites_sign_up_new_users_once() {
+        let users = UserStore::temporary().unwrap();
+        let token = users.invite("shire", Role::Player).unwrap();
+        assert!(users
+            .authenticate(&invite("Sam", "not-a-token"), "shire")
+            .unwrap()
+            .is_none());
+        assert!(users
+            .authenticate(&invite("Sam", &token), "mordor")
+            .unwrap()
+            .is_none());
+
+        let signed_up = users
+            .authenticate(&invite("Sam", &token), "shire")
+            .unwrap()
+            .unwrap();
+        let issued = signed_up.password.unwrap();
+        assert!(users
+            .authenticate(&invite("Sam", &token), "shire")
+            .unwrap()
+            .is_none());
+        let again = users
+            .authenticate(&password("Sam", &issued), "shire")
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.id, signed_up.id);
+        assert_eq!(again.password, None);
+    }
+
+    #[test]
+    fn signing_up_makes_up_a_password() {
+        let users = UserStore::temporary().unwrap();
+        let (host, password) = users.sign_up("Host", "shire", Role::GameMaster).unwrap();
+        assert_eq!(users.role("shire", host).unwrap(), Some(Role::GameMaster));
+        let found = users
+            .authenticate(&self::password("Host", &password), "shire")
+            .unwrap();
+        assert_eq!(found.map(|login| login.id), Some(host));
+        let token = users.invite("shire", Role::Player).unwrap();
+        assert!(users
+            .authenticate(&invite("Host", &token), "shire")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn invites_cant_log_in_as_existing_users() {
+        let users = UserStore::temporary().unwrap();
+        users.add("Frodo", Some("ring")).unwrap();
+        users.add("Gollum", None).unwrap();
+        let token = users.invite("shire", Role::Player).unwrap();
+        for name in ["Frodo", "Gollum"] {
+            assert!(users
+                .authenticate(&invite(name, &token), "shire")
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn invites_give_their_role_to_new_users_only() {
+        let users = UserStore::temporary().unwrap();
+        let watch = users.invite("shire", Role::Spectator).unwrap();
+        let sam = users
+            .authenticate(&invite("Sam", &watch), "shire")
+            .unwrap()
+            .unwrap()
+            .id;
+        assert_eq!(users.role("shire", sam).unwrap(), Some(Role::Spectator));
+        assert_eq!(users.role("mordor", sam).unwrap(), None);
+
+        // Whoever holds the invite can't take over Sam once they're promoted.
+        users.set_role("shire", sam, Role::GameMaster).unwrap();
+        assert!(users
+            .authenticate(&invite("Sam", &watch), "shire")
+            .unwrap()
+            .is_none());
+        assert_eq!(users.role("shire", sam).unwrap(), Some(Role::GameMaster));
+    }
+}